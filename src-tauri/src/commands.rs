@@ -1,9 +1,19 @@
 //! Tauri commands for proxy and application control
 //! These commands are invoked from the frontend
-use crate::network::WEBRTC_PROTECTION_SCRIPT;
-use crate::proxy::{ProxyConfig, ProxyPreset, ProxyStatus, ProxyTestResult, ProxyType};
+use crate::network::{
+    merge_host_overrides, parse_hosts_file, DnsCacheStats, DnsConfig, DnsQueryLogEntry,
+    DnsResolver, DnsStubConfig, DnsStubStatus, InterceptorConfig, PoisoningReport, RoutingConfig,
+    RoutingTable, FIGMA_DOMAINS, WEBRTC_PROTECTION_SCRIPT,
+};
+use crate::proxy::{
+    detect_system_proxy, parse_ss_uri, BalancerConfig, BalancerMember, BalancerStatus,
+    FailoverConfig, FailoverMember, FailoverStatus, HealthCheckConfig, HealthMonitorStatus,
+    ProxyConfig, ProxyPreset, ProxyProfiles, ProxyStatus, ProxyTestResult, SystemProxy,
+};
 use crate::utils::{
-    delete_proxy_password, get_proxy_password, store_proxy_password, AdvancedSettings, STORE_FILENAME,
+    delete_chain_passwords, delete_profile_password, delete_proxy_password, get_config_password,
+    get_profile_password, load_chain_passwords, store_chain_passwords, store_profile_password,
+    store_proxy_password, AdvancedSettings, STORE_FILENAME,
 };
 use crate::{utils::keys, AppState};
 use tauri::{Manager, State, WebviewUrl, WebviewWindowBuilder};
@@ -27,9 +37,11 @@ pub async fn set_proxy_config(
         } else if !config.host.is_empty() {
             let _ = delete_proxy_password(&config.host, config.port);
         }
-    } else if config.username.is_none() && !config.host.is_empty() {
+    } else if !config.uses_password() && !config.host.is_empty() {
         let _ = delete_proxy_password(&config.host, config.port);
     }
+    store_chain_passwords(config.profile_id.as_deref(), &config.chain)
+        .map_err(|e| format!("Failed to store password: {}", e))?;
 
     // Configure proxy manager
     state
@@ -63,13 +75,14 @@ pub async fn test_proxy_connection(
         config.port
     );
 
-    // Get password from keyring if not provided
-    let config = if config.username.is_some() && config.password.is_none() {
-        let password = get_proxy_password(&config.host, config.port).ok();
+    // Get passwords from keyring if not provided
+    let mut config = if config.uses_password() && config.password.is_none() {
+        let password = get_config_password(&config);
         ProxyConfig { password, ..config }
     } else {
         config
     };
+    load_chain_passwords(&mut config);
 
     let result = state.proxy_manager.test_connection(&config).await;
     Ok(result)
@@ -91,10 +104,11 @@ pub async fn toggle_proxy(
     log::info!("Toggling proxy: {}", enabled);
     if enabled {
         let mut config = state.proxy_manager.get_config().await;
-        if config.username.is_some() && config.password.is_none() {
-            let password = get_proxy_password(&config.host, config.port).ok();
-            config.password = password;
+        if config.uses_password() && config.password.is_none() {
+            config.password = get_config_password(&config);
         }
+        config.enabled = true;
+        load_chain_passwords(&mut config);
         state
             .proxy_manager
             .configure(config)
@@ -129,6 +143,14 @@ pub async fn get_proxy_config(state: State<'_, AppState>) -> Result<ProxyConfig,
     Ok(config)
 }
 
+/// Detect the system proxy and report where it came from
+#[tauri::command]
+pub async fn get_system_proxy() -> Result<Option<SystemProxy>, String> {
+    tauri::async_runtime::spawn_blocking(detect_system_proxy)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 /// Delete stored proxy credentials
 #[tauri::command]
 pub async fn delete_proxy_credentials(host: String, port: u16) -> Result<(), String> {
@@ -138,22 +160,487 @@ pub async fn delete_proxy_credentials(host: String, port: u16) -> Result<(), Str
 /// Check URL interception (called from injected JS)
 #[tauri::command]
 pub async fn should_intercept_url(url: String, state: State<'_, AppState>) -> Result<bool, String> {
-    let config = state.interceptor_config.read().await;
-    Ok(config.should_intercept(&url))
+    // A PAC script sending the URL direct takes precedence over the interceptor patterns
+    if let Some(pac) = state.proxy_manager.pac_resolver().await {
+        if let Ok(parsed) = url::Url::parse(&url) {
+            let resolver = state.proxy_manager.resolver();
+            let direct =
+                tokio::task::spawn_blocking(move || pac.is_direct(&parsed, resolver.as_ref()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+            if direct {
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(state.interceptor.read().await.should_intercept(&url))
+}
+
+/// Get the request interception patterns
+#[tauri::command]
+pub async fn get_interceptor_config(
+    state: State<'_, AppState>,
+) -> Result<InterceptorConfig, String> {
+    Ok(state.interceptor_config.read().await.clone())
+}
+
+/// Save the request interception patterns, rejecting any that do not compile
+#[tauri::command]
+pub async fn set_interceptor_config(
+    config: InterceptorConfig,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    log::info!("Setting interceptor config: enabled={}", config.enabled);
+    let matcher = config.compile()?;
+    *state.interceptor.write().await = matcher;
+    *state.interceptor_config.write().await = config.clone();
+
+    let store = StoreBuilder::new(&app, STORE_FILENAME)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    store.set(keys::INTERCEPTOR, value);
+    store.save().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Get saved proxy profiles in display order
+#[tauri::command]
+pub async fn get_proxy_presets(state: State<'_, AppState>) -> Result<Vec<ProxyPreset>, String> {
+    Ok(state.proxy_profiles.read().await.list())
+}
+
+/// Create a proxy profile, storing its password in the keyring
+#[tauri::command]
+pub async fn create_proxy_profile(
+    profile: ProxyPreset,
+    password: Option<String>,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<ProxyPreset, String> {
+    let mut profiles = state.proxy_profiles.write().await;
+    let created = profiles.create(profile)?;
+    log::info!("Created proxy profile {} ({})", created.name, created.id);
+
+    store_chain_passwords(Some(&created.id), &created.chain)
+        .map_err(|e| format!("Failed to store password: {}", e))?;
+    if let Some(password) = password.filter(|p| !p.is_empty()) {
+        store_profile_password(&created.id, &password)
+            .map_err(|e| format!("Failed to store password: {}", e))?;
+    }
+
+    persist_profiles(&app, &profiles)?;
+    Ok(created)
+}
+
+/// Update a proxy profile. A `None` password keeps the stored one, an empty one clears it.
+#[tauri::command]
+pub async fn update_proxy_profile(
+    profile: ProxyPreset,
+    password: Option<String>,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<ProxyPreset, String> {
+    let mut profiles = state.proxy_profiles.write().await;
+    // Hop passwords are keyed by position, so carry the stored ones over to wherever
+    // their hop ended up before re-storing them
+    let previous = profiles.get(&profile.id).map(profile_config);
+    let updated = profiles.update(profile)?;
+    log::info!("Updated proxy profile {} ({})", updated.name, updated.id);
+
+    let mut chain = updated.chain.clone();
+    if let Some(previous) = previous {
+        for hop in chain.iter_mut().filter(|hop| hop.password.is_none()) {
+            hop.password = previous
+                .chain
+                .iter()
+                .find(|old| {
+                    old.host == hop.host && old.port == hop.port && old.username == hop.username
+                })
+                .and_then(|old| old.password.clone());
+        }
+        delete_chain_passwords(Some(&updated.id), &previous.chain);
+    }
+    store_chain_passwords(Some(&updated.id), &chain)
+        .map_err(|e| format!("Failed to store password: {}", e))?;
+
+    match password {
+        Some(ref password) if !password.is_empty() => {
+            store_profile_password(&updated.id, password)
+                .map_err(|e| format!("Failed to store password: {}", e))?;
+        }
+        Some(_) => {
+            let _ = delete_profile_password(&updated.id);
+        }
+        None if !updated.requires_auth => {
+            let _ = delete_profile_password(&updated.id);
+        }
+        None => {}
+    }
+
+    persist_profiles(&app, &profiles)?;
+    Ok(updated)
+}
+
+/// Delete a proxy profile and its stored passwords
+#[tauri::command]
+pub async fn delete_proxy_profile(
+    id: String,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    let active = state.proxy_manager.get_config().await;
+    if active.enabled && active.profile_id.as_deref() == Some(id.as_str()) {
+        return Err("Cannot delete the active profile".to_string());
+    }
+
+    let mut profiles = state.proxy_profiles.write().await;
+    let removed = profiles.remove(&id)?;
+    log::info!("Deleted proxy profile {} ({})", removed.name, removed.id);
+    let _ = delete_profile_password(&removed.id);
+    delete_chain_passwords(Some(&removed.id), &removed.chain);
+
+    persist_profiles(&app, &profiles)
+}
+
+/// Duplicate a proxy profile, including its stored passwords
+#[tauri::command]
+pub async fn duplicate_proxy_profile(
+    id: String,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<ProxyPreset, String> {
+    let mut profiles = state.proxy_profiles.write().await;
+    let copy = profiles.duplicate(&id)?;
+
+    if let Ok(password) = get_profile_password(&id) {
+        store_profile_password(&copy.id, &password)
+            .map_err(|e| format!("Failed to store password: {}", e))?;
+    }
+    let mut original = ProxyConfig {
+        profile_id: Some(id),
+        ..copy.to_config()
+    };
+    load_chain_passwords(&mut original);
+    store_chain_passwords(Some(&copy.id), &original.chain)
+        .map_err(|e| format!("Failed to store password: {}", e))?;
+
+    persist_profiles(&app, &profiles)?;
+    Ok(copy)
+}
+
+/// Import a Shadowsocks `ss://` URI as a new proxy profile
+#[tauri::command]
+pub async fn import_shadowsocks_uri(
+    uri: String,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<ProxyPreset, String> {
+    let (profile, password) = parse_ss_uri(&uri)?;
+
+    let mut profiles = state.proxy_profiles.write().await;
+    let created = profiles.create(profile)?;
+    log::info!(
+        "Imported Shadowsocks profile {} ({})",
+        created.name,
+        created.id
+    );
+
+    store_profile_password(&created.id, &password)
+        .map_err(|e| format!("Failed to store password: {}", e))?;
+
+    persist_profiles(&app, &profiles)?;
+    Ok(created)
+}
+
+/// Reorder proxy profiles
+#[tauri::command]
+pub async fn reorder_proxy_profiles(
+    ids: Vec<String>,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    let mut profiles = state.proxy_profiles.write().await;
+    profiles.reorder(&ids)?;
+    persist_profiles(&app, &profiles)
+}
+
+/// Test a saved proxy profile and record the result on it
+#[tauri::command]
+pub async fn test_proxy_profile(
+    id: String,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<ProxyTestResult, String> {
+    let mut config = state
+        .proxy_profiles
+        .read()
+        .await
+        .get(&id)
+        .map(ProxyPreset::to_config)
+        .ok_or_else(|| format!("Profile not found: {}", id))?;
+    if config.uses_password() {
+        config.password = get_profile_password(&id).ok();
+    }
+    load_chain_passwords(&mut config);
+
+    let result = state.proxy_manager.test_connection(&config).await;
+
+    let mut profiles = state.proxy_profiles.write().await;
+    profiles.record_test(&id, result.clone())?;
+    persist_profiles(&app, &profiles)?;
+
+    Ok(result)
+}
+
+/// Activate a proxy profile, making it the current proxy configuration
+#[tauri::command]
+pub async fn activate_proxy_profile(
+    id: String,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<ProxyConfig, String> {
+    let profile = state
+        .proxy_profiles
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| format!("Profile not found: {}", id))?;
+    log::info!("Activating proxy profile {} ({})", profile.name, profile.id);
+
+    let current = state.proxy_manager.get_config().await;
+    let mut config = ProxyConfig {
+        auto_connect: current.auto_connect,
+        ..profile.to_config()
+    };
+    if config.uses_password() {
+        config.password = get_profile_password(&id).ok();
+    }
+    load_chain_passwords(&mut config);
+
+    state
+        .proxy_manager
+        .configure(config.clone())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Persist config (do not store password)
+    let store = StoreBuilder::new(&app, STORE_FILENAME)
+        .build()
+        .map_err(|e| e.to_string())?;
+    config.password = None;
+    let value = serde_json::to_value(&config).map_err(|e| e.to_string())?;
+    store.set(keys::PROXY_CONFIG, value);
+    store.save().map_err(|e| e.to_string())?;
+
+    Ok(config)
+}
+
+/// Persist proxy profiles to the settings store
+fn persist_profiles(app: &tauri::AppHandle, profiles: &ProxyProfiles) -> Result<(), String> {
+    let store = StoreBuilder::new(app, STORE_FILENAME)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(profiles).map_err(|e| e.to_string())?;
+    store.set(keys::PROXY_PRESETS, value);
+    store.save().map_err(|e| e.to_string())
+}
+
+/// Get the failover group settings
+#[tauri::command]
+pub async fn get_failover_config(state: State<'_, AppState>) -> Result<FailoverConfig, String> {
+    Ok(state.failover_config.read().await.clone())
+}
+
+/// Save the failover group settings and start or stop failover accordingly
+#[tauri::command]
+pub async fn set_failover_config(
+    config: FailoverConfig,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    log::info!("Setting failover config: enabled={}", config.enabled);
+    if config.enabled && state.balancer_config.read().await.enabled {
+        return Err("Disable load balancing before enabling failover".to_string());
+    }
+    apply_failover(&state, &config).await?;
+    *state.failover_config.write().await = config.clone();
+
+    let store = StoreBuilder::new(&app, STORE_FILENAME)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    store.set(keys::FAILOVER, value);
+    store.save().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Get the probe results and active upstream of the failover group
+#[tauri::command]
+pub async fn get_failover_status(state: State<'_, AppState>) -> Result<FailoverStatus, String> {
+    Ok(state.failover.status().await)
+}
+
+/// Start failover across the configured profiles, or stop it when disabled
+pub(crate) async fn apply_failover(
+    state: &AppState,
+    config: &FailoverConfig,
+) -> Result<(), String> {
+    config.validate()?;
+    if !config.enabled {
+        state.failover.stop().await;
+        return Ok(());
+    }
+
+    let members = {
+        let profiles = state.proxy_profiles.read().await;
+        config
+            .profile_ids
+            .iter()
+            .map(|id| {
+                let profile = profiles
+                    .get(id)
+                    .ok_or_else(|| format!("Profile not found: {}", id))?;
+                Ok(FailoverMember {
+                    profile_id: id.clone(),
+                    name: profile.name.clone(),
+                    config: profile_config(profile),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?
+    };
+
+    state.failover.start(config.clone(), members).await
+}
+
+/// Get the load balancer settings
+#[tauri::command]
+pub async fn get_balancer_config(state: State<'_, AppState>) -> Result<BalancerConfig, String> {
+    Ok(state.balancer_config.read().await.clone())
+}
+
+/// Save the load balancer settings and start or stop balancing accordingly
+#[tauri::command]
+pub async fn set_balancer_config(
+    config: BalancerConfig,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    log::info!(
+        "Setting load balancer config: enabled={}, strategy={:?}",
+        config.enabled,
+        config.strategy
+    );
+    if config.enabled && state.failover_config.read().await.enabled {
+        return Err("Disable failover before enabling load balancing".to_string());
+    }
+    let mut config = config;
+    if config.selected_profile_id.is_none() {
+        config.selected_profile_id = state
+            .balancer_config
+            .read()
+            .await
+            .selected_profile_id
+            .clone();
+    }
+    apply_balancer(&state, &config).await?;
+    *state.balancer_config.write().await = config.clone();
+
+    let store = StoreBuilder::new(&app, STORE_FILENAME)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    store.set(keys::BALANCER, value);
+    store.save().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Get the probe results of every balanced proxy and the current selection
+#[tauri::command]
+pub async fn get_balancer_status(state: State<'_, AppState>) -> Result<BalancerStatus, String> {
+    Ok(state.balancer.status().await)
+}
+
+/// Start balancing across every saved profile, or stop it when disabled
+pub(crate) async fn apply_balancer(
+    state: &AppState,
+    config: &BalancerConfig,
+) -> Result<(), String> {
+    config.validate()?;
+    if !config.enabled {
+        state.balancer.stop().await;
+        return Ok(());
+    }
+
+    let members = state
+        .proxy_profiles
+        .read()
+        .await
+        .list()
+        .iter()
+        .map(|profile| BalancerMember {
+            profile_id: profile.id.clone(),
+            name: profile.name.clone(),
+            config: profile_config(profile),
+        })
+        .collect();
+
+    state.balancer.start(config.clone(), members).await
 }
 
-/// Get proxy presets
+/// Get the split routing rules
 #[tauri::command]
-pub async fn get_proxy_presets() -> Result<Vec<ProxyPreset>, String> {
-    // Return some example presets - in production these would come from storage or API
-    Ok(vec![ProxyPreset {
-        name: "Custom Server".to_string(),
-        proxy_type: ProxyType::Socks5,
-        host: String::new(),
-        port: 1080,
-        requires_auth: false,
-        location: None,
-    }])
+pub async fn get_routing_config(state: State<'_, AppState>) -> Result<RoutingConfig, String> {
+    Ok(state.routing_config.read().await.clone())
+}
+
+/// Save the split routing rules and apply them to new connections
+#[tauri::command]
+pub async fn set_routing_config(
+    config: RoutingConfig,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    log::info!(
+        "Setting routing config: enabled={}, {} rules",
+        config.enabled,
+        config.rules.len()
+    );
+    apply_routing(&state, &config).await?;
+    *state.routing_config.write().await = config.clone();
+
+    let store = StoreBuilder::new(&app, STORE_FILENAME)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    store.set(keys::ROUTING, value);
+    store.save().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Compile the routing rules and hand them to the proxy manager, or clear them when disabled
+pub(crate) async fn apply_routing(state: &AppState, config: &RoutingConfig) -> Result<(), String> {
+    let routing = if config.enabled {
+        Some(std::sync::Arc::new(RoutingTable::compile(config)?))
+    } else {
+        config.validate()?;
+        None
+    };
+    state.proxy_manager.set_routing(routing).await;
+    Ok(())
+}
+
+/// Connection settings of a saved profile with its passwords loaded from the keyring
+fn profile_config(profile: &ProxyPreset) -> ProxyConfig {
+    let mut config = profile.to_config();
+    if config.uses_password() {
+        config.password = get_profile_password(&profile.id).ok();
+    }
+    load_chain_passwords(&mut config);
+    config
 }
 
 /// Save advanced settings
@@ -164,10 +651,94 @@ pub async fn save_advanced_settings(
     app: tauri::AppHandle,
 ) -> Result<(), String> {
     log::info!("Saving advanced settings");
-    *state.advanced_settings.write().await = settings;
     let store = StoreBuilder::new(&app, STORE_FILENAME)
         .build()
         .map_err(|e| e.to_string())?;
+    if state.advanced_settings.read().await.custom_dns != settings.custom_dns {
+        // The shorthand only carries the servers; keep the cache settings and host overrides
+        let mut config = state.dns_config.read().await.clone();
+        match settings.custom_dns.as_deref().map(str::trim) {
+            Some(custom_dns) if !custom_dns.is_empty() => {
+                config.servers = DnsConfig::from_custom_dns(custom_dns)?.servers;
+                config.enabled = true;
+            }
+            _ => config.enabled = false,
+        }
+        apply_dns(&state, &config).await?;
+        store.set(
+            keys::DNS,
+            serde_json::to_value(&config).map_err(|e| e.to_string())?,
+        );
+        *state.dns_config.write().await = config;
+    }
+    state
+        .proxy_manager
+        .set_kill_switch(settings.kill_switch)
+        .await;
+    *state.advanced_settings.write().await = settings;
+    let value = serde_json::to_value(state.advanced_settings.read().await.clone())
+        .map_err(|e| e.to_string())?;
+    store.set(keys::ADVANCED_SETTINGS, value);
+    store.save().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Get the custom DNS servers
+#[tauri::command]
+pub async fn get_dns_config(state: State<'_, AppState>) -> Result<DnsConfig, String> {
+    Ok(state.dns_config.read().await.clone())
+}
+
+/// Save the custom DNS servers and host overrides and resolve through them
+#[tauri::command]
+pub async fn set_dns_config(
+    config: DnsConfig,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    log::info!(
+        "Setting DNS config: enabled={}, {} servers, {} host overrides",
+        config.enabled,
+        config.servers.len(),
+        config.hosts.len()
+    );
+    save_dns_config(&state, &app, config).await
+}
+
+/// Merge a file in `/etc/hosts` format into the host overrides; names already
+/// overridden take the addresses from the file
+#[tauri::command]
+pub async fn import_hosts_file(
+    path: String,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<DnsConfig, String> {
+    log::info!("Importing host overrides from {}", path);
+    let contents =
+        std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let imported = parse_hosts_file(&contents)?;
+
+    let mut config = state.dns_config.read().await.clone();
+    merge_host_overrides(&mut config.hosts, imported);
+    save_dns_config(&state, &app, config.clone()).await?;
+    Ok(config)
+}
+
+/// Apply and persist `config`, keeping the `custom_dns` shorthand in step
+async fn save_dns_config(
+    state: &AppState,
+    app: &tauri::AppHandle,
+    config: DnsConfig,
+) -> Result<(), String> {
+    apply_dns(state, &config).await?;
+    *state.dns_config.write().await = config.clone();
+    state.advanced_settings.write().await.custom_dns = config.to_custom_dns();
+
+    let store = StoreBuilder::new(app, STORE_FILENAME)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    store.set(keys::DNS, value);
     let value = serde_json::to_value(state.advanced_settings.read().await.clone())
         .map_err(|e| e.to_string())?;
     store.set(keys::ADVANCED_SETTINGS, value);
@@ -175,22 +746,216 @@ pub async fn save_advanced_settings(
     Ok(())
 }
 
+/// Contents and hit rate of the DNS cache; `None` when the system resolver is used
+/// or caching is off
+#[tauri::command]
+pub async fn get_dns_cache_stats(
+    state: State<'_, AppState>,
+) -> Result<Option<DnsCacheStats>, String> {
+    Ok(state
+        .proxy_manager
+        .resolver()
+        .and_then(|resolver| resolver.cache_stats()))
+}
+
+/// Compare the answers for the Figma hosts from the system resolver, each configured
+/// DNS server and an encrypted resolver, flagging signs of DNS poisoning
+#[tauri::command]
+pub async fn check_dns_poisoning(state: State<'_, AppState>) -> Result<PoisoningReport, String> {
+    log::info!("Checking DNS answers for poisoning");
+    let config = state.dns_config.read().await.clone();
+    let report = crate::network::check_dns_poisoning(&config, FIGMA_DOMAINS).await;
+    if report.recommend_encrypted_dns {
+        log::warn!(
+            "DNS answers look tampered with ({}); encrypted DNS is recommended",
+            report.suspicious_resolvers.join(", ")
+        );
+    }
+    Ok(report)
+}
+
+/// Whether the local stub DNS server is running, with its settings
+#[tauri::command]
+pub async fn get_dns_stub_status(
+    state: State<'_, AppState>,
+) -> Result<(DnsStubConfig, DnsStubStatus), String> {
+    Ok((
+        state.dns_stub_config.read().await.clone(),
+        state.dns_stub.status().await,
+    ))
+}
+
+/// Start the local stub DNS server on `127.0.0.1:port` and start it with the app from now on
+#[tauri::command]
+pub async fn start_dns_stub(
+    port: u16,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<DnsStubStatus, String> {
+    log::info!("Starting DNS stub server on port {}", port);
+    if state.proxy_manager.resolver().is_none() {
+        return Err("Configure custom DNS servers before starting the DNS stub server".to_string());
+    }
+    state
+        .dns_stub
+        .start(port, state.proxy_manager.subscribe_resolver())
+        .await
+        .map_err(|e| format!("Failed to listen on port {}: {}", port, e))?;
+    save_dns_stub_config(
+        &state,
+        &app,
+        DnsStubConfig {
+            enabled: true,
+            port,
+        },
+    )
+    .await?;
+    Ok(state.dns_stub.status().await)
+}
+
+/// Stop the local stub DNS server and keep it stopped on the next launch
+#[tauri::command]
+pub async fn stop_dns_stub(
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    log::info!("Stopping DNS stub server");
+    state.dns_stub.stop().await;
+    let config = DnsStubConfig {
+        enabled: false,
+        ..state.dns_stub_config.read().await.clone()
+    };
+    save_dns_stub_config(&state, &app, config).await
+}
+
+/// Queries answered by the stub DNS server, newest first
+#[tauri::command]
+pub async fn get_dns_query_log(
+    state: State<'_, AppState>,
+) -> Result<Vec<DnsQueryLogEntry>, String> {
+    Ok(state.dns_stub.query_log())
+}
+
+/// Empty the stub DNS server's query log
+#[tauri::command]
+pub async fn clear_dns_query_log(state: State<'_, AppState>) -> Result<(), String> {
+    state.dns_stub.clear_query_log();
+    Ok(())
+}
+
+async fn save_dns_stub_config(
+    state: &AppState,
+    app: &tauri::AppHandle,
+    config: DnsStubConfig,
+) -> Result<(), String> {
+    *state.dns_stub_config.write().await = config.clone();
+    let store = StoreBuilder::new(app, STORE_FILENAME)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    store.set(keys::DNS_STUB, value);
+    store.save().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Resolve through the configured servers and host overrides, or the system resolver
+/// when neither is set
+pub(crate) async fn apply_dns(state: &AppState, config: &DnsConfig) -> Result<(), String> {
+    let resolver = if config.enabled || !config.hosts.is_empty() {
+        Some(DnsResolver::new(config)?)
+    } else {
+        config.validate()?;
+        None
+    };
+    state
+        .proxy_manager
+        .set_resolver(resolver)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 /// Get advanced settings
 #[tauri::command]
 pub async fn get_advanced_settings(state: State<'_, AppState>) -> Result<AdvancedSettings, String> {
     Ok(state.advanced_settings.read().await.clone())
 }
 
+/// Get the health check settings
+#[tauri::command]
+pub async fn get_health_check_config(
+    state: State<'_, AppState>,
+) -> Result<HealthCheckConfig, String> {
+    Ok(state.health_monitor.config().await)
+}
+
+/// Save the health check settings; the running monitor picks them up right away
+#[tauri::command]
+pub async fn set_health_check_config(
+    config: HealthCheckConfig,
+    state: State<'_, AppState>,
+    app: tauri::AppHandle,
+) -> Result<(), String> {
+    log::info!("Setting health check config");
+    state.health_monitor.set_config(config.clone()).await?;
+
+    let store = StoreBuilder::new(&app, STORE_FILENAME)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(config).map_err(|e| e.to_string())?;
+    store.set(keys::HEALTH_CHECK, value);
+    store.save().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
 /// Trigger manual health check
 #[tauri::command]
 pub async fn trigger_health_check(state: State<'_, AppState>) -> Result<bool, String> {
-    Ok(state.health_monitor.check_health().await)
+    Ok(state.health_monitor.check_now().await)
+}
+
+/// Whether the health monitor is running, paused or stopped, with its last result
+#[tauri::command]
+pub async fn get_health_monitor_status(
+    state: State<'_, AppState>,
+) -> Result<HealthMonitorStatus, String> {
+    Ok(state.health_monitor.status().await)
+}
+
+/// Start the periodic health checks
+#[tauri::command]
+pub async fn start_health_monitor(state: State<'_, AppState>) -> Result<(), String> {
+    state.health_monitor.start().await;
+    Ok(())
+}
+
+/// Stop the periodic health checks until started again or the proxy is re-enabled
+#[tauri::command]
+pub async fn stop_health_monitor(state: State<'_, AppState>) -> Result<(), String> {
+    state.health_monitor.stop().await;
+    Ok(())
+}
+
+/// Skip the periodic health checks until resumed
+#[tauri::command]
+pub async fn pause_health_monitor(state: State<'_, AppState>) -> Result<(), String> {
+    state.health_monitor.pause();
+    Ok(())
+}
+
+/// Resume the periodic health checks with an immediate check
+#[tauri::command]
+pub async fn resume_health_monitor(state: State<'_, AppState>) -> Result<(), String> {
+    state.health_monitor.resume();
+    Ok(())
 }
 
 /// Clear application cache
 #[tauri::command]
-pub async fn clear_cache() -> Result<(), String> {
+pub async fn clear_cache(state: State<'_, AppState>) -> Result<(), String> {
     log::info!("Clearing application cache");
+    if let Some(resolver) = state.proxy_manager.resolver() {
+        resolver.clear_cache();
+    }
     // In a real implementation, this would clear WebView cache
     // For Tauri 2.0, this would involve webview-specific APIs
     Ok(())
@@ -215,8 +980,9 @@ pub async fn complete_first_run(state: State<'_, AppState>) -> Result<(), String
     Ok(())
 }
 
+/// Open the Figma window, routed through the local forwarding proxy when enabled
 #[tauri::command]
-pub async fn create_figma_window(app: tauri::AppHandle, proxy: String) -> Result<(), String> {
+pub async fn create_figma_window(app: tauri::AppHandle) -> Result<(), String> {
     const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/122.0.0.0 Safari/537.36";
 
     // Reuse existing window if already open
@@ -227,27 +993,13 @@ pub async fn create_figma_window(app: tauri::AppHandle, proxy: String) -> Result
         return Ok(());
     }
 
-    let settings = app.state::<AppState>().advanced_settings.read().await.clone();
+    let state = app.state::<AppState>();
+    let settings = state.advanced_settings.read().await.clone();
     let user_agent = settings
         .custom_user_agent
         .as_deref()
         .unwrap_or(DEFAULT_USER_AGENT);
 
-    // Parse and validate the proxy URL
-    let mut proxy_url = Url::parse(&proxy).map_err(|e| format!("Invalid Proxy URL: {}", e))?;
-
-    // Validate scheme and normalize
-    match proxy_url.scheme() {
-        "http" | "https" | "socks5" => {
-            if proxy_url.scheme() == "https" {
-                proxy_url
-                    .set_scheme("http")
-                    .map_err(|_| "Failed to normalize proxy scheme")?;
-            }
-        }
-        _ => return Err(format!("Unsupported proxy scheme: {}", proxy_url.scheme())),
-    }
-
     // Build the window with Figma as the EXTERNAL URL
     let mut builder = WebviewWindowBuilder::new(
         &app,
@@ -256,9 +1008,22 @@ pub async fn create_figma_window(app: tauri::AppHandle, proxy: String) -> Result
     )
     .title("Figma - Bypassed")
     .inner_size(1280.0, 800.0)
-    .proxy_url(proxy_url)
     .user_agent(user_agent);
 
+    // The webview always talks to the loopback forwarder, which adds the
+    // upstream credentials that webview engines drop from proxy URLs
+    match state.proxy_manager.local_proxy_url().await {
+        Some(local_proxy) => {
+            let proxy_url =
+                Url::parse(&local_proxy).map_err(|e| format!("Invalid Proxy URL: {}", e))?;
+            builder = builder.proxy_url(proxy_url);
+        }
+        None if settings.kill_switch => {
+            return Err("Kill switch is on: enable the proxy before opening Figma".to_string());
+        }
+        None => {}
+    }
+
     if settings.webrtc_protection {
         builder = builder.initialization_script(WEBRTC_PROTECTION_SCRIPT);
     }
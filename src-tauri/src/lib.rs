@@ -12,39 +12,69 @@ use tauri_plugin_store::StoreBuilder;
 
 pub mod commands;
 pub mod network;
+pub mod notifications;
 pub mod proxy;
 pub mod utils;
 
-use network::InterceptorConfig;
+use network::{
+    create_dns_stub, DnsConfig, DnsStubConfig, InterceptorConfig, InterceptorMatcher,
+    RoutingConfig, SharedDnsStub,
+};
+use notifications::{notify, NotificationKind, Notifier};
 use proxy::{
-    create_health_monitor, create_proxy_manager, HealthCheckConfig, ProxyConfig, SharedHealthMonitor,
-    SharedProxyManager,
+    create_failover_group, create_health_monitor, create_load_balancer, create_proxy_manager,
+    BalancerConfig, FailoverConfig, HealthCheckConfig, LinkState, ProxyConfig, ProxyPreset,
+    ProxyProfiles, SharedFailoverGroup, SharedHealthMonitor, SharedLoadBalancer,
+    SharedProxyManager, StatusChange,
 };
-use utils::{get_proxy_password, keys, AdvancedSettings, STORE_FILENAME};
+use utils::{get_config_password, keys, load_chain_passwords, AdvancedSettings, STORE_FILENAME};
 
 /// Global application state
 pub struct AppState {
     pub proxy_manager: SharedProxyManager,
     pub health_monitor: SharedHealthMonitor,
+    pub failover: SharedFailoverGroup,
+    pub failover_config: RwLock<FailoverConfig>,
+    pub balancer: SharedLoadBalancer,
+    pub balancer_config: RwLock<BalancerConfig>,
     pub interceptor_config: RwLock<InterceptorConfig>,
+    pub interceptor: RwLock<InterceptorMatcher>,
+    pub routing_config: RwLock<RoutingConfig>,
+    pub dns_config: RwLock<DnsConfig>,
+    pub dns_stub: SharedDnsStub,
+    pub dns_stub_config: RwLock<DnsStubConfig>,
     pub advanced_settings: RwLock<AdvancedSettings>,
+    pub proxy_profiles: RwLock<ProxyProfiles>,
     pub is_first_run: RwLock<bool>,
     pub tray_icon: Mutex<Option<TrayIcon>>,
+    pub notifier: Notifier,
 }
 
 impl AppState {
     pub fn new() -> Self {
         let proxy_manager = create_proxy_manager();
-        let health_monitor =
-            create_health_monitor(proxy_manager.clone(), Some(HealthCheckConfig::default()));
+        let health_monitor = create_health_monitor(proxy_manager.clone(), None);
+        let failover = create_failover_group(proxy_manager.clone());
+        let balancer = create_load_balancer(proxy_manager.clone());
 
         Self {
             proxy_manager,
             health_monitor,
+            failover,
+            failover_config: RwLock::new(FailoverConfig::default()),
+            balancer,
+            balancer_config: RwLock::new(BalancerConfig::default()),
             interceptor_config: RwLock::new(InterceptorConfig::default()),
+            interceptor: RwLock::new(InterceptorMatcher::default()),
+            routing_config: RwLock::new(RoutingConfig::default()),
+            dns_config: RwLock::new(DnsConfig::default()),
+            dns_stub: create_dns_stub(),
+            dns_stub_config: RwLock::new(DnsStubConfig::default()),
             advanced_settings: RwLock::new(AdvancedSettings::default()),
+            proxy_profiles: RwLock::new(ProxyProfiles::default()),
             is_first_run: RwLock::new(true),
             tray_icon: Mutex::new(None),
+            notifier: Notifier::new(),
         }
     }
 }
@@ -78,16 +108,100 @@ pub fn run() {
                     if let Ok(settings) = serde_json::from_value::<AdvancedSettings>(value) {
                         let state = app.state::<AppState>();
                         tauri::async_runtime::block_on(async {
+                            state.proxy_manager.set_kill_switch(settings.kill_switch).await;
                             *state.advanced_settings.write().await = settings;
                         });
                     }
                 }
 
+                // Older settings only have the `custom_dns` shorthand
+                let dns = store
+                    .get(keys::DNS)
+                    .and_then(|value| serde_json::from_value::<DnsConfig>(value).ok());
+                let state = app.state::<AppState>();
+                tauri::async_runtime::block_on(async {
+                    let dns = match dns {
+                        Some(config) => Ok(Some(config)),
+                        None => state
+                            .advanced_settings
+                            .read()
+                            .await
+                            .custom_dns
+                            .as_deref()
+                            .map(DnsConfig::from_custom_dns)
+                            .transpose(),
+                    };
+                    match dns {
+                        Ok(Some(config)) => {
+                            if let Err(err) = commands::apply_dns(&state, &config).await {
+                                log::warn!("Failed to apply custom DNS servers: {}", err);
+                            }
+                            *state.dns_config.write().await = config;
+                        }
+                        Ok(None) => {}
+                        Err(err) => log::warn!("Ignoring stored custom DNS servers: {}", err),
+                    }
+                });
+
+                if let Some(value) = store.get(keys::DNS_STUB) {
+                    if let Ok(config) = serde_json::from_value::<DnsStubConfig>(value) {
+                        let state = app.state::<AppState>();
+                        tauri::async_runtime::block_on(async {
+                            if config.enabled {
+                                let resolver = state.proxy_manager.subscribe_resolver();
+                                let started = state.dns_stub.start(config.port, resolver);
+                                if let Err(err) = started.await {
+                                    log::warn!("Failed to start the DNS stub server: {}", err);
+                                }
+                            }
+                            *state.dns_stub_config.write().await = config;
+                        });
+                    }
+                }
+
+                if let Some(value) = store.get(keys::PROXY_PRESETS) {
+                    if let Ok(profiles) = serde_json::from_value::<Vec<ProxyPreset>>(value) {
+                        let state = app.state::<AppState>();
+                        tauri::async_runtime::block_on(async {
+                            *state.proxy_profiles.write().await =
+                                ProxyProfiles::from_stored(profiles);
+                        });
+                    }
+                }
+
+                if let Some(value) = store.get(keys::INTERCEPTOR) {
+                    if let Ok(config) = serde_json::from_value::<InterceptorConfig>(value) {
+                        match config.compile() {
+                            Ok(matcher) => {
+                                let state = app.state::<AppState>();
+                                tauri::async_runtime::block_on(async {
+                                    *state.interceptor.write().await = matcher;
+                                    *state.interceptor_config.write().await = config;
+                                });
+                            }
+                            Err(err) => log::warn!("Ignoring stored interceptor config: {}", err),
+                        }
+                    }
+                }
+
+                if let Some(value) = store.get(keys::ROUTING) {
+                    if let Ok(config) = serde_json::from_value::<RoutingConfig>(value) {
+                        let state = app.state::<AppState>();
+                        tauri::async_runtime::block_on(async {
+                            if let Err(err) = commands::apply_routing(&state, &config).await {
+                                log::warn!("Failed to apply stored routing rules: {}", err);
+                            }
+                            *state.routing_config.write().await = config;
+                        });
+                    }
+                }
+
                 if let Some(value) = store.get(keys::PROXY_CONFIG) {
                     if let Ok(mut config) = serde_json::from_value::<ProxyConfig>(value) {
-                        if config.username.is_some() && config.password.is_none() {
-                            config.password = get_proxy_password(&config.host, config.port).ok();
+                        if config.uses_password() && config.password.is_none() {
+                            config.password = get_config_password(&config);
                         }
+                        load_chain_passwords(&mut config);
                         let state = app.state::<AppState>();
                         tauri::async_runtime::block_on(async {
                             if let Err(err) = state.proxy_manager.configure(config).await {
@@ -96,6 +210,41 @@ pub fn run() {
                         });
                     }
                 }
+
+                if let Some(value) = store.get(keys::HEALTH_CHECK) {
+                    if let Ok(config) = serde_json::from_value::<HealthCheckConfig>(value) {
+                        let state = app.state::<AppState>();
+                        tauri::async_runtime::block_on(async {
+                            if let Err(err) = state.health_monitor.set_config(config).await {
+                                log::warn!("Ignoring stored health check settings: {}", err);
+                            }
+                        });
+                    }
+                }
+
+                if let Some(value) = store.get(keys::FAILOVER) {
+                    if let Ok(config) = serde_json::from_value::<FailoverConfig>(value) {
+                        let state = app.state::<AppState>();
+                        tauri::async_runtime::block_on(async {
+                            if let Err(err) = commands::apply_failover(&state, &config).await {
+                                log::warn!("Failed to start proxy failover: {}", err);
+                            }
+                            *state.failover_config.write().await = config;
+                        });
+                    }
+                }
+
+                if let Some(value) = store.get(keys::BALANCER) {
+                    if let Ok(config) = serde_json::from_value::<BalancerConfig>(value) {
+                        let state = app.state::<AppState>();
+                        tauri::async_runtime::block_on(async {
+                            if let Err(err) = commands::apply_balancer(&state, &config).await {
+                                log::warn!("Failed to start load balancing: {}", err);
+                            }
+                            *state.balancer_config.write().await = config;
+                        });
+                    }
+                }
             } else {
                 log::warn!("Failed to open settings store");
             }
@@ -122,12 +271,158 @@ pub fn run() {
                 });
             }
 
+            // Forward kill switch transitions to the frontend and notify when it engages
+            let mut kill_switch = app.state::<AppState>().proxy_manager.subscribe_kill_switch();
+            let kill_switch_handle = handle.clone();
+            tauri::async_runtime::spawn(async move {
+                while kill_switch.changed().await.is_ok() {
+                    let state = kill_switch.borrow_and_update().clone();
+                    let event = if state.engaged {
+                        notify(
+                            &kill_switch_handle,
+                            NotificationKind::KillSwitch,
+                            "Kill switch engaged",
+                            state
+                                .reason
+                                .as_deref()
+                                .unwrap_or("Figma traffic is blocked until the proxy is back"),
+                        )
+                        .await;
+                        "kill-switch-engaged"
+                    } else {
+                        "kill-switch-released"
+                    };
+                    let _ = kill_switch_handle.emit(event, state);
+                }
+            });
+
+            // Forward connection state changes to the frontend and the tray tooltip, and
+            // notify when the connection is lost or comes back
+            let mut status_changes = app.state::<AppState>().health_monitor.subscribe();
+            let status_handle = handle.clone();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    let event = match status_changes.recv().await {
+                        Ok(event) => event,
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(_) => break,
+                    };
+                    if let Some(state) = status_handle.try_state::<AppState>() {
+                        let tooltip = match event.change {
+                            StatusChange::Connected | StatusChange::Recovered => {
+                                "Figma Free - Connected"
+                            }
+                            StatusChange::Degraded => "Figma Free - Degraded",
+                            StatusChange::Disconnected => "Figma Free - Disconnected",
+                        };
+                        if let Ok(tray) = state.tray_icon.lock() {
+                            if let Some(ref tray) = *tray {
+                                let _ = tray.set_tooltip(Some(tooltip));
+                            }
+                        }
+                    }
+                    match event.change {
+                        StatusChange::Disconnected => {
+                            let body = event.error.clone().unwrap_or_else(|| {
+                                "Figma cannot be reached through the proxy".to_string()
+                            });
+                            notify(
+                                &status_handle,
+                                NotificationKind::Disconnected,
+                                "Proxy connection lost",
+                                &body,
+                            )
+                            .await;
+                        }
+                        // Coming back from degraded follows no "connection lost", so it
+                        // gets no "restored" either
+                        StatusChange::Recovered
+                            if event.previous == Some(LinkState::Disconnected) =>
+                        {
+                            let body = match event.latency_ms {
+                                Some(latency) => {
+                                    format!("Figma is reachable again ({} ms)", latency)
+                                }
+                                None => "Figma is reachable again".to_string(),
+                            };
+                            notify(
+                                &status_handle,
+                                NotificationKind::Recovered,
+                                "Proxy connection restored",
+                                &body,
+                            )
+                            .await;
+                        }
+                        StatusChange::Connected
+                        | StatusChange::Degraded
+                        | StatusChange::Recovered => {}
+                    }
+                    let _ = status_handle.emit(event.change.event_name(), event);
+                }
+            });
+
+            // Forward failover switches to the frontend, notify and persist the new active proxy
+            let mut failover = app.state::<AppState>().failover.subscribe();
+            let failover_handle = handle.clone();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    let event = match failover.recv().await {
+                        Ok(event) => event,
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(_) => break,
+                    };
+                    if let Some(state) = failover_handle.try_state::<AppState>() {
+                        let mut config = state.proxy_manager.get_config().await;
+                        config.password = None;
+                        if let Ok(store) =
+                            StoreBuilder::new(&failover_handle, STORE_FILENAME).build()
+                        {
+                            if let Ok(value) = serde_json::to_value(config) {
+                                store.set(keys::PROXY_CONFIG, value);
+                                let _ = store.save();
+                            }
+                        }
+                    }
+                    notify(
+                        &failover_handle,
+                        NotificationKind::Failover,
+                        &format!("Switched to {}", event.to.name),
+                        &event.reason,
+                    )
+                    .await;
+                    let _ = failover_handle.emit("proxy-failover", event);
+                }
+            });
+
+            // Remember the load balancer's selection across restarts
+            let mut selection = app.state::<AppState>().balancer.subscribe_selection();
+            let balancer_handle = handle.clone();
+            tauri::async_runtime::spawn(async move {
+                while selection.changed().await.is_ok() {
+                    let selected = selection.borrow_and_update().clone();
+                    let Some(state) = balancer_handle.try_state::<AppState>() else {
+                        continue;
+                    };
+                    let config = {
+                        let mut config = state.balancer_config.write().await;
+                        config.selected_profile_id = selected;
+                        config.clone()
+                    };
+                    if let Ok(store) = StoreBuilder::new(&balancer_handle, STORE_FILENAME).build() {
+                        if let Ok(value) = serde_json::to_value(config) {
+                            store.set(keys::BALANCER, value);
+                            let _ = store.save();
+                        }
+                    }
+                }
+            });
+
             // Start health monitoring in background
             let state = app.state::<AppState>();
             let health_monitor = state.health_monitor.clone();
             tauri::async_runtime::spawn(async move {
                 health_monitor.start().await;
-                health_monitor.run_loop().await;
+                health_monitor.watch_proxy().await;
             });
 
             log::info!("Application setup complete");
@@ -139,12 +434,48 @@ pub fn run() {
             commands::get_proxy_status,
             commands::toggle_proxy,
             commands::get_proxy_config,
+            commands::get_system_proxy,
             commands::delete_proxy_credentials,
             commands::should_intercept_url,
+            commands::get_interceptor_config,
+            commands::set_interceptor_config,
             commands::get_proxy_presets,
+            commands::create_proxy_profile,
+            commands::update_proxy_profile,
+            commands::delete_proxy_profile,
+            commands::duplicate_proxy_profile,
+            commands::import_shadowsocks_uri,
+            commands::reorder_proxy_profiles,
+            commands::test_proxy_profile,
+            commands::activate_proxy_profile,
+            commands::get_failover_config,
+            commands::set_failover_config,
+            commands::get_failover_status,
+            commands::get_balancer_config,
+            commands::set_balancer_config,
+            commands::get_balancer_status,
+            commands::get_routing_config,
+            commands::set_routing_config,
+            commands::get_dns_config,
+            commands::set_dns_config,
+            commands::get_dns_cache_stats,
+            commands::import_hosts_file,
+            commands::check_dns_poisoning,
+            commands::get_dns_stub_status,
+            commands::start_dns_stub,
+            commands::stop_dns_stub,
+            commands::get_dns_query_log,
+            commands::clear_dns_query_log,
             commands::save_advanced_settings,
             commands::get_advanced_settings,
             commands::trigger_health_check,
+            commands::get_health_monitor_status,
+            commands::start_health_monitor,
+            commands::stop_health_monitor,
+            commands::pause_health_monitor,
+            commands::resume_health_monitor,
+            commands::get_health_check_config,
+            commands::set_health_check_config,
             commands::clear_cache,
             commands::get_app_version,
             commands::is_first_run,
@@ -196,11 +527,11 @@ fn setup_system_tray(handle: &tauri::AppHandle) -> Result<TrayIcon, Box<dyn std:
                             let is_enabled = state.proxy_manager.is_enabled().await;
                             if !is_enabled {
                                 let mut config = state.proxy_manager.get_config().await;
-                                if config.username.is_some() && config.password.is_none() {
-                                    let password =
-                                        get_proxy_password(&config.host, config.port).ok();
-                                    config.password = password;
+                                if config.uses_password() && config.password.is_none() {
+                                    config.password = get_config_password(&config);
                                 }
+                                config.enabled = true;
+                                load_chain_passwords(&mut config);
                                 let _ = state.proxy_manager.configure(config).await;
                             } else {
                                 let _ = state.proxy_manager.toggle(false).await;
@@ -1,64 +1,292 @@
 //! DNS utilities and custom resolver support
 
+use base64::Engine;
+use std::fmt;
+
+use crate::network::dns_cache::DnsCacheConfig;
+use crate::network::hosts::{HostOverride, HostTable};
 use std::net::{IpAddr, SocketAddr};
 
-/// DNS configuration
+/// How queries reach a DNS server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsTransport {
+    /// Plain DNS over UDP, retried over TCP when the answer is truncated
+    #[default]
+    Udp,
+    /// Plain DNS over TCP
+    Tcp,
+    /// DNS-over-HTTPS (RFC 8484)
+    Doh,
+    /// DNS-over-TLS (RFC 7858)
+    Dot,
+    /// DNS-over-QUIC (RFC 9250)
+    Doq,
+}
+
+impl DnsTransport {
+    /// Port used when the server address has none
+    pub fn default_port(self) -> u16 {
+        match self {
+            Self::Udp | Self::Tcp => 53,
+            Self::Doh => 443,
+            Self::Dot | Self::Doq => 853,
+        }
+    }
+
+    /// Whether the server is authenticated with TLS
+    pub fn is_encrypted(self) -> bool {
+        matches!(self, Self::Doh | Self::Dot | Self::Doq)
+    }
+}
+
+impl fmt::Display for DnsTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Udp => "UDP",
+            Self::Tcp => "TCP",
+            Self::Doh => "DoH",
+            Self::Dot => "DoT",
+            Self::Doq => "DoQ",
+        })
+    }
+}
+
+/// One DNS server
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsServer {
+    /// `ip` or `ip:port` (`[ipv6]:port`), or the endpoint URL for DoH
+    pub address: String,
+    #[serde(default)]
+    pub transport: DnsTransport,
+    /// Name the certificate must match for DoT and DoQ
+    #[serde(default)]
+    pub tls_name: Option<String>,
+    /// Base64 SHA-256 of the server certificate's SubjectPublicKeyInfo
+    #[serde(default)]
+    pub spki_pin: Option<String>,
+}
+
+impl DnsServer {
+    pub fn new(address: impl Into<String>, transport: DnsTransport) -> Self {
+        Self {
+            address: address.into(),
+            transport,
+            tls_name: None,
+            spki_pin: None,
+        }
+    }
+
+    pub fn with_tls_name(mut self, tls_name: impl Into<String>) -> Self {
+        self.tls_name = Some(tls_name.into());
+        self
+    }
+
+    /// Server address with the transport's default port filled in; `None` for DoH
+    /// and for addresses that are not IP literals
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        if self.transport == DnsTransport::Doh {
+            return None;
+        }
+        let address = self.address.trim();
+        address.parse::<SocketAddr>().ok().or_else(|| {
+            address
+                .trim_matches(|c| c == '[' || c == ']')
+                .parse::<IpAddr>()
+                .ok()
+                .map(|ip| SocketAddr::new(ip, self.transport.default_port()))
+        })
+    }
+
+    /// Decoded SPKI pin, if one is set
+    pub fn spki_pin_bytes(&self) -> Result<Option<[u8; 32]>, String> {
+        let Some(pin) = self.spki_pin.as_deref() else {
+            return Ok(None);
+        };
+        base64::engine::general_purpose::STANDARD
+            .decode(pin.trim())
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .map(Some)
+            .ok_or_else(|| format!("Invalid SPKI pin (expected base64 SHA-256): {}", pin))
+    }
+
+    /// Check that the address, TLS name and pin fit the transport
+    pub fn validate(&self) -> Result<(), String> {
+        if self.address.trim().is_empty() {
+            return Err("DNS server address is required".to_string());
+        }
+
+        match self.transport {
+            DnsTransport::Doh => {
+                match url::Url::parse(self.address.trim()) {
+                    Ok(parsed) if parsed.scheme() == "https" && parsed.host_str().is_some() => {}
+                    _ => return Err(format!("Invalid DoH URL: {}", self.address)),
+                }
+                if self.tls_name.is_some() {
+                    return Err("DoH servers take their TLS name from the URL".to_string());
+                }
+            }
+            transport => {
+                if self.socket_addr().is_none() {
+                    return Err(format!(
+                        "Invalid {} server address (expected an IP address and optional port): {}",
+                        transport, self.address
+                    ));
+                }
+                if !transport.is_encrypted() && (self.tls_name.is_some() || self.spki_pin.is_some())
+                {
+                    return Err(format!(
+                        "{} servers do not use TLS; remove the TLS name and SPKI pin",
+                        transport
+                    ));
+                }
+                if transport.is_encrypted() {
+                    let name = self
+                        .tls_name
+                        .as_deref()
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .ok_or_else(|| format!("{} servers need a TLS name", transport))?;
+                    if tokio_rustls::rustls::pki_types::ServerName::try_from(name).is_err() {
+                        return Err(format!("Invalid TLS name: {}", name));
+                    }
+                }
+            }
+        }
+
+        self.spki_pin_bytes().map(|_| ())
+    }
+
+    /// Entry in the `custom_dns` shorthand; the SPKI pin cannot be written there
+    pub fn to_custom_dns(&self) -> String {
+        let tls_name = self.tls_name.as_deref().unwrap_or_default();
+        match self.transport {
+            DnsTransport::Udp => self.address.clone(),
+            DnsTransport::Tcp => format!("tcp://{}", self.address),
+            DnsTransport::Doh => self.address.clone(),
+            DnsTransport::Dot => format!("tls://{}#{}", self.address, tls_name),
+            DnsTransport::Doq => format!("quic://{}#{}", self.address, tls_name),
+        }
+    }
+}
+
+/// DNS configuration, persisted under `keys::DNS`
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DnsConfig {
-    /// Custom DNS servers
-    pub servers: Vec<String>,
-    /// Use DNS over HTTPS
-    pub use_doh: bool,
-    /// DoH provider URL
-    pub doh_url: Option<String>,
+    /// Whether these servers replace the system resolver
+    #[serde(default)]
+    pub enabled: bool,
+    /// Servers tried in order
+    pub servers: Vec<DnsServer>,
+    /// Cache for the answers of these servers
+    #[serde(default)]
+    pub cache: DnsCacheConfig,
+    /// Names pinned to fixed addresses; applied even when the custom servers are disabled
+    #[serde(default)]
+    pub hosts: Vec<HostOverride>,
 }
 
 impl Default for DnsConfig {
     fn default() -> Self {
         Self {
+            enabled: false,
             servers: vec![
-                "1.1.1.1".to_string(), // Cloudflare
-                "8.8.8.8".to_string(), // Google
-                "9.9.9.9".to_string(), // Quad9
+                DnsServer::new(providers::CLOUDFLARE, DnsTransport::Udp),
+                DnsServer::new(providers::GOOGLE, DnsTransport::Udp),
+                DnsServer::new(providers::QUAD9, DnsTransport::Udp),
             ],
-            use_doh: false,
-            doh_url: Some("https://cloudflare-dns.com/dns-query".to_string()),
+            cache: DnsCacheConfig::default(),
+            hosts: Vec::new(),
         }
     }
 }
 
 impl DnsConfig {
-    /// Parse DNS servers into SocketAddr
+    /// Addresses of the servers queried over plain DNS
     pub fn get_socket_addrs(&self) -> Vec<SocketAddr> {
         self.servers
             .iter()
-            .filter_map(|s| s.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 53)))
+            .filter(|server| !server.transport.is_encrypted())
+            .filter_map(DnsServer::socket_addr)
             .collect()
     }
 
+    /// Parse the `custom_dns` advanced setting, a comma-separated list of servers:
+    /// `ip[:port]` (UDP), `tcp://ip[:port]`, `tls://ip[:port]#name` (DoT),
+    /// `quic://ip[:port]#name` (DoQ) or an `https://` DoH URL
+    pub fn from_custom_dns(custom_dns: &str) -> Result<Self, String> {
+        let servers = custom_dns
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|e| !e.is_empty())
+            .map(parse_custom_dns_entry)
+            .collect();
+
+        let config = Self {
+            enabled: true,
+            servers,
+            cache: DnsCacheConfig::default(),
+            hosts: Vec::new(),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The servers in `custom_dns` form, or `None` when disabled
+    pub fn to_custom_dns(&self) -> Option<String> {
+        self.enabled.then(|| {
+            self.servers
+                .iter()
+                .map(DnsServer::to_custom_dns)
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+    }
+
     /// Validate DNS configuration
     pub fn validate(&self) -> Result<(), String> {
-        if self.servers.is_empty() {
+        if self.enabled && self.servers.is_empty() {
             return Err("At least one DNS server is required".to_string());
         }
 
-        for server in &self.servers {
-            if server.parse::<IpAddr>().is_err() {
-                return Err(format!("Invalid DNS server address: {}", server));
-            }
+        for (index, server) in self.servers.iter().enumerate() {
+            server
+                .validate()
+                .map_err(|e| format!("DNS server {}: {}", index + 1, e))?;
         }
 
-        if self.use_doh && self.doh_url.is_none() {
-            return Err("DoH URL is required when DoH is enabled".to_string());
-        }
+        self.cache.validate()?;
+        HostTable::compile(&self.hosts).map(|_| ())
+    }
+}
 
-        Ok(())
+fn parse_custom_dns_entry(entry: &str) -> DnsServer {
+    if entry.starts_with("https://") {
+        return DnsServer::new(entry, DnsTransport::Doh);
+    }
+
+    let (transport, rest) = if let Some(rest) = entry.strip_prefix("tcp://") {
+        (DnsTransport::Tcp, rest)
+    } else if let Some(rest) = entry.strip_prefix("tls://") {
+        (DnsTransport::Dot, rest)
+    } else if let Some(rest) = entry.strip_prefix("quic://") {
+        (DnsTransport::Doq, rest)
+    } else {
+        (DnsTransport::Udp, entry)
+    };
+
+    match rest.split_once('#') {
+        Some((address, tls_name)) => DnsServer::new(address, transport).with_tls_name(tls_name),
+        None => DnsServer::new(rest, transport),
     }
 }
 
 /// Well-known DNS providers
 pub mod providers {
+    use super::{DnsServer, DnsTransport};
+
     pub const CLOUDFLARE: &str = "1.1.1.1";
     pub const CLOUDFLARE_SECONDARY: &str = "1.0.0.1";
     pub const GOOGLE: &str = "8.8.8.8";
@@ -69,4 +297,37 @@ pub mod providers {
     pub const CLOUDFLARE_DOH: &str = "https://cloudflare-dns.com/dns-query";
     pub const GOOGLE_DOH: &str = "https://dns.google/dns-query";
     pub const QUAD9_DOH: &str = "https://dns.quad9.net/dns-query";
+
+    pub const CLOUDFLARE_TLS_NAME: &str = "cloudflare-dns.com";
+    pub const GOOGLE_TLS_NAME: &str = "dns.google";
+    pub const QUAD9_TLS_NAME: &str = "dns.quad9.net";
+
+    pub const CLOUDFLARE_DOT: &str = "1.1.1.1:853";
+    pub const GOOGLE_DOT: &str = "8.8.8.8:853";
+    pub const QUAD9_DOT: &str = "9.9.9.9:853";
+
+    // Cloudflare, Google and Quad9 do not serve DNS-over-QUIC; AdGuard is the
+    // widely available public DoQ resolver
+    pub const ADGUARD_DOQ: &str = "94.140.14.14:853";
+    pub const ADGUARD_TLS_NAME: &str = "dns.adguard-dns.com";
+
+    /// Cloudflare over DNS-over-TLS
+    pub fn cloudflare_dot() -> DnsServer {
+        DnsServer::new(CLOUDFLARE_DOT, DnsTransport::Dot).with_tls_name(CLOUDFLARE_TLS_NAME)
+    }
+
+    /// Google over DNS-over-TLS
+    pub fn google_dot() -> DnsServer {
+        DnsServer::new(GOOGLE_DOT, DnsTransport::Dot).with_tls_name(GOOGLE_TLS_NAME)
+    }
+
+    /// Quad9 over DNS-over-TLS
+    pub fn quad9_dot() -> DnsServer {
+        DnsServer::new(QUAD9_DOT, DnsTransport::Dot).with_tls_name(QUAD9_TLS_NAME)
+    }
+
+    /// AdGuard over DNS-over-QUIC
+    pub fn adguard_doq() -> DnsServer {
+        DnsServer::new(ADGUARD_DOQ, DnsTransport::Doq).with_tls_name(ADGUARD_TLS_NAME)
+    }
 }
@@ -0,0 +1,349 @@
+//! DNS answer cache for the custom resolver
+//! Keeps answers for their TTL, remembers failed lookups for a while, and flags names
+//! that are looked up often so they can be refreshed shortly before they expire
+
+use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::{RData, Record, RecordType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a lookup that failed on every server is remembered
+const FAILURE_TTL: Duration = Duration::from_secs(5);
+
+/// Hits after which a name is refreshed before it expires
+const PREFETCH_MIN_HITS: u64 = 3;
+
+/// Share of the TTL left when a popular name is refreshed
+const PREFETCH_REMAINING: f64 = 0.1;
+
+/// DNS cache settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsCacheConfig {
+    /// Whether answers are cached
+    pub enabled: bool,
+    /// Maximum number of cached names; the least recently used are evicted first
+    pub max_entries: usize,
+    /// Upper bound for how long an answer is kept, whatever its TTL
+    pub max_ttl_secs: u64,
+    /// Upper bound for how long NXDOMAIN and empty answers are kept
+    pub negative_ttl_secs: u64,
+    /// Refresh popular names before they expire
+    pub prefetch: bool,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: 1024,
+            max_ttl_secs: 86_400,
+            negative_ttl_secs: 300,
+            prefetch: true,
+        }
+    }
+}
+
+impl DnsCacheConfig {
+    /// Validate cache settings
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.max_entries == 0 {
+            return Err("DNS cache size must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// One cached lookup as shown to the user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsCacheEntry {
+    pub name: String,
+    pub record_type: String,
+    /// Answer records, empty for negative entries
+    pub answers: Vec<String>,
+    /// NXDOMAIN, an empty answer or a failed lookup
+    pub negative: bool,
+    /// Why the lookup failed, for failed lookups
+    pub error: Option<String>,
+    pub ttl_remaining_secs: u64,
+    pub hits: u64,
+}
+
+/// Cache contents and counters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsCacheStats {
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Share of lookups answered from the cache, between 0 and 1
+    pub hit_rate: f64,
+    pub prefetches: u64,
+    pub evictions: u64,
+    /// Entries, most used first
+    pub entries: Vec<DnsCacheEntry>,
+}
+
+/// Answer found in the cache
+pub struct CacheHit {
+    pub result: io::Result<Message>,
+    /// Whether the caller should refresh the entry in the background
+    pub prefetch: bool,
+}
+
+type Key = (String, RecordType);
+
+struct Entry {
+    result: Result<Message, (io::ErrorKind, String)>,
+    negative: bool,
+    stored: Instant,
+    ttl: Duration,
+    last_used: Instant,
+    hits: u64,
+    prefetching: bool,
+}
+
+impl Entry {
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.ttl.checked_sub(now.duration_since(self.stored))
+    }
+}
+
+/// Size-capped DNS cache shared by the clones of a resolver
+pub struct DnsCache {
+    config: DnsCacheConfig,
+    entries: Mutex<HashMap<Key, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    prefetches: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl DnsCache {
+    pub fn new(config: DnsCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            prefetches: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Cached answer for `name`, with its TTLs counted down, or `None` on a miss
+    pub fn get(&self, name: &str, record_type: RecordType) -> Option<CacheHit> {
+        let key = key(name, record_type);
+        let now = Instant::now();
+        let mut entries = self.entries.lock().ok()?;
+
+        let Some(entry) = entries.get_mut(&key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let Some(remaining) = entry.remaining(now) else {
+            entries.remove(&key);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+
+        entry.hits += 1;
+        entry.last_used = now;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        let prefetch = self.config.prefetch
+            && !entry.negative
+            && !entry.prefetching
+            && entry.hits >= PREFETCH_MIN_HITS
+            && remaining.as_secs_f64() <= entry.ttl.as_secs_f64() * PREFETCH_REMAINING;
+        if prefetch {
+            entry.prefetching = true;
+            self.prefetches.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let result = match entry.result {
+            Ok(ref message) => Ok(aged(message, elapsed)),
+            Err((kind, ref error)) => Err(io::Error::new(kind, error.clone())),
+        };
+        Some(CacheHit { result, prefetch })
+    }
+
+    /// Remember the outcome of a lookup; answers with a zero TTL are not kept
+    pub fn insert(&self, name: &str, record_type: RecordType, result: &io::Result<Message>) {
+        let (result, negative, ttl) = match result {
+            Ok(message) => {
+                let negative = is_negative(message);
+                let ttl = if negative {
+                    negative_ttl(message).min(self.config.negative_ttl_secs)
+                } else {
+                    positive_ttl(message).min(self.config.max_ttl_secs)
+                };
+                (Ok(message.clone()), negative, Duration::from_secs(ttl))
+            }
+            Err(e) => (Err((e.kind(), e.to_string())), true, FAILURE_TTL),
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        let key = key(name, record_type);
+        let now = Instant::now();
+        // Keep the hit count so a refreshed popular name stays popular
+        let hits = entries.get(&key).map(|entry| entry.hits).unwrap_or(0);
+
+        if !entries.contains_key(&key) && entries.len() >= self.config.max_entries {
+            self.evict(&mut entries, now);
+        }
+        entries.insert(
+            key,
+            Entry {
+                result,
+                negative,
+                stored: now,
+                ttl,
+                last_used: now,
+                hits,
+                prefetching: false,
+            },
+        );
+    }
+
+    /// Let the entry be prefetched again after a refresh that failed
+    pub fn prefetch_failed(&self, name: &str, record_type: RecordType) {
+        if let Ok(mut entries) = self.entries.lock() {
+            if let Some(entry) = entries.get_mut(&key(name, record_type)) {
+                entry.prefetching = false;
+            }
+        }
+    }
+
+    /// Drop every entry and reset the counters
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.clear();
+        }
+        for counter in [&self.hits, &self.misses, &self.prefetches, &self.evictions] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Current contents and counters
+    pub fn stats(&self) -> DnsCacheStats {
+        let now = Instant::now();
+        let mut entries: Vec<DnsCacheEntry> = self
+            .entries
+            .lock()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter_map(|((name, record_type), entry)| {
+                        let remaining = entry.remaining(now)?;
+                        let (answers, error) = match entry.result {
+                            Ok(ref message) => (
+                                message
+                                    .answers()
+                                    .iter()
+                                    .filter_map(|record| record.data().map(RData::to_string))
+                                    .collect(),
+                                None,
+                            ),
+                            Err((_, ref error)) => (Vec::new(), Some(error.clone())),
+                        };
+                        Some(DnsCacheEntry {
+                            name: name.clone(),
+                            record_type: record_type.to_string(),
+                            answers,
+                            negative: entry.negative,
+                            error,
+                            ttl_remaining_secs: remaining.as_secs(),
+                            hits: entry.hits,
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        entries.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.name.cmp(&b.name)));
+
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        DnsCacheStats {
+            capacity: self.config.max_entries,
+            hits,
+            misses,
+            hit_rate: if hits + misses == 0 {
+                0.0
+            } else {
+                hits as f64 / (hits + misses) as f64
+            },
+            prefetches: self.prefetches.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries,
+        }
+    }
+
+    /// Make room for one entry: drop expired entries, or else the least recently used one
+    fn evict(&self, entries: &mut HashMap<Key, Entry>, now: Instant) {
+        let before = entries.len();
+        entries.retain(|_, entry| entry.remaining(now).is_some());
+        if entries.len() == before {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        self.evictions
+            .fetch_add((before - entries.len()) as u64, Ordering::Relaxed);
+    }
+}
+
+fn key(name: &str, record_type: RecordType) -> Key {
+    (name.trim_end_matches('.').to_ascii_lowercase(), record_type)
+}
+
+/// NXDOMAIN, or a successful answer without any records (NODATA)
+fn is_negative(message: &Message) -> bool {
+    message.response_code() == ResponseCode::NXDomain || message.answers().is_empty()
+}
+
+/// Lowest TTL in the answer section
+fn positive_ttl(message: &Message) -> u64 {
+    message.answers().iter().map(Record::ttl).min().unwrap_or(0) as u64
+}
+
+/// Negative TTL from the SOA record in the authority section (RFC 2308), or the
+/// failure TTL when the server sent none
+fn negative_ttl(message: &Message) -> u64 {
+    message
+        .name_servers()
+        .iter()
+        .find_map(|record| match record.data() {
+            Some(RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+            _ => None,
+        })
+        .map(u64::from)
+        .unwrap_or(FAILURE_TTL.as_secs())
+}
+
+/// Copy of `message` with every TTL reduced by `elapsed` seconds
+fn aged(message: &Message, elapsed: u32) -> Message {
+    let mut message = message.clone();
+    for record in message.answers_mut() {
+        record.set_ttl(record.ttl().saturating_sub(elapsed));
+    }
+    for record in message.name_servers_mut() {
+        record.set_ttl(record.ttl().saturating_sub(elapsed));
+    }
+    message
+}
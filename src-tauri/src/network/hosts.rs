@@ -0,0 +1,320 @@
+//! Static host overrides
+//! Hosts-style table pinning names to fixed addresses before any DNS server is asked
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+/// Addresses a name always resolves to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostOverride {
+    /// Exact name, or `*.example.com` for every subdomain of `example.com`
+    pub name: String,
+    /// IPv4 and IPv6 addresses
+    pub addresses: Vec<String>,
+}
+
+/// Host overrides ready for lookups
+#[derive(Debug, Default)]
+pub struct HostTable {
+    exact: HashMap<String, Vec<IpAddr>>,
+    /// Wildcard suffixes, longest first so the most specific one wins
+    wildcards: Vec<(String, Vec<IpAddr>)>,
+}
+
+impl HostTable {
+    /// Parse `overrides`, rejecting malformed names and addresses and names listed twice
+    pub fn compile(overrides: &[HostOverride]) -> Result<Self, String> {
+        let mut table = Self::default();
+        let mut wildcard_names = HashSet::new();
+
+        for entry in overrides {
+            let name = normalize(&entry.name);
+            let (is_wildcard, domain) = match name.strip_prefix("*.") {
+                Some(domain) => (true, domain),
+                None => (false, name.as_str()),
+            };
+            if !is_valid_name(domain) {
+                return Err(format!("Invalid host name: {}", entry.name));
+            }
+            if entry.addresses.is_empty() {
+                return Err(format!("{} needs at least one address", entry.name));
+            }
+            let addresses = entry
+                .addresses
+                .iter()
+                .map(|address| {
+                    address
+                        .trim()
+                        .trim_matches(|c| c == '[' || c == ']')
+                        .parse::<IpAddr>()
+                        .map_err(|_| format!("Invalid address for {}: {}", entry.name, address))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+
+            let duplicate = if is_wildcard {
+                table.wildcards.push((domain.to_string(), addresses));
+                !wildcard_names.insert(domain.to_string())
+            } else {
+                table.exact.insert(name.clone(), addresses).is_some()
+            };
+            if duplicate {
+                return Err(format!("{} is listed more than once", entry.name));
+            }
+        }
+
+        table
+            .wildcards
+            .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Ok(table)
+    }
+
+    /// Addresses pinned for `host`; exact names take precedence over wildcards
+    pub fn lookup(&self, host: &str) -> Option<&[IpAddr]> {
+        let host = normalize(host);
+        if let Some(addresses) = self.exact.get(&host) {
+            return Some(addresses);
+        }
+        self.wildcards
+            .iter()
+            .find(|(suffix, _)| {
+                host.strip_suffix(suffix.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.') && prefix.len() > 1)
+            })
+            .map(|(_, addresses)| addresses.as_slice())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcards.is_empty()
+    }
+}
+
+/// Parse a file in `/etc/hosts` format: an address followed by one or more names per
+/// line, with `#` starting a comment. Names listed on several lines collect every address.
+/// Lines with a scoped IPv6 address (`fe80::1%lo0`) are skipped, as the zone cannot be kept.
+pub fn parse_hosts_file(contents: &str) -> Result<Vec<HostOverride>, String> {
+    let mut overrides: Vec<HostOverride> = Vec::new();
+    // Position of each name in `overrides`, so large block lists stay linear
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace().peekable();
+        let Some(address) = fields.next() else {
+            continue;
+        };
+        if fields.peek().is_none() {
+            return Err(format!(
+                "Line {}: no host name after {}",
+                index + 1,
+                address
+            ));
+        }
+        if let Some((ip, _zone)) = address.split_once('%') {
+            if ip.parse::<IpAddr>().is_ok_and(|ip| ip.is_ipv6()) {
+                log::debug!("Line {}: skipping scoped address {}", index + 1, address);
+                continue;
+            }
+        }
+        if address.parse::<IpAddr>().is_err() {
+            return Err(format!("Line {}: invalid address {}", index + 1, address));
+        }
+
+        for name in fields {
+            let name = normalize(name);
+            match positions.get(&name) {
+                Some(&position) => {
+                    let entry = &mut overrides[position];
+                    if !entry.addresses.iter().any(|a| a == address) {
+                        entry.addresses.push(address.to_string());
+                    }
+                }
+                None => {
+                    positions.insert(name.clone(), overrides.len());
+                    overrides.push(HostOverride {
+                        name,
+                        addresses: vec![address.to_string()],
+                    });
+                }
+            }
+        }
+    }
+
+    HostTable::compile(&overrides)?;
+    Ok(overrides)
+}
+
+/// Add `imported` to `hosts`, replacing the addresses of names already there
+pub fn merge_host_overrides(hosts: &mut Vec<HostOverride>, imported: Vec<HostOverride>) {
+    let mut positions: HashMap<String, usize> = hosts
+        .iter()
+        .enumerate()
+        .map(|(position, entry)| (entry.name.clone(), position))
+        .collect();
+
+    for entry in imported {
+        match positions.get(&entry.name) {
+            Some(&position) => hosts[position].addresses = entry.addresses,
+            None => {
+                positions.insert(entry.name.clone(), hosts.len());
+                hosts.push(entry);
+            }
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, addresses: &[&str]) -> HostOverride {
+        HostOverride {
+            name: name.to_string(),
+            addresses: addresses.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    fn ips(addresses: &[&str]) -> Vec<IpAddr> {
+        addresses.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_hosts_file() {
+        let parsed = parse_hosts_file(
+            "# comment\n\
+             127.0.0.1\tlocalhost Localhost.\n\
+             \n\
+             ::1 localhost  # trailing comment\n\
+             0.0.0.0 ads.example tracker.example\n\
+             0.0.0.0 ads.example\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                entry("localhost", &["127.0.0.1", "::1"]),
+                entry("ads.example", &["0.0.0.0"]),
+                entry("tracker.example", &["0.0.0.0"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_scoped_ipv6_lines() {
+        let parsed = parse_hosts_file(
+            "127.0.0.1 localhost\n\
+             fe80::1%lo0 localhost\n",
+        )
+        .unwrap();
+        assert_eq!(parsed, vec![entry("localhost", &["127.0.0.1"])]);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(
+            parse_hosts_file("127.0.0.1 ok\nnot-an-ip host\n").unwrap_err(),
+            "Line 2: invalid address not-an-ip"
+        );
+        assert_eq!(
+            parse_hosts_file("127.0.0.1\n").unwrap_err(),
+            "Line 1: no host name after 127.0.0.1"
+        );
+        assert_eq!(
+            parse_hosts_file("10.0.0.1%eth0 host\n").unwrap_err(),
+            "Line 1: invalid address 10.0.0.1%eth0"
+        );
+        assert!(parse_hosts_file("127.0.0.1 bad..name\n").is_err());
+    }
+
+    #[test]
+    fn parses_large_files() {
+        let contents: String = (0..100_000)
+            .map(|i| format!("0.0.0.0 host{}.example\n", i))
+            .collect();
+        assert_eq!(parse_hosts_file(&contents).unwrap().len(), 100_000);
+    }
+
+    #[test]
+    fn merge_replaces_existing_names() {
+        let mut hosts = vec![entry("a.example", &["10.0.0.1"])];
+        merge_host_overrides(
+            &mut hosts,
+            vec![
+                entry("a.example", &["10.0.0.2"]),
+                entry("b.example", &["10.0.0.3"]),
+            ],
+        );
+        assert_eq!(
+            hosts,
+            vec![
+                entry("a.example", &["10.0.0.2"]),
+                entry("b.example", &["10.0.0.3"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn exact_names_win_over_wildcards() {
+        let table = HostTable::compile(&[
+            entry("*.figma.com", &["10.0.0.1"]),
+            entry("www.figma.com", &["10.0.0.2"]),
+        ])
+        .unwrap();
+        assert_eq!(table.lookup("www.figma.com"), Some(&ips(&["10.0.0.2"])[..]));
+        assert_eq!(table.lookup("api.figma.com"), Some(&ips(&["10.0.0.1"])[..]));
+        // A wildcard covers subdomains only, not the domain itself
+        assert_eq!(table.lookup("figma.com"), None);
+    }
+
+    #[test]
+    fn most_specific_wildcard_wins() {
+        let table = HostTable::compile(&[
+            entry("*.figma.com", &["10.0.0.1"]),
+            entry("*.static.figma.com", &["10.0.0.2"]),
+        ])
+        .unwrap();
+        assert_eq!(
+            table.lookup("a.static.figma.com"),
+            Some(&ips(&["10.0.0.2"])[..])
+        );
+        assert_eq!(
+            table.lookup("static.figma.com"),
+            Some(&ips(&["10.0.0.1"])[..])
+        );
+        assert_eq!(table.lookup("A.Figma.COM."), Some(&ips(&["10.0.0.1"])[..]));
+        assert_eq!(table.lookup("notfigma.com"), None);
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        assert!(HostTable::compile(&[
+            entry("*.figma.com", &["10.0.0.1"]),
+            entry("*.FIGMA.com", &["10.0.0.2"]),
+        ])
+        .is_err());
+        assert!(HostTable::compile(&[
+            entry("figma.com", &["10.0.0.1"]),
+            entry("figma.com.", &["10.0.0.2"]),
+        ])
+        .is_err());
+    }
+}
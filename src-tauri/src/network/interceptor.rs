@@ -1,9 +1,17 @@
 //! Request interceptor for WebView traffic
 //! Handles routing requests through the proxy when enabled
 
+use regex::RegexSet;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
 /// Request interception configuration
+///
+/// Patterns are globs over `scheme://host[:port]/path` (query and fragment are ignored),
+/// or host-only globs such as `*.figma.com` or `localhost:3000` that match any scheme and path.
+/// `?` matches one character and `*` a run of characters within a host label or path segment;
+/// `**` also crosses `.` and `/`, so `**.figma.com` matches `figma.com` and all its subdomains.
+/// The scheme and port may be `*`; a pattern without a port matches any port.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct InterceptorConfig {
@@ -20,51 +28,206 @@ impl Default for InterceptorConfig {
         Self {
             enabled: true,
             intercept_patterns: vec![
-                "https://*.figma.com/*".to_string(),
-                "https://www.figma.com/*".to_string(),
+                "https://*.figma.com/**".to_string(),
+                "https://www.figma.com/**".to_string(),
             ],
             bypass_patterns: vec![
-                "https://fonts.googleapis.com/*".to_string(),
-                "https://fonts.gstatic.com/*".to_string(),
+                "https://fonts.googleapis.com/**".to_string(),
+                "https://fonts.gstatic.com/**".to_string(),
             ],
         }
     }
 }
 
 impl InterceptorConfig {
-    /// Check if a URL should be intercepted
+    /// Validate every pattern
+    pub fn validate(&self) -> Result<(), String> {
+        self.compile().map(|_| ())
+    }
+
+    /// Compile the patterns into a matcher
+    pub fn compile(&self) -> Result<InterceptorMatcher, String> {
+        Ok(InterceptorMatcher {
+            enabled: self.enabled,
+            intercept: compile_patterns(&self.intercept_patterns)?,
+            bypass: compile_patterns(&self.bypass_patterns)?,
+        })
+    }
+}
+
+/// Interceptor patterns compiled once, for checks on every connection
+#[derive(Debug, Clone)]
+pub struct InterceptorMatcher {
+    enabled: bool,
+    intercept: RegexSet,
+    bypass: RegexSet,
+}
+
+impl Default for InterceptorMatcher {
+    fn default() -> Self {
+        InterceptorConfig::default()
+            .compile()
+            .expect("default interceptor patterns are valid")
+    }
+}
+
+impl InterceptorMatcher {
+    /// Check if a URL should be intercepted; bypass patterns win over intercept patterns
     pub fn should_intercept(&self, url: &str) -> bool {
         if !self.enabled {
             return false;
         }
+        let Some(url) = normalize_url(url) else {
+            return false;
+        };
+        !self.bypass.is_match(&url) && self.intercept.is_match(&url)
+    }
+}
 
-        // Check bypass patterns first
-        for pattern in &self.bypass_patterns {
-            if Self::matches_pattern(url, pattern) {
-                return false;
-            }
+/// Render a URL as `scheme://host:port/path`, the form patterns are compiled against
+fn normalize_url(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?.trim_end_matches('.').to_ascii_lowercase();
+    let port = url.port_or_known_default()?;
+    Some(format!(
+        "{}://{}:{}{}",
+        url.scheme(),
+        host,
+        port,
+        url.path()
+    ))
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<RegexSet, String> {
+    let regexes = patterns
+        .iter()
+        .map(|pattern| {
+            pattern_to_regex(pattern.trim())
+                .map_err(|reason| format!("Invalid pattern \"{}\": {}", pattern, reason))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    RegexSet::new(regexes).map_err(|e| e.to_string())
+}
+
+/// Translate one pattern into an anchored regex over the normalized URL
+fn pattern_to_regex(pattern: &str) -> Result<String, String> {
+    if pattern.is_empty() {
+        return Err("pattern is empty".to_string());
+    }
+    if pattern.chars().any(char::is_whitespace) {
+        return Err("whitespace is not allowed".to_string());
+    }
+
+    let (scheme, rest) = match pattern.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme), rest),
+        None if pattern.contains('/') => {
+            return Err("expected scheme://host/path or a host-only pattern".to_string())
         }
+        None => (None, pattern),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], Some(&rest[index..])),
+        None => (rest, None),
+    };
+    let (host, port) = split_host_port(authority)?;
 
-        // Check intercept patterns
-        for pattern in &self.intercept_patterns {
-            if Self::matches_pattern(url, pattern) {
-                return true;
-            }
+    let scheme = match scheme {
+        None | Some("*") => "[a-z][a-z0-9+.-]*".to_string(),
+        Some(scheme)
+            if scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '.' | '-')) =>
+        {
+            regex::escape(&scheme.to_ascii_lowercase())
         }
+        Some(scheme) => return Err(format!("invalid scheme \"{}\"", scheme)),
+    };
 
-        false
+    if host.is_empty() {
+        return Err("host is empty".to_string());
     }
+    let host = host.to_ascii_lowercase();
+    if let Some(c) = host.chars().find(|&c| {
+        !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '*' | '?' | '[' | ']' | ':'))
+    }) {
+        return Err(format!("invalid character '{}' in host", c));
+    }
+    let host = glob_to_regex(&host, '.')?;
+
+    let port = match port {
+        None | Some("*") => "[0-9]+".to_string(),
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) if port > 0 => port.to_string(),
+            _ => return Err(format!("invalid port \"{}\"", port)),
+        },
+    };
+
+    let path = match path {
+        None => "/.*".to_string(),
+        Some(path) => glob_to_regex(path, '/')?,
+    };
+
+    Ok(format!("^{}://{}:{}{}$", scheme, host, port, path))
+}
 
-    /// Simple glob pattern matching
-    fn matches_pattern(url: &str, pattern: &str) -> bool {
-        let pattern = pattern.replace('.', r"\.");
-        let pattern = pattern.replace('*', ".*");
-        let regex = match regex::Regex::new(&format!("^{}$", pattern)) {
-            Ok(r) => r,
-            Err(_) => return false,
+/// Split `host[:port]`, keeping bracketed IPv6 literals whole
+fn split_host_port(authority: &str) -> Result<(&str, Option<&str>), String> {
+    if authority.starts_with('[') {
+        let end = authority
+            .find(']')
+            .ok_or_else(|| "unterminated IPv6 literal".to_string())?;
+        return match &authority[end + 1..] {
+            "" => Ok((&authority[..=end], None)),
+            rest => match rest.strip_prefix(':') {
+                Some(port) => Ok((&authority[..=end], Some(port))),
+                None => Err(format!("unexpected \"{}\" after IPv6 literal", rest)),
+            },
         };
-        regex.is_match(url)
     }
+    Ok(match authority.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+    })
+}
+
+/// Glob to regex for one URL component whose segments are divided by `separator`
+fn glob_to_regex(glob: &str, separator: char) -> Result<String, String> {
+    let sep = regex::escape(&separator.to_string());
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                if chars.get(i + 2) == Some(&'*') {
+                    return Err("\"***\" is not a valid wildcard".to_string());
+                }
+                if chars.get(i + 2) == Some(&separator) {
+                    // `**.` and `**/` also match zero segments
+                    regex.push_str(&format!("(?:.*{})?", sep));
+                    i += 3;
+                } else {
+                    regex.push_str(".*");
+                    i += 2;
+                }
+            }
+            '*' => {
+                regex.push_str(&format!("[^{}]*", sep));
+                i += 1;
+            }
+            '?' => {
+                regex.push_str(&format!("[^{}]", sep));
+                i += 1;
+            }
+            c => {
+                regex.push_str(&regex::escape(&c.to_string()));
+                i += 1;
+            }
+        }
+    }
+    Ok(regex)
 }
 
 /// JavaScript code to inject into WebView for request interception
@@ -151,3 +314,96 @@ pub const WEBRTC_PROTECTION_SCRIPT: &str = r#"
     console.log('[Figma Desktop] WebRTC protection enabled');
 })();
 "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(intercept: &[&str]) -> InterceptorMatcher {
+        InterceptorConfig {
+            enabled: true,
+            intercept_patterns: intercept.iter().map(|p| p.to_string()).collect(),
+            bypass_patterns: Vec::new(),
+        }
+        .compile()
+        .unwrap()
+    }
+
+    fn rejection(pattern: &str) -> String {
+        pattern_to_regex(pattern).unwrap_err()
+    }
+
+    #[test]
+    fn double_star_matches_apex_and_subdomains() {
+        let m = matcher(&["**.figma.com"]);
+        assert!(m.should_intercept("https://figma.com/"));
+        assert!(m.should_intercept("https://www.figma.com/file/abc"));
+        assert!(m.should_intercept("https://a.b.figma.com/"));
+        assert!(!m.should_intercept("https://notfigma.com/"));
+        assert!(!m.should_intercept("https://figma.com.evil.net/"));
+    }
+
+    #[test]
+    fn single_star_stays_within_a_label_or_segment() {
+        let m = matcher(&["https://*.figma.com/api/*"]);
+        assert!(m.should_intercept("https://www.figma.com/api/files"));
+        assert!(!m.should_intercept("https://a.b.figma.com/api/files"));
+        assert!(!m.should_intercept("https://figma.com/api/files"));
+        assert!(!m.should_intercept("https://www.figma.com/api/files/abc"));
+    }
+
+    #[test]
+    fn scheme_and_port_wildcards() {
+        let m = matcher(&["*://localhost:*/**"]);
+        assert!(m.should_intercept("http://localhost:3000/"));
+        assert!(m.should_intercept("wss://localhost/socket"));
+
+        let m = matcher(&["https://localhost:3000/**"]);
+        assert!(m.should_intercept("https://localhost:3000/a/b"));
+        assert!(!m.should_intercept("https://localhost:3001/a/b"));
+        assert!(!m.should_intercept("http://localhost:3000/a/b"));
+
+        // No port in the pattern matches any port, including the scheme default
+        let m = matcher(&["https://figma.com/**"]);
+        assert!(m.should_intercept("https://figma.com/"));
+        assert!(m.should_intercept("https://figma.com:8443/"));
+    }
+
+    #[test]
+    fn bracketed_ipv6_hosts() {
+        let m = matcher(&["https://[::1]/**", "http://[fe80::1]:8080"]);
+        assert!(m.should_intercept("https://[::1]/path"));
+        assert!(m.should_intercept("https://[::1]:8443/"));
+        assert!(m.should_intercept("http://[fe80::1]:8080/x"));
+        assert!(!m.should_intercept("http://[fe80::1]:8081/x"));
+        assert_eq!(rejection("https://[::1/"), "unterminated IPv6 literal");
+        assert_eq!(
+            rejection("https://[::1]x/"),
+            "unexpected \"x\" after IPv6 literal"
+        );
+    }
+
+    #[test]
+    fn rejection_messages() {
+        assert_eq!(
+            rejection("https://x.com/***"),
+            "\"***\" is not a valid wildcard"
+        );
+        assert_eq!(rejection("https://x.com:99999/"), "invalid port \"99999\"");
+        assert_eq!(rejection("https://x.com:0/"), "invalid port \"0\"");
+        assert_eq!(rejection("1http://x.com/"), "invalid scheme \"1http\"");
+        assert_eq!(rejection("ht_tp://x.com/"), "invalid scheme \"ht_tp\"");
+
+        let error = InterceptorConfig {
+            enabled: true,
+            intercept_patterns: vec!["https://x.com:abc/".to_string()],
+            bypass_patterns: Vec::new(),
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "Invalid pattern \"https://x.com:abc/\": invalid port \"abc\""
+        );
+    }
+}
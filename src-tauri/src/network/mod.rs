@@ -1,8 +1,21 @@
 //! Network module
-//! Handles DNS configuration and request interception
+//! Handles DNS configuration, resolution, caching, host overrides and poisoning checks,
+//! the local stub DNS server, request interception and split routing
 
 pub mod dns;
+pub mod dns_cache;
+pub mod hosts;
 pub mod interceptor;
+pub mod poisoning;
+pub mod resolver;
+pub mod routing;
+pub mod stub;
 
 pub use dns::*;
+pub use dns_cache::*;
+pub use hosts::*;
 pub use interceptor::*;
+pub use poisoning::*;
+pub use resolver::*;
+pub use routing::*;
+pub use stub::*;
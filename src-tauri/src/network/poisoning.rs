@@ -0,0 +1,340 @@
+//! DNS poisoning diagnostics
+//! Resolves the Figma hosts through the system resolver, each configured server and an
+//! encrypted reference, and reports answers that point somewhere they should not
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+use crate::network::dns::{providers, DnsConfig, DnsServer, DnsTransport};
+use crate::network::dns_cache::DnsCacheConfig;
+use crate::network::resolver::DnsResolver;
+
+/// Hosts the app needs: the web app, its API, static assets and image storage
+pub const FIGMA_DOMAINS: &[&str] = &[
+    "figma.com",
+    "www.figma.com",
+    "api.figma.com",
+    "static.figma.com",
+    "s3-alpha-sig.figma.com",
+];
+
+/// Limit for one lookup, so a hanging system resolver cannot stall the report
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ranges that are never a public answer for a Figma host
+const BOGON_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "100::/64",
+    "2001:db8::/32",
+    "ff00::/8",
+];
+
+/// Ranges reachable only inside a network
+const PRIVATE_RANGES: &[&str] = &[
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// Block pages that censoring and filtering resolvers are known to answer with
+const SINKHOLES: &[&str] = &[
+    // Iran
+    "10.10.34.34",
+    "10.10.34.35",
+    "10.10.34.36",
+    // Turkey (BTK)
+    "195.175.254.2",
+    // Cisco Umbrella / OpenDNS block pages
+    "146.112.61.104",
+    "146.112.61.105",
+    "146.112.61.106",
+    "146.112.61.107",
+    "146.112.61.108",
+    "146.112.61.110",
+];
+
+/// Why an address is suspicious
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AddressFlag {
+    /// Private, loopback, link-local or carrier-grade NAT address
+    Private,
+    /// Reserved, documentation or multicast address
+    Bogon,
+    /// Known block page of a filtering resolver
+    Sinkhole,
+}
+
+/// How far one resolver's answer can be trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AnswerVerdict {
+    /// Public addresses sharing at least one with the encrypted reference, or no
+    /// reference to compare with
+    Clean,
+    /// Public addresses, but none of the encrypted reference's; CDNs answering by
+    /// location cause this too, so it is a hint rather than proof
+    Mismatch,
+    /// At least one flagged address
+    Poisoned,
+    /// The lookup failed, or came back empty while the encrypted reference had addresses
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlaggedAddress {
+    pub address: String,
+    pub flag: AddressFlag,
+}
+
+/// One resolver's answer for one host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolverAnswer {
+    /// `system`, or the server address or URL
+    pub resolver: String,
+    /// `system`, `udp`, `tcp`, `doh`, `dot` or `doq`
+    pub transport: String,
+    pub addresses: Vec<String>,
+    pub flagged: Vec<FlaggedAddress>,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+    pub verdict: AnswerVerdict,
+}
+
+/// Every resolver's answer for one host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DomainReport {
+    pub domain: String,
+    /// Union of the clean addresses returned by encrypted resolvers
+    pub reference: Vec<String>,
+    pub answers: Vec<ResolverAnswer>,
+}
+
+/// Result of `check_dns_poisoning`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoisoningReport {
+    pub domains: Vec<DomainReport>,
+    /// Resolvers with a poisoned, mismatched or failed answer for any host
+    pub suspicious_resolvers: Vec<String>,
+    /// Whether switching to DoH, DoT or DoQ is advised: a plain resolver returned flagged
+    /// addresses or nothing while an encrypted one answered cleanly
+    pub recommend_encrypted_dns: bool,
+    pub checked_at: String,
+}
+
+/// A resolver taking part in the comparison
+#[derive(Clone)]
+struct Candidate {
+    label: String,
+    transport: Option<DnsTransport>,
+    resolver: Option<DnsResolver>,
+}
+
+impl Candidate {
+    fn is_encrypted(&self) -> bool {
+        self.transport.is_some_and(DnsTransport::is_encrypted)
+    }
+
+    fn transport_name(&self) -> String {
+        match self.transport {
+            Some(transport) => transport.to_string().to_ascii_lowercase(),
+            None => "system".to_string(),
+        }
+    }
+
+    async fn lookup(&self, domain: &str) -> Result<Vec<IpAddr>, String> {
+        let lookup = async {
+            match self.resolver {
+                Some(ref resolver) => resolver.lookup_ip(domain).await,
+                None => tokio::net::lookup_host((domain, 443))
+                    .await
+                    .map(|addrs| addrs.map(|addr| addr.ip()).collect()),
+            }
+        };
+        match tokio::time::timeout(LOOKUP_TIMEOUT, lookup).await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err(format!("Lookup of {} timed out", domain)),
+        }
+    }
+}
+
+/// Resolve `domains` through the system resolver, each server of `config` and, unless
+/// `config` already has one, Cloudflare's DoH endpoint, then compare the answers
+pub async fn check_dns_poisoning(config: &DnsConfig, domains: &[&str]) -> PoisoningReport {
+    let mut servers = config.servers.clone();
+    if !servers.iter().any(|server| server.transport.is_encrypted()) {
+        servers.push(DnsServer::new(providers::CLOUDFLARE_DOH, DnsTransport::Doh));
+    }
+
+    let mut candidates = vec![Candidate {
+        label: "system".to_string(),
+        transport: None,
+        resolver: None,
+    }];
+    for server in servers {
+        let single = DnsConfig {
+            enabled: true,
+            servers: vec![server.clone()],
+            cache: DnsCacheConfig {
+                enabled: false,
+                ..DnsCacheConfig::default()
+            },
+            hosts: Vec::new(),
+        };
+        match DnsResolver::new(&single) {
+            Ok(resolver) => candidates.push(Candidate {
+                label: server.address.clone(),
+                transport: Some(server.transport),
+                resolver: Some(resolver),
+            }),
+            Err(e) => log::warn!("Skipping DNS server {}: {}", server.address, e),
+        }
+    }
+
+    let mut lookups = JoinSet::new();
+    for (domain_index, domain) in domains.iter().enumerate() {
+        for (candidate_index, candidate) in candidates.iter().enumerate() {
+            let candidate = candidate.clone();
+            let domain = domain.to_string();
+            lookups.spawn(async move {
+                let started = Instant::now();
+                let result = candidate.lookup(&domain).await;
+                let latency_ms = started.elapsed().as_millis() as u64;
+                (domain_index, candidate_index, result, latency_ms)
+            });
+        }
+    }
+    let mut results = vec![vec![None; candidates.len()]; domains.len()];
+    while let Some(result) = lookups.join_next().await {
+        if let Ok((domain_index, candidate_index, result, latency_ms)) = result {
+            results[domain_index][candidate_index] = Some((result, latency_ms));
+        }
+    }
+
+    let bogons = parse_ranges(BOGON_RANGES);
+    let private = parse_ranges(PRIVATE_RANGES);
+    let classify = |ip: &IpAddr| -> Option<AddressFlag> {
+        if SINKHOLES.iter().any(|sinkhole| sinkhole.parse() == Ok(*ip)) {
+            Some(AddressFlag::Sinkhole)
+        } else if private.iter().any(|net| net.contains(ip)) {
+            Some(AddressFlag::Private)
+        } else if bogons.iter().any(|net| net.contains(ip)) {
+            Some(AddressFlag::Bogon)
+        } else {
+            None
+        }
+    };
+
+    let mut reports = Vec::new();
+    let mut suspicious = BTreeSet::new();
+    let mut plain_misbehaved = false;
+    let mut encrypted_clean = false;
+
+    for (domain, domain_results) in domains.iter().zip(results) {
+        // Clean addresses from encrypted resolvers are what the other answers are held to
+        let reference: BTreeSet<IpAddr> = candidates
+            .iter()
+            .zip(&domain_results)
+            .filter(|(candidate, _)| candidate.is_encrypted())
+            .filter_map(|(_, result)| result.as_ref()?.0.as_ref().ok())
+            .filter(|addrs| addrs.iter().all(|ip| classify(ip).is_none()))
+            .flatten()
+            .copied()
+            .collect();
+        encrypted_clean |= !reference.is_empty();
+
+        let answers = candidates
+            .iter()
+            .zip(domain_results)
+            .map(|(candidate, result)| {
+                let (result, latency_ms) =
+                    result.unwrap_or_else(|| (Err("Lookup did not finish".to_string()), 0));
+                let (addrs, error) = match result {
+                    Ok(addrs) => (addrs, None),
+                    Err(e) => (Vec::new(), Some(e)),
+                };
+                let flagged: Vec<FlaggedAddress> = addrs
+                    .iter()
+                    .filter_map(|ip| {
+                        classify(ip).map(|flag| FlaggedAddress {
+                            address: ip.to_string(),
+                            flag,
+                        })
+                    })
+                    .collect();
+
+                let verdict = if !flagged.is_empty() {
+                    AnswerVerdict::Poisoned
+                } else if error.is_some() || (addrs.is_empty() && !reference.is_empty()) {
+                    AnswerVerdict::Failed
+                } else if !reference.is_empty()
+                    && !candidate.is_encrypted()
+                    && !addrs.iter().any(|ip| reference.contains(ip))
+                {
+                    AnswerVerdict::Mismatch
+                } else {
+                    AnswerVerdict::Clean
+                };
+                if verdict != AnswerVerdict::Clean {
+                    suspicious.insert(candidate.label.clone());
+                }
+                // A failure only counts against the resolver when encrypted DNS got through
+                plain_misbehaved |= !candidate.is_encrypted()
+                    && (verdict == AnswerVerdict::Poisoned
+                        || (verdict == AnswerVerdict::Failed && !reference.is_empty()));
+
+                ResolverAnswer {
+                    resolver: candidate.label.clone(),
+                    transport: candidate.transport_name(),
+                    addresses: addrs.iter().map(IpAddr::to_string).collect(),
+                    flagged,
+                    error,
+                    latency_ms,
+                    verdict,
+                }
+            })
+            .collect();
+
+        reports.push(DomainReport {
+            domain: domain.to_string(),
+            reference: reference.iter().map(IpAddr::to_string).collect(),
+            answers,
+        });
+    }
+
+    PoisoningReport {
+        domains: reports,
+        suspicious_resolvers: suspicious.into_iter().collect(),
+        recommend_encrypted_dns: plain_misbehaved && encrypted_clean,
+        checked_at: chrono::Utc::now().to_rfc3339(),
+    }
+}
+
+fn parse_ranges(ranges: &[&str]) -> Vec<IpNet> {
+    ranges
+        .iter()
+        .filter_map(|range| range.parse().ok())
+        .collect()
+}
@@ -0,0 +1,660 @@
+//! DNS resolver built from `DnsConfig`
+//! Answers from the host overrides, then through the configured servers over UDP/TCP,
+//! DNS-over-HTTPS, DNS-over-TLS or DNS-over-QUIC (or the system resolver when custom servers
+//! are off), for the reqwest clients and for the proxy connections opened by the local forwarder
+
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, Error as TlsError, RootCertStore, SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::network::dns::{DnsConfig, DnsServer, DnsTransport};
+use crate::network::dns_cache::{DnsCache, DnsCacheStats};
+use crate::network::hosts::HostTable;
+
+/// How long to wait for one server before trying the next
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// EDNS payload size advertised over UDP (the DNS flag day recommendation)
+const UDP_PAYLOAD: u16 = 1232;
+
+/// TTL of answers made up from the host overrides
+const HOSTS_TTL: u32 = 60;
+
+/// ALPN protocol for DNS-over-QUIC (RFC 9250)
+const DOQ_ALPN: &[u8] = b"doq";
+
+/// Where queries are sent
+#[derive(Debug, Clone)]
+enum NameServer {
+    /// Plain DNS over UDP, retried over TCP when the answer is truncated
+    Udp(SocketAddr),
+    /// Plain DNS over TCP
+    Tcp(SocketAddr),
+    /// DNS-over-HTTPS (RFC 8484)
+    Https {
+        url: String,
+        client: reqwest::Client,
+    },
+    /// DNS-over-TLS (RFC 7858)
+    Tls {
+        addr: SocketAddr,
+        name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    },
+    /// DNS-over-QUIC (RFC 9250); the connection is kept open between queries
+    Quic {
+        addr: SocketAddr,
+        name: String,
+        config: quinn::ClientConfig,
+        connection: Arc<tokio::sync::Mutex<Option<quinn::Connection>>>,
+    },
+}
+
+impl NameServer {
+    fn new(server: &DnsServer) -> Result<Self, String> {
+        let pin = server.spki_pin_bytes()?;
+        let addr = server.socket_addr();
+        let tls_name = server
+            .tls_name
+            .as_deref()
+            .map(str::trim)
+            .unwrap_or_default();
+
+        Ok(match (server.transport, addr) {
+            (DnsTransport::Udp, Some(addr)) => Self::Udp(addr),
+            (DnsTransport::Tcp, Some(addr)) => Self::Tcp(addr),
+            (DnsTransport::Doh, _) => {
+                let mut builder = reqwest::Client::builder().no_proxy().timeout(QUERY_TIMEOUT);
+                if pin.is_some() {
+                    builder = builder.use_preconfigured_tls(tls_config(pin, &[b"http/1.1"])?);
+                }
+                Self::Https {
+                    url: server.address.trim().to_string(),
+                    client: builder.build().map_err(|e| e.to_string())?,
+                }
+            }
+            (DnsTransport::Dot, Some(addr)) => Self::Tls {
+                addr,
+                name: ServerName::try_from(tls_name.to_string()).map_err(|e| e.to_string())?,
+                config: Arc::new(tls_config(pin, &[])?),
+            },
+            (DnsTransport::Doq, Some(addr)) => {
+                let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls_config(
+                    pin,
+                    &[DOQ_ALPN],
+                )?)
+                .map_err(|e| e.to_string())?;
+                Self::Quic {
+                    addr,
+                    name: tls_name.to_string(),
+                    config: quinn::ClientConfig::new(Arc::new(crypto)),
+                    connection: Arc::new(tokio::sync::Mutex::new(None)),
+                }
+            }
+            (transport, None) => {
+                return Err(format!(
+                    "Invalid {} server address: {}",
+                    transport, server.address
+                ))
+            }
+        })
+    }
+
+    async fn exchange(&self, query: &Message) -> io::Result<Message> {
+        match self {
+            Self::Udp(addr) => {
+                let response = exchange_udp(*addr, query).await?;
+                if response.truncated() {
+                    exchange_tcp(*addr, query).await
+                } else {
+                    Ok(response)
+                }
+            }
+            Self::Tcp(addr) => exchange_tcp(*addr, query).await,
+            Self::Https { url, client } => exchange_https(client, url, query).await,
+            Self::Tls { addr, name, config } => {
+                let stream = TcpStream::connect(addr).await?;
+                let stream = TlsConnector::from(config.clone())
+                    .connect(name.clone(), stream)
+                    .await?;
+                exchange_stream(stream, query).await
+            }
+            Self::Quic {
+                addr,
+                name,
+                config,
+                connection,
+            } => exchange_quic(*addr, name, config, connection, query).await,
+        }
+    }
+}
+
+/// DNS resolver; cheap to clone, and clones share the cache
+#[derive(Clone)]
+pub struct DnsResolver {
+    /// Empty when the system resolver answers instead
+    servers: Arc<Vec<NameServer>>,
+    cache: Option<Arc<DnsCache>>,
+    hosts: Arc<HostTable>,
+}
+
+impl DnsResolver {
+    /// Build a resolver applying the host overrides and querying the configured servers
+    /// in order, or only applying the overrides when custom servers are disabled
+    pub fn new(config: &DnsConfig) -> Result<Self, String> {
+        config.validate()?;
+
+        let servers = if config.enabled {
+            config
+                .servers
+                .iter()
+                .map(NameServer::new)
+                .collect::<Result<Vec<_>, String>>()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            servers: Arc::new(servers),
+            cache: (config.enabled && config.cache.enabled)
+                .then(|| Arc::new(DnsCache::new(config.cache.clone()))),
+            hosts: Arc::new(HostTable::compile(&config.hosts)?),
+        })
+    }
+
+    /// Answer from the host overrides or the cache when possible, refreshing popular names
+    /// in the background shortly before they expire
+    pub async fn query(&self, name: &str, record_type: RecordType) -> io::Result<Message> {
+        if let Some(addrs) = self.hosts.lookup(name) {
+            return pinned_answer(name, record_type, addrs);
+        }
+        let Some(ref cache) = self.cache else {
+            return self.query_servers(name, record_type).await;
+        };
+
+        if let Some(hit) = cache.get(name, record_type) {
+            if hit.prefetch {
+                let resolver = self.clone();
+                let name = name.to_string();
+                tokio::spawn(async move {
+                    let result = resolver.query_servers(&name, record_type).await;
+                    if let Some(ref cache) = resolver.cache {
+                        match result {
+                            Ok(_) => cache.insert(&name, record_type, &result),
+                            Err(e) => {
+                                log::debug!("DNS prefetch for {} failed: {}", name, e);
+                                cache.prefetch_failed(&name, record_type);
+                            }
+                        }
+                    }
+                });
+            }
+            return hit.result;
+        }
+
+        let result = self.query_servers(name, record_type).await;
+        cache.insert(name, record_type, &result);
+        result
+    }
+
+    /// Cache contents and hit rate, or `None` when caching is off
+    pub fn cache_stats(&self) -> Option<DnsCacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Forget every cached answer
+    pub fn clear_cache(&self) {
+        if let Some(ref cache) = self.cache {
+            cache.clear();
+        }
+    }
+
+    /// Send a query for `name` to each server in turn until one answers.
+    /// NXDOMAIN and empty answers are returned as they are; other failures move on.
+    async fn query_servers(&self, name: &str, record_type: RecordType) -> io::Result<Message> {
+        let query = build_query(name, record_type)?;
+        let mut last_error = None;
+
+        for server in self.servers.iter() {
+            match tokio::time::timeout(QUERY_TIMEOUT, server.exchange(&query)).await {
+                Ok(Ok(response)) => match response.response_code() {
+                    ResponseCode::NoError | ResponseCode::NXDomain => return Ok(response),
+                    code => {
+                        last_error = Some(io::Error::other(format!(
+                            "DNS server answered {} for {}",
+                            code, name
+                        )))
+                    }
+                },
+                Ok(Err(e)) => last_error = Some(e),
+                Err(_) => {
+                    last_error = Some(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("DNS query for {} timed out", name),
+                    ))
+                }
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No DNS server configured")))
+    }
+
+    /// IPv4 and IPv6 addresses of `host`
+    pub async fn lookup_ip(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if let Ok(ip) = host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+        {
+            return Ok(vec![ip]);
+        }
+        if let Some(addrs) = self.hosts.lookup(host) {
+            return Ok(addrs.to_vec());
+        }
+        if self.servers.is_empty() {
+            return Ok(tokio::net::lookup_host((host, 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect());
+        }
+
+        let (v4, v6) = tokio::join!(
+            self.query(host, RecordType::A),
+            self.query(host, RecordType::AAAA)
+        );
+        let mut addrs = Vec::new();
+        let mut last_error = None;
+        for response in [v4, v6] {
+            match response {
+                Ok(response) => {
+                    addrs.extend(response.answers().iter().filter_map(
+                        |record| match record.data() {
+                            Some(RData::A(a)) => Some(IpAddr::V4(a.0)),
+                            Some(RData::AAAA(aaaa)) => Some(IpAddr::V6(aaaa.0)),
+                            _ => None,
+                        },
+                    ))
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No addresses found for {}", host),
+                )
+            }));
+        }
+        Ok(addrs)
+    }
+}
+
+impl reqwest::dns::Resolve for DnsResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs = resolver.lookup_ip(name.as_str()).await?;
+            let addrs: reqwest::dns::Addrs =
+                Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
+
+/// Resolve `host:port` through `resolver`, or the system resolver with `None`
+pub async fn resolve_socket_addrs(
+    resolver: Option<&DnsResolver>,
+    host: &str,
+    port: u16,
+) -> io::Result<Vec<SocketAddr>> {
+    match resolver {
+        Some(resolver) => Ok(resolver
+            .lookup_ip(host)
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect()),
+        None => Ok(tokio::net::lookup_host((host, port)).await?.collect()),
+    }
+}
+
+/// Connect to `host:port`, trying each address `resolver` returns in turn
+pub async fn connect_tcp(
+    resolver: Option<&DnsResolver>,
+    host: &str,
+    port: u16,
+) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in resolve_socket_addrs(resolver, host, port).await? {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No addresses found for {}", host),
+        )
+    }))
+}
+
+fn build_query(name: &str, record_type: RecordType) -> io::Result<Message> {
+    let fqdn = format!("{}.", name.trim_end_matches('.'));
+    let name = Name::from_utf8(&fqdn)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    let mut edns = Edns::new();
+    edns.set_max_payload(UDP_PAYLOAD);
+
+    let mut message = Message::new();
+    message
+        .set_id(random_id())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, record_type))
+        .set_edns(edns);
+    Ok(message)
+}
+
+/// Response carrying the overridden addresses of the requested family
+fn pinned_answer(name: &str, record_type: RecordType, addrs: &[IpAddr]) -> io::Result<Message> {
+    let mut message = build_query(name, record_type)?;
+    let name = message.queries()[0].name().clone();
+    let answers = addrs
+        .iter()
+        .filter_map(|ip| match (ip, record_type) {
+            (IpAddr::V4(ip), RecordType::A) => Some(RData::A((*ip).into())),
+            (IpAddr::V6(ip), RecordType::AAAA) => Some(RData::AAAA((*ip).into())),
+            _ => None,
+        })
+        .map(|data| Record::from_rdata(name.clone(), HOSTS_TTL, data))
+        .collect();
+    message
+        .set_message_type(MessageType::Response)
+        .set_recursion_available(true)
+        .set_authoritative(true)
+        .insert_answers(answers);
+    Ok(message)
+}
+
+fn random_id() -> u16 {
+    uuid::Uuid::new_v4().as_u128() as u16
+}
+
+fn encode(message: &Message) -> io::Result<Vec<u8>> {
+    message
+        .to_vec()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Message> {
+    Message::from_vec(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/// Whether `response` answers `query` (same ID and question), guarding against spoofed replies
+fn answers(query: &Message, response: &Message) -> bool {
+    response.message_type() == MessageType::Response
+        && response.id() == query.id()
+        && response.queries() == query.queries()
+}
+
+async fn exchange_udp(server: SocketAddr, query: &Message) -> io::Result<Message> {
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    socket.send(&encode(query)?).await?;
+
+    let mut buf = vec![0u8; UDP_PAYLOAD as usize];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Ignore stray datagrams that do not answer our question
+        if let Ok(response) = decode(&buf[..len]) {
+            if answers(query, &response) {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+async fn exchange_tcp(server: SocketAddr, query: &Message) -> io::Result<Message> {
+    exchange_stream(TcpStream::connect(server).await?, query).await
+}
+
+/// Send `query` with the two-byte length prefix used by DNS over TCP and TLS
+async fn exchange_stream<S>(mut stream: S, query: &Message) -> io::Result<Message>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&frame(query)?).await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    let response = decode(&buf)?;
+    if !answers(query, &response) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "DNS response does not match the query",
+        ));
+    }
+    Ok(response)
+}
+
+fn frame(query: &Message) -> io::Result<Vec<u8>> {
+    let bytes = encode(query)?;
+    let len = u16::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "DNS query too large"))?;
+
+    let mut framed = Vec::with_capacity(bytes.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(&bytes);
+    Ok(framed)
+}
+
+async fn exchange_https(
+    client: &reqwest::Client,
+    url: &str,
+    query: &Message,
+) -> io::Result<Message> {
+    // RFC 8484 recommends ID 0 so identical queries are cacheable
+    let mut query = query.clone();
+    query.set_id(0);
+
+    let response = client
+        .post(url)
+        .header("content-type", "application/dns-message")
+        .header("accept", "application/dns-message")
+        .body(encode(&query)?)
+        .send()
+        .await
+        .map_err(io::Error::other)?;
+    if !response.status().is_success() {
+        return Err(io::Error::other(format!(
+            "DoH server answered HTTP {}",
+            response.status()
+        )));
+    }
+
+    let body = response.bytes().await.map_err(io::Error::other)?;
+    let response = decode(&body)?;
+    if !answers(&query, &response) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "DNS response does not match the query",
+        ));
+    }
+    Ok(response)
+}
+
+async fn exchange_quic(
+    addr: SocketAddr,
+    name: &str,
+    config: &quinn::ClientConfig,
+    connection: &tokio::sync::Mutex<Option<quinn::Connection>>,
+    query: &Message,
+) -> io::Result<Message> {
+    // RFC 9250 requires ID 0; the stream identifies the exchange
+    let mut query = query.clone();
+    query.set_id(0);
+
+    let conn = {
+        let mut connection = connection.lock().await;
+        match connection.as_ref() {
+            Some(conn) if conn.close_reason().is_none() => conn.clone(),
+            _ => {
+                let conn = connect_quic(addr, name, config).await?;
+                *connection = Some(conn.clone());
+                conn
+            }
+        }
+    };
+
+    let (mut send, mut recv) = conn.open_bi().await.map_err(io::Error::other)?;
+    send.write_all(&frame(&query)?)
+        .await
+        .map_err(io::Error::other)?;
+    send.finish().map_err(io::Error::other)?;
+
+    let bytes = recv
+        .read_to_end(u16::MAX as usize + 2)
+        .await
+        .map_err(io::Error::other)?;
+    let len = bytes
+        .get(..2)
+        .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+        .filter(|len| bytes.len() == len + 2)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Truncated DoQ response"))?;
+    let response = decode(&bytes[2..2 + len])?;
+    if !answers(&query, &response) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "DNS response does not match the query",
+        ));
+    }
+    Ok(response)
+}
+
+async fn connect_quic(
+    addr: SocketAddr,
+    name: &str,
+    config: &quinn::ClientConfig,
+) -> io::Result<quinn::Connection> {
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let mut endpoint = quinn::Endpoint::client(bind)?;
+    endpoint.set_default_client_config(config.clone());
+    endpoint
+        .connect(addr, name)
+        .map_err(io::Error::other)?
+        .await
+        .map_err(io::Error::other)
+}
+
+/// rustls client configuration for an encrypted DNS server, checking the SPKI pin when set
+fn tls_config(pin: Option<[u8; 32]>, alpn: &[&[u8]]) -> Result<ClientConfig, String> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let roots = Arc::new(roots);
+
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let mut config = match pin {
+        Some(pin) => {
+            let inner = WebPkiServerVerifier::builder_with_provider(roots, provider)
+                .build()
+                .map_err(|e| e.to_string())?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier { inner, pin }))
+                .with_no_client_auth()
+        }
+        None => builder.with_root_certificates(roots).with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(config)
+}
+
+/// Verifies the chain as usual, then requires the server key to match the pin
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pin: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let cert = webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|e| TlsError::General(format!("Invalid server certificate: {}", e)))?;
+        let spki = cert.subject_public_key_info();
+        let digest = ring::digest::digest(&ring::digest::SHA256, spki.as_ref());
+        if digest.as_ref() != self.pin {
+            return Err(TlsError::General(
+                "DNS server key does not match the SPKI pin".to_string(),
+            ));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
@@ -0,0 +1,377 @@
+//! Split routing rules
+//! Decides per connection whether a target goes direct, through the proxy, or is blocked
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+use crate::network::resolver::{resolve_socket_addrs, DnsResolver};
+
+/// What to do with a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RouteAction {
+    /// Connect without the proxy
+    Direct,
+    /// Connect through the active proxy
+    Proxy,
+    /// Refuse the connection
+    Block,
+}
+
+/// One routing rule; every criterion that is set must match
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingRule {
+    /// Host patterns: `*`, exact names, or domains matching their subdomains too
+    /// (`figma.com`, `*.figma.com`, `.figma.com`)
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// IP ranges such as `10.0.0.0/8`; hostnames are resolved locally (through the
+    /// configured DNS servers, if any) to check them
+    #[serde(default)]
+    pub cidrs: Vec<String>,
+    /// Ports or port ranges such as `443` or `8000-8100`
+    #[serde(default)]
+    pub ports: Vec<String>,
+    /// Action when the rule matches
+    pub action: RouteAction,
+}
+
+/// Routing rules, persisted under `keys::ROUTING`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingConfig {
+    /// Whether the rules are applied
+    pub enabled: bool,
+    /// Rules in priority order; the first match wins
+    pub rules: Vec<RoutingRule>,
+    /// Action when no rule matches
+    pub default_action: RouteAction,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rules: Vec::new(),
+            default_action: RouteAction::Proxy,
+        }
+    }
+}
+
+impl RoutingConfig {
+    /// Validate every rule
+    pub fn validate(&self) -> Result<(), String> {
+        RoutingTable::compile(self).map(|_| ())
+    }
+}
+
+/// Rule with its criteria parsed
+#[derive(Debug)]
+struct CompiledRule {
+    hosts: Vec<String>,
+    cidrs: Vec<IpNet>,
+    ports: Vec<RangeInclusive<u16>>,
+    action: RouteAction,
+}
+
+impl CompiledRule {
+    fn matches_host(&self, host: &str) -> bool {
+        self.hosts.is_empty()
+            || self.hosts.iter().any(|pattern| {
+                if pattern == "*" {
+                    return true;
+                }
+                let suffix = pattern.trim_start_matches('*').trim_start_matches('.');
+                host == suffix || host.ends_with(&format!(".{}", suffix))
+            })
+    }
+
+    fn matches_port(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.iter().any(|range| range.contains(&port))
+    }
+
+    fn matches_ip(&self, addrs: &[IpAddr]) -> bool {
+        self.cidrs.is_empty()
+            || addrs
+                .iter()
+                .any(|ip| self.cidrs.iter().any(|net| net.contains(ip)))
+    }
+}
+
+/// Routing rules ready to be matched against connections
+#[derive(Debug)]
+pub struct RoutingTable {
+    rules: Vec<CompiledRule>,
+    default_action: RouteAction,
+}
+
+impl RoutingTable {
+    /// Parse the rules of `config`, rejecting malformed criteria
+    pub fn compile(config: &RoutingConfig) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                compile_rule(rule).map_err(|e| format!("Rule {}: {}", index + 1, e))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self {
+            rules,
+            default_action: config.default_action,
+        })
+    }
+
+    /// Action for a connection to `host:port`; CIDR rules resolve names through `resolver`
+    pub async fn route(
+        &self,
+        host: &str,
+        port: u16,
+        resolver: Option<&DnsResolver>,
+    ) -> RouteAction {
+        let host = host
+            .trim_matches(|c| c == '[' || c == ']')
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        // Resolved on the first CIDR rule that needs it
+        let mut addrs: Option<Vec<IpAddr>> = host.parse::<IpAddr>().ok().map(|ip| vec![ip]);
+
+        for rule in &self.rules {
+            if !rule.matches_host(&host) || !rule.matches_port(port) {
+                continue;
+            }
+            if !rule.cidrs.is_empty() && addrs.is_none() {
+                addrs = Some(resolve(resolver, &host, port).await);
+            }
+            if rule.matches_ip(addrs.as_deref().unwrap_or_default()) {
+                return rule.action;
+            }
+        }
+        self.default_action
+    }
+
+    /// Action for a request to `url`; URLs without a host take the default action
+    pub async fn route_url(&self, url: &str, resolver: Option<&DnsResolver>) -> RouteAction {
+        let Ok(url) = url::Url::parse(url) else {
+            return self.default_action;
+        };
+        match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => self.route(host, port, resolver).await,
+            _ => self.default_action,
+        }
+    }
+}
+
+fn compile_rule(rule: &RoutingRule) -> Result<CompiledRule, String> {
+    if rule.hosts.is_empty() && rule.cidrs.is_empty() && rule.ports.is_empty() {
+        return Err("at least one host, CIDR range or port is required".to_string());
+    }
+
+    let hosts = rule
+        .hosts
+        .iter()
+        .map(|pattern| {
+            let pattern = pattern.trim().trim_end_matches('.').to_ascii_lowercase();
+            let name = pattern.trim_start_matches('*').trim_start_matches('.');
+            if pattern != "*" && (name.is_empty() || name.contains(['*', '/', ':', ' '])) {
+                return Err(format!("invalid host pattern: {}", pattern));
+            }
+            Ok(pattern)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let cidrs = rule
+        .cidrs
+        .iter()
+        .map(|entry| {
+            let entry = entry.trim();
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("invalid CIDR range: {}", entry))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let ports = rule
+        .ports
+        .iter()
+        .map(|entry| parse_port_range(entry).ok_or_else(|| format!("invalid port: {}", entry)))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(CompiledRule {
+        hosts,
+        cidrs,
+        ports,
+        action: rule.action,
+    })
+}
+
+fn parse_port_range(entry: &str) -> Option<RangeInclusive<u16>> {
+    let entry = entry.trim();
+    let (start, end) = match entry.split_once('-') {
+        Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+        None => {
+            let port = entry.parse().ok()?;
+            (port, port)
+        }
+    };
+    (start > 0 && start <= end).then_some(start..=end)
+}
+
+async fn resolve(resolver: Option<&DnsResolver>, host: &str, port: u16) -> Vec<IpAddr> {
+    match resolve_socket_addrs(resolver, host, port).await {
+        Ok(addrs) => addrs.into_iter().map(|addr| addr.ip()).collect(),
+        Err(e) => {
+            log::debug!("Routing could not resolve {}: {}", host, e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(hosts: &[&str], cidrs: &[&str], ports: &[&str], action: RouteAction) -> RoutingRule {
+        RoutingRule {
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            cidrs: cidrs.iter().map(|c| c.to_string()).collect(),
+            ports: ports.iter().map(|p| p.to_string()).collect(),
+            action,
+        }
+    }
+
+    fn table(rules: Vec<RoutingRule>) -> RoutingTable {
+        RoutingTable::compile(&RoutingConfig {
+            enabled: true,
+            rules,
+            default_action: RouteAction::Proxy,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_host_patterns() {
+        let compiled = compile_rule(&rule(
+            &["Figma.com.", "*.cdn.example", ".static.example"],
+            &[],
+            &[],
+            RouteAction::Direct,
+        ))
+        .unwrap();
+        assert!(compiled.matches_host("figma.com"));
+        assert!(compiled.matches_host("www.figma.com"));
+        assert!(compiled.matches_host("cdn.example"));
+        assert!(compiled.matches_host("a.b.cdn.example"));
+        assert!(compiled.matches_host("static.example"));
+        assert!(!compiled.matches_host("notfigma.com"));
+        assert!(!compiled.matches_host("figma.com.evil"));
+
+        let wildcard = compile_rule(&rule(&["*"], &[], &[], RouteAction::Direct)).unwrap();
+        assert!(wildcard.matches_host("anything.example"));
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!(parse_port_range("443"), Some(443..=443));
+        assert_eq!(parse_port_range(" 8000 - 8100 "), Some(8000..=8100));
+        assert_eq!(parse_port_range("0"), None);
+        assert_eq!(parse_port_range("9000-8000"), None);
+        assert_eq!(parse_port_range("70000"), None);
+        assert_eq!(parse_port_range("http"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        let compile = |rule| {
+            RoutingTable::compile(&RoutingConfig {
+                enabled: true,
+                rules: vec![rule],
+                default_action: RouteAction::Proxy,
+            })
+            .unwrap_err()
+        };
+        assert_eq!(
+            compile(rule(&[], &[], &[], RouteAction::Block)),
+            "Rule 1: at least one host, CIDR range or port is required"
+        );
+        assert_eq!(
+            compile(rule(&["a*b.com"], &[], &[], RouteAction::Block)),
+            "Rule 1: invalid host pattern: a*b.com"
+        );
+        assert_eq!(
+            compile(rule(&[], &["10.0.0.0/33"], &[], RouteAction::Block)),
+            "Rule 1: invalid CIDR range: 10.0.0.0/33"
+        );
+        assert_eq!(
+            compile(rule(&[], &[], &["1-0"], RouteAction::Block)),
+            "Rule 1: invalid port: 1-0"
+        );
+    }
+
+    #[tokio::test]
+    async fn matches_cidr_ranges() {
+        let routing = table(vec![rule(
+            &[],
+            &["10.0.0.0/8", "fd00::/8", "192.168.1.1"],
+            &[],
+            RouteAction::Direct,
+        )]);
+        assert_eq!(
+            routing.route("10.1.2.3", 443, None).await,
+            RouteAction::Direct
+        );
+        assert_eq!(
+            routing.route("[fd00::1]", 443, None).await,
+            RouteAction::Direct
+        );
+        assert_eq!(
+            routing.route("192.168.1.1", 80, None).await,
+            RouteAction::Direct
+        );
+        assert_eq!(
+            routing.route("192.168.1.2", 80, None).await,
+            RouteAction::Proxy
+        );
+        assert_eq!(
+            routing.route("11.0.0.1", 443, None).await,
+            RouteAction::Proxy
+        );
+    }
+
+    #[tokio::test]
+    async fn first_matching_rule_wins() {
+        let routing = table(vec![
+            rule(
+                &["internal.example"],
+                &[],
+                &["8000-8100"],
+                RouteAction::Block,
+            ),
+            rule(&["internal.example"], &[], &[], RouteAction::Direct),
+            rule(&["*"], &[], &[], RouteAction::Block),
+        ]);
+        assert_eq!(
+            routing.route("internal.example", 8080, None).await,
+            RouteAction::Block
+        );
+        assert_eq!(
+            routing.route("INTERNAL.example.", 443, None).await,
+            RouteAction::Direct
+        );
+        assert_eq!(
+            routing.route("other.example", 443, None).await,
+            RouteAction::Block
+        );
+        assert_eq!(
+            routing
+                .route_url("https://internal.example/file", None)
+                .await,
+            RouteAction::Direct
+        );
+    }
+}
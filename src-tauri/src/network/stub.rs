@@ -0,0 +1,394 @@
+//! Local stub DNS server
+//! Answers other tools on a loopback port through the app's resolver, so they get the same
+//! upstreams, encryption, cache and host overrides as the app itself
+
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, ResponseCode};
+use hickory_proto::rr::RData;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+
+use crate::network::resolver::DnsResolver;
+
+/// Queries kept in the log
+const QUERY_LOG_SIZE: usize = 500;
+
+/// How long a TCP client may stay idle between queries
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest UDP response sent, whatever the client advertises
+const MAX_UDP_PAYLOAD: u16 = 4096;
+
+/// Stub server settings, persisted under `keys::DNS_STUB`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsStubConfig {
+    /// Start the listener with the app
+    pub enabled: bool,
+    /// Port on 127.0.0.1, for UDP and TCP
+    pub port: u16,
+}
+
+impl Default for DnsStubConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 5053,
+        }
+    }
+}
+
+/// Whether the stub server is listening, and where
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsStubStatus {
+    pub running: bool,
+    pub address: Option<String>,
+    /// Queries answered since the server started
+    pub queries: u64,
+}
+
+/// One query answered by the stub server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsQueryLogEntry {
+    pub timestamp: String,
+    pub client: String,
+    /// `udp` or `tcp`
+    pub protocol: String,
+    pub name: String,
+    pub record_type: String,
+    pub response_code: String,
+    pub answers: Vec<String>,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+struct Listener {
+    address: SocketAddr,
+    shutdown: CancellationToken,
+    /// UDP and TCP serve loops, which own the sockets until they return
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// Loopback DNS listener over UDP and TCP
+pub struct DnsStubServer {
+    listener: tokio::sync::Mutex<Option<Listener>>,
+    log: Mutex<VecDeque<DnsQueryLogEntry>>,
+    queries: AtomicU64,
+}
+
+impl DnsStubServer {
+    pub fn new() -> Self {
+        Self {
+            listener: tokio::sync::Mutex::new(None),
+            log: Mutex::new(VecDeque::with_capacity(QUERY_LOG_SIZE)),
+            queries: AtomicU64::new(0),
+        }
+    }
+
+    /// Listen on `127.0.0.1:port`, replacing a listener that is already running, and answer
+    /// through whichever resolver `resolver` holds at the time of each query
+    pub async fn start(
+        self: &Arc<Self>,
+        port: u16,
+        resolver: watch::Receiver<Option<DnsResolver>>,
+    ) -> io::Result<SocketAddr> {
+        if port == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DNS stub port must be between 1 and 65535",
+            ));
+        }
+        self.stop().await;
+
+        let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        let tcp = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        let address = udp.local_addr()?;
+        let shutdown = CancellationToken::new();
+
+        let tasks = vec![
+            tokio::spawn(
+                self.clone()
+                    .serve_udp(Arc::new(udp), resolver.clone(), shutdown.clone()),
+            ),
+            tokio::spawn(self.clone().serve_tcp(tcp, resolver, shutdown.clone())),
+        ];
+        self.queries.store(0, Ordering::Relaxed);
+        *self.listener.lock().await = Some(Listener {
+            address,
+            shutdown,
+            tasks,
+        });
+
+        log::info!("DNS stub server listening on {}", address);
+        Ok(address)
+    }
+
+    /// Close the listener, if one is running, and wait until the port is released
+    pub async fn stop(&self) {
+        let listener = self.listener.lock().await.take();
+        if let Some(listener) = listener {
+            listener.shutdown.cancel();
+            for task in listener.tasks {
+                if let Err(e) = task.await {
+                    log::warn!("DNS stub server task failed: {}", e);
+                }
+            }
+            log::info!("DNS stub server on {} stopped", listener.address);
+        }
+    }
+
+    pub async fn status(&self) -> DnsStubStatus {
+        let address = self
+            .listener
+            .lock()
+            .await
+            .as_ref()
+            .map(|listener| listener.address.to_string());
+        DnsStubStatus {
+            running: address.is_some(),
+            address,
+            queries: self.queries.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Logged queries, newest first
+    pub fn query_log(&self) -> Vec<DnsQueryLogEntry> {
+        self.log
+            .lock()
+            .map(|log| log.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn clear_query_log(&self) {
+        if let Ok(mut log) = self.log.lock() {
+            log.clear();
+        }
+    }
+
+    async fn serve_udp(
+        self: Arc<Self>,
+        socket: Arc<UdpSocket>,
+        resolver: watch::Receiver<Option<DnsResolver>>,
+        shutdown: CancellationToken,
+    ) {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        // In-flight answers hold the socket too, so they are aborted on shutdown
+        let mut answers = JoinSet::new();
+        loop {
+            let (len, client) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                Some(_) = answers.join_next() => continue,
+                received = socket.recv_from(&mut buf) => match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        // ICMP errors from earlier replies surface here; keep serving
+                        log::debug!("DNS stub UDP receive failed: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let packet = buf[..len].to_vec();
+            let server = self.clone();
+            let socket = socket.clone();
+            let resolver = resolver.borrow().clone();
+            answers.spawn(async move {
+                let answer = server.answer(&packet, client, "udp", resolver.as_ref());
+                if let Some(response) = answer.await {
+                    let _ = socket.send_to(&response, client).await;
+                }
+            });
+        }
+        answers.shutdown().await;
+    }
+
+    async fn serve_tcp(
+        self: Arc<Self>,
+        listener: TcpListener,
+        resolver: watch::Receiver<Option<DnsResolver>>,
+        shutdown: CancellationToken,
+    ) {
+        let mut connections = JoinSet::new();
+        loop {
+            let (stream, client) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                Some(_) = connections.join_next() => continue,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::debug!("DNS stub TCP accept failed: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let server = self.clone();
+            let resolver = resolver.clone();
+            connections.spawn(async move {
+                let _ = server.serve_connection(stream, client, resolver).await;
+            });
+        }
+        connections.shutdown().await;
+    }
+
+    /// Answer length-prefixed queries until the client closes or goes idle
+    async fn serve_connection(
+        &self,
+        mut stream: TcpStream,
+        client: SocketAddr,
+        resolver: watch::Receiver<Option<DnsResolver>>,
+    ) -> io::Result<()> {
+        loop {
+            let len = match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+                Ok(len) => len? as usize,
+                Err(_) => return Ok(()),
+            };
+            let mut packet = vec![0u8; len];
+            stream.read_exact(&mut packet).await?;
+
+            let current = resolver.borrow().clone();
+            let Some(response) = self.answer(&packet, client, "tcp", current.as_ref()).await else {
+                return Ok(());
+            };
+            let len = u16::try_from(response.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "DNS response too large")
+            })?;
+            let mut framed = Vec::with_capacity(response.len() + 2);
+            framed.extend_from_slice(&len.to_be_bytes());
+            framed.extend_from_slice(&response);
+            stream.write_all(&framed).await?;
+        }
+    }
+
+    /// Resolve one query packet; `None` when it cannot be parsed at all
+    async fn answer(
+        &self,
+        packet: &[u8],
+        client: SocketAddr,
+        protocol: &str,
+        resolver: Option<&DnsResolver>,
+    ) -> Option<Vec<u8>> {
+        let request = Message::from_vec(packet).ok()?;
+        if request.message_type() != MessageType::Query {
+            return None;
+        }
+        let [query] = request.queries() else {
+            return encode(&Message::error_msg(
+                request.id(),
+                request.op_code(),
+                ResponseCode::FormErr,
+            ));
+        };
+        if request.op_code() != OpCode::Query {
+            return encode(&Message::error_msg(
+                request.id(),
+                request.op_code(),
+                ResponseCode::NotImp,
+            ));
+        }
+
+        let started = Instant::now();
+        let name = query.name().to_utf8();
+        let result = match resolver {
+            Some(resolver) => resolver.query(&name, query.query_type()).await,
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Custom DNS is not configured",
+            )),
+        };
+
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true)
+            .add_query(query.clone());
+        if request.extensions().is_some() {
+            let mut edns = Edns::new();
+            edns.set_max_payload(MAX_UDP_PAYLOAD);
+            response.set_edns(edns);
+        }
+        let error = match result {
+            Ok(ref upstream) => {
+                response
+                    .set_response_code(upstream.response_code())
+                    .insert_answers(upstream.answers().to_vec());
+                response.insert_name_servers(upstream.name_servers().to_vec());
+                response.insert_additionals(upstream.additionals().to_vec());
+                None
+            }
+            Err(ref e) => {
+                response.set_response_code(ResponseCode::ServFail);
+                Some(e.to_string())
+            }
+        };
+
+        self.record(DnsQueryLogEntry {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            client: client.to_string(),
+            protocol: protocol.to_string(),
+            name: name.trim_end_matches('.').to_string(),
+            record_type: query.query_type().to_string(),
+            response_code: response.response_code().to_string(),
+            answers: response
+                .answers()
+                .iter()
+                .filter_map(|record| record.data().map(RData::to_string))
+                .collect(),
+            error,
+            latency_ms: started.elapsed().as_millis() as u64,
+        });
+
+        let mut bytes = encode(&response)?;
+        let limit = request.max_payload().clamp(512, MAX_UDP_PAYLOAD) as usize;
+        if protocol == "udp" && bytes.len() > limit {
+            // Too big for the client's buffer: send the header only so it retries over TCP
+            response.take_answers();
+            response.take_name_servers();
+            response.take_additionals();
+            response.set_truncated(true);
+            bytes = encode(&response)?;
+        }
+        Some(bytes)
+    }
+
+    fn record(&self, entry: DnsQueryLogEntry) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut log) = self.log.lock() {
+            if log.len() == QUERY_LOG_SIZE {
+                log.pop_front();
+            }
+            log.push_back(entry);
+        }
+    }
+}
+
+impl Default for DnsStubServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn encode(message: &Message) -> Option<Vec<u8>> {
+    message
+        .to_vec()
+        .map_err(|e| log::debug!("DNS stub could not encode a response: {}", e))
+        .ok()
+}
+
+pub type SharedDnsStub = Arc<DnsStubServer>;
+
+pub fn create_dns_stub() -> SharedDnsStub {
+    Arc::new(DnsStubServer::new())
+}
@@ -0,0 +1,78 @@
+//! Native desktop notifications
+//! Tells the user about proxy state changes even while Figma covers the screen
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+use tauri_plugin_notification::NotificationExt;
+
+use crate::AppState;
+
+/// Minimum time between two notifications of the same kind, so a flapping
+/// connection does not flood the notification center
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Proxy state changes a notification can be shown for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NotificationKind {
+    Disconnected,
+    Recovered,
+    Failover,
+    KillSwitch,
+}
+
+/// Rate limiter for notifications, per kind
+#[derive(Default)]
+pub struct Notifier {
+    last_shown: Mutex<HashMap<NotificationKind, Instant>>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a notification of `kind` may be shown now, recording it if so
+    fn allow(&self, kind: NotificationKind) -> bool {
+        let Ok(mut last_shown) = self.last_shown.lock() else {
+            return false;
+        };
+        let now = Instant::now();
+        match last_shown.get(&kind) {
+            Some(last) if now.duration_since(*last) < MIN_INTERVAL => false,
+            _ => {
+                last_shown.insert(kind, now);
+                true
+            }
+        }
+    }
+}
+
+/// Show a native notification, unless the user opted out of `kind` in the advanced
+/// settings or one of the same kind was shown within the last minute
+pub async fn notify(app: &AppHandle, kind: NotificationKind, title: &str, body: &str) {
+    let Some(state) = app.try_state::<AppState>() else {
+        return;
+    };
+    let enabled = {
+        let settings = state.advanced_settings.read().await;
+        match kind {
+            NotificationKind::Disconnected => settings.notify_on_disconnect,
+            NotificationKind::Recovered => settings.notify_on_recover,
+            NotificationKind::Failover => settings.notify_on_failover,
+            NotificationKind::KillSwitch => settings.notify_on_kill_switch,
+        }
+    };
+    if !enabled {
+        return;
+    }
+    if !state.notifier.allow(kind) {
+        log::debug!("Skipping {:?} notification (rate limited)", kind);
+        return;
+    }
+
+    if let Err(e) = app.notification().builder().title(title).body(body).show() {
+        log::warn!("Failed to show notification: {}", e);
+    }
+}
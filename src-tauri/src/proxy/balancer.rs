@@ -0,0 +1,512 @@
+//! Load balancing across saved proxies
+//! Probes every member on a schedule and picks the upstream for each new connection
+//! by latency, success rate, round-robin or least connections
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+use crate::proxy::config::{ProxyConfig, ProxyTestResult, ProxyType};
+use crate::proxy::manager::SharedProxyManager;
+use crate::proxy::upstream::{connect_upstream, BoxedStream};
+
+/// Probe results kept per member for success rate and latency
+const PROBE_WINDOW: usize = 10;
+
+/// How an upstream is chosen for a new connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum BalanceStrategy {
+    /// The healthy member with the lowest average latency
+    #[default]
+    LowestLatency,
+    /// The member with the highest probe success rate
+    HighestSuccessRate,
+    /// Healthy members in turn
+    RoundRobin,
+    /// The healthy member with the fewest open connections
+    LeastConnections,
+}
+
+/// Load balancer settings, persisted under `keys::BALANCER`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BalancerConfig {
+    /// Whether load balancing is active
+    pub enabled: bool,
+    /// Selection strategy
+    #[serde(default)]
+    pub strategy: BalanceStrategy,
+    /// Seconds between probe rounds
+    #[serde(default = "default_probe_interval")]
+    pub probe_interval_secs: u64,
+    /// Profile last selected by the latency or success rate strategies
+    #[serde(default)]
+    pub selected_profile_id: Option<String>,
+}
+
+fn default_probe_interval() -> u64 {
+    60
+}
+
+impl Default for BalancerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            strategy: BalanceStrategy::default(),
+            probe_interval_secs: default_probe_interval(),
+            selected_profile_id: None,
+        }
+    }
+}
+
+impl BalancerConfig {
+    /// Validate the load balancer settings
+    pub fn validate(&self) -> Result<(), String> {
+        if self.probe_interval_secs < 10 {
+            return Err("Probe interval must be at least 10 seconds".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Saved proxy taking part in load balancing, with its credentials loaded
+#[derive(Debug, Clone)]
+pub struct BalancerMember {
+    pub profile_id: String,
+    pub name: String,
+    pub config: ProxyConfig,
+}
+
+impl BalancerMember {
+    /// Whether connections can be dialed to this proxy per request; PAC, SSH and
+    /// Shadowsocks plugins need per-upstream state that only `ProxyManager` keeps
+    pub fn is_balanceable(config: &ProxyConfig) -> bool {
+        match config.proxy_type {
+            ProxyType::Pac | ProxyType::Ssh => false,
+            ProxyType::Shadowsocks => config.shadowsocks.plugin.is_none(),
+            _ => true,
+        }
+    }
+}
+
+/// Probe statistics of one member
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemberStats {
+    pub profile_id: String,
+    pub name: String,
+    /// Proxy as `type://host:port`
+    pub proxy: String,
+    /// Whether the last probe succeeded
+    pub healthy: bool,
+    /// Share of successful probes in the recent window, from 0 to 1
+    pub success_rate: Option<f64>,
+    /// Average latency of the recent successful probes
+    pub latency_ms: Option<u64>,
+    /// Connections currently open through this member
+    pub active_connections: usize,
+    /// Error of the last failed probe
+    pub last_error: Option<String>,
+    /// Timestamp of the last probe
+    pub last_checked: Option<String>,
+}
+
+/// Load balancer state reported to the frontend
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BalancerStatus {
+    /// Whether load balancing is running
+    pub enabled: bool,
+    /// Selection strategy
+    pub strategy: BalanceStrategy,
+    /// Profile new connections go to, for the latency and success rate strategies
+    pub selected: Option<String>,
+    /// Members in profile order
+    pub members: Vec<MemberStats>,
+}
+
+struct Member {
+    info: BalancerMember,
+    samples: VecDeque<ProxyTestResult>,
+    last_checked: Option<String>,
+    active: Arc<AtomicUsize>,
+}
+
+impl Member {
+    fn healthy(&self) -> bool {
+        self.samples.back().map(|s| s.success).unwrap_or(false)
+    }
+
+    fn success_rate(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let successes = self.samples.iter().filter(|s| s.success).count();
+        Some(successes as f64 / self.samples.len() as f64)
+    }
+
+    fn latency_ms(&self) -> Option<u64> {
+        let latencies: Vec<u64> = self.samples.iter().filter_map(|s| s.latency_ms).collect();
+        if latencies.is_empty() {
+            return None;
+        }
+        Some(latencies.iter().sum::<u64>() / latencies.len() as u64)
+    }
+
+    fn stats(&self) -> MemberStats {
+        MemberStats {
+            profile_id: self.info.profile_id.clone(),
+            name: self.info.name.clone(),
+            proxy: self.info.config.label(),
+            healthy: self.healthy(),
+            success_rate: self.success_rate(),
+            latency_ms: self.latency_ms(),
+            active_connections: self.active.load(Ordering::Relaxed),
+            last_error: self.samples.back().and_then(|s| s.error.clone()),
+            last_checked: self.last_checked.clone(),
+        }
+    }
+}
+
+struct BalancerState {
+    strategy: BalanceStrategy,
+    members: Vec<Member>,
+    /// Member chosen by the latency and success rate strategies
+    selected: Option<usize>,
+    next: AtomicUsize,
+}
+
+impl BalancerState {
+    /// Member the latency and success rate strategies route to, if any is healthy
+    fn best(&self) -> Option<usize> {
+        let healthy = (0..self.members.len()).filter(|&i| self.members[i].healthy());
+        let latency = |i: usize| self.members[i].latency_ms().unwrap_or(u64::MAX);
+        match self.strategy {
+            BalanceStrategy::LowestLatency => healthy.min_by_key(|&i| latency(i)),
+            BalanceStrategy::HighestSuccessRate => healthy.max_by(|&a, &b| {
+                let rate = |i: usize| self.members[i].success_rate().unwrap_or(0.0);
+                rate(a)
+                    .total_cmp(&rate(b))
+                    .then_with(|| latency(b).cmp(&latency(a)))
+            }),
+            BalanceStrategy::RoundRobin | BalanceStrategy::LeastConnections => None,
+        }
+    }
+
+    /// Member for a new connection
+    fn pick(&self) -> Option<usize> {
+        let healthy: Vec<usize> = (0..self.members.len())
+            .filter(|&i| self.members[i].healthy())
+            .collect();
+        if healthy.is_empty() {
+            // Nothing probed healthy yet: stay with the remembered choice
+            return self.selected.or(if self.members.is_empty() {
+                None
+            } else {
+                Some(0)
+            });
+        }
+
+        match self.strategy {
+            BalanceStrategy::LowestLatency | BalanceStrategy::HighestSuccessRate => self
+                .selected
+                .filter(|i| healthy.contains(i))
+                .or(self.best()),
+            BalanceStrategy::RoundRobin => {
+                let turn = self.next.fetch_add(1, Ordering::Relaxed);
+                Some(healthy[turn % healthy.len()])
+            }
+            BalanceStrategy::LeastConnections => healthy.into_iter().min_by_key(|&i| {
+                let member = &self.members[i];
+                (
+                    member.active.load(Ordering::Relaxed),
+                    member.latency_ms().unwrap_or(u64::MAX),
+                )
+            }),
+        }
+    }
+}
+
+/// Load balancer plugged into the local forwarders by `ProxyManager`
+pub struct LoadBalancer {
+    proxy_manager: SharedProxyManager,
+    state: RwLock<Option<BalancerState>>,
+    prober: RwLock<Option<CancellationToken>>,
+    selection: watch::Sender<Option<String>>,
+}
+
+impl LoadBalancer {
+    pub fn new(proxy_manager: SharedProxyManager) -> Self {
+        Self {
+            proxy_manager,
+            state: RwLock::new(None),
+            prober: RwLock::new(None),
+            selection: watch::Sender::new(None),
+        }
+    }
+
+    /// Start (or restart) balancing across `members`
+    pub async fn start(
+        self: &Arc<Self>,
+        config: BalancerConfig,
+        members: Vec<BalancerMember>,
+    ) -> Result<(), String> {
+        config.validate()?;
+        self.stop().await;
+
+        let members: Vec<Member> = members
+            .into_iter()
+            .filter(|member| {
+                let balanceable = BalancerMember::is_balanceable(&member.config);
+                if !balanceable {
+                    log::info!("Load balancing skips profile {}", member.name);
+                }
+                balanceable
+            })
+            .map(|info| Member {
+                info,
+                samples: VecDeque::with_capacity(PROBE_WINDOW),
+                last_checked: None,
+                active: Arc::new(AtomicUsize::new(0)),
+            })
+            .collect();
+        if members.is_empty() {
+            return Err("No saved proxy can be load balanced".to_string());
+        }
+
+        // Start from the remembered choice until the first probe round is in
+        let selected = config
+            .selected_profile_id
+            .as_deref()
+            .and_then(|id| members.iter().position(|m| m.info.profile_id == id))
+            .unwrap_or(0);
+        let initial = members[selected].info.config.clone();
+        log::info!(
+            "Starting load balancing across {} proxies ({:?})",
+            members.len(),
+            config.strategy
+        );
+
+        *self.state.write().await = Some(BalancerState {
+            strategy: config.strategy,
+            members,
+            selected: Some(selected),
+            next: AtomicUsize::new(0),
+        });
+        self.proxy_manager
+            .set_balancer(Some(self.clone()), initial)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let token = CancellationToken::new();
+        tokio::spawn(self.clone().probe_loop(
+            Duration::from_secs(config.probe_interval_secs),
+            token.clone(),
+        ));
+        *self.prober.write().await = Some(token);
+        Ok(())
+    }
+
+    /// Stop balancing; the proxy last selected stays configured
+    pub async fn stop(&self) {
+        if let Some(token) = self.prober.write().await.take() {
+            token.cancel();
+            log::info!("Load balancing stopped");
+        }
+        if self.state.write().await.take().is_some() {
+            let config = self.proxy_manager.get_config().await;
+            if let Err(e) = self.proxy_manager.set_balancer(None, config).await {
+                log::warn!("Failed to restore single proxy after load balancing: {}", e);
+            }
+        }
+    }
+
+    /// Probe statistics and current selection
+    pub async fn status(&self) -> BalancerStatus {
+        match *self.state.read().await {
+            Some(ref state) => BalancerStatus {
+                enabled: true,
+                strategy: state.strategy,
+                selected: match state.strategy {
+                    BalanceStrategy::LowestLatency | BalanceStrategy::HighestSuccessRate => state
+                        .selected
+                        .map(|i| state.members[i].info.profile_id.clone()),
+                    BalanceStrategy::RoundRobin | BalanceStrategy::LeastConnections => None,
+                },
+                members: state.members.iter().map(Member::stats).collect(),
+            },
+            None => BalancerStatus::default(),
+        }
+    }
+
+    /// Subscribe to changes of the selected profile, to remember it across restarts
+    pub fn subscribe_selection(&self) -> watch::Receiver<Option<String>> {
+        self.selection.subscribe()
+    }
+
+    /// Open a tunnel to `host:port` through the member picked by the strategy, or with
+    /// `plain_http` a connection to the member itself if it is an HTTP(S) proxy
+    pub async fn dial(
+        &self,
+        host: &str,
+        port: u16,
+        plain_http: bool,
+    ) -> io::Result<(BoxedStream, Option<ProxyConfig>)> {
+        let (config, active) = {
+            let state = self.state.read().await;
+            let state = state.as_ref().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "Load balancing is stopped")
+            })?;
+            let index = state.pick().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "No upstream available")
+            })?;
+            let member = &state.members[index];
+            (member.info.config.clone(), member.active.clone())
+        };
+
+        let resolver = self.proxy_manager.resolver();
+        let (stream, proxy) =
+            connect_upstream(&config, host, port, resolver.as_ref(), plain_http).await?;
+        active.fetch_add(1, Ordering::Relaxed);
+        let stream = Box::new(CountedStream {
+            inner: stream,
+            active,
+        });
+        Ok((stream, proxy))
+    }
+
+    async fn probe_loop(self: Arc<Self>, interval: Duration, token: CancellationToken) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = interval.tick() => self.probe_round(&token).await,
+            }
+        }
+    }
+
+    /// Probe every member concurrently with the connection test, then update the selection
+    async fn probe_round(self: &Arc<Self>, token: &CancellationToken) {
+        let configs: Vec<ProxyConfig> = match *self.state.read().await {
+            Some(ref state) => state
+                .members
+                .iter()
+                .map(|m| m.info.config.clone())
+                .collect(),
+            None => return,
+        };
+
+        let mut probes = JoinSet::new();
+        for (index, config) in configs.into_iter().enumerate() {
+            let proxy_manager = self.proxy_manager.clone();
+            probes.spawn(async move { (index, proxy_manager.test_connection(&config).await) });
+        }
+        let mut results = Vec::new();
+        while let Some(result) = probes.join_next().await {
+            if let Ok(result) = result {
+                results.push(result);
+            }
+        }
+
+        if token.is_cancelled() {
+            return;
+        }
+        let changed = {
+            let mut state = self.state.write().await;
+            let Some(ref mut state) = *state else {
+                return;
+            };
+            let checked = chrono::Utc::now().to_rfc3339();
+            for (index, result) in results {
+                let member = &mut state.members[index];
+                if member.samples.len() == PROBE_WINDOW {
+                    member.samples.pop_front();
+                }
+                member.samples.push_back(result);
+                member.last_checked = Some(checked.clone());
+            }
+
+            match state.best() {
+                Some(best) if state.selected != Some(best) => {
+                    state.selected = Some(best);
+                    let member = &state.members[best].info;
+                    log::info!(
+                        "Load balancing selected {} ({:?})",
+                        member.name,
+                        state.strategy
+                    );
+                    Some(member.clone())
+                }
+                _ => None,
+            }
+        };
+
+        // Keep the manager's active configuration (and status) on the selected proxy
+        if let Some(member) = changed {
+            if let Err(e) = self
+                .proxy_manager
+                .set_balancer(Some(self.clone()), member.config)
+                .await
+            {
+                log::warn!("Failed to apply selected proxy {}: {}", member.name, e);
+            }
+            self.selection.send_replace(Some(member.profile_id));
+        }
+    }
+}
+
+/// Tunnel that counts as an open connection of its member until dropped
+struct CountedStream {
+    inner: BoxedStream,
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for CountedStream {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for CountedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub type SharedLoadBalancer = Arc<LoadBalancer>;
+
+pub fn create_load_balancer(proxy_manager: SharedProxyManager) -> SharedLoadBalancer {
+    Arc::new(LoadBalancer::new(proxy_manager))
+}
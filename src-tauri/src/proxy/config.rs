@@ -1,7 +1,9 @@
 //! Proxy configuration module
 //! Handles proxy settings, serialization, and validation
 
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 
 /// Proxy protocol type
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -9,20 +11,52 @@ use serde::{Deserialize, Serialize};
 pub enum ProxyType {
     #[default]
     Socks5,
+    /// SOCKS5 with hostnames resolved by the proxy (no local DNS lookups)
+    Socks5h,
+    Socks4,
+    /// SOCKS4 with hostnames resolved by the proxy
+    Socks4a,
     Http,
     Https,
+    /// Proxy auto-config script decides per URL
+    Pac,
+    /// Shadowsocks server (AEAD or AEAD-2022 cipher)
+    Shadowsocks,
+    /// SSH server used for dynamic (`ssh -D` style) port forwarding
+    Ssh,
 }
 
 impl std::fmt::Display for ProxyType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ProxyType::Socks5 => write!(f, "socks5"),
+            ProxyType::Socks5h => write!(f, "socks5h"),
+            ProxyType::Socks4 => write!(f, "socks4"),
+            ProxyType::Socks4a => write!(f, "socks4a"),
             ProxyType::Http => write!(f, "http"),
             ProxyType::Https => write!(f, "https"),
+            ProxyType::Pac => write!(f, "pac"),
+            ProxyType::Shadowsocks => write!(f, "ss"),
+            ProxyType::Ssh => write!(f, "ssh"),
         }
     }
 }
 
+impl ProxyType {
+    /// Whether this is one of the SOCKS variants
+    pub fn is_socks(&self) -> bool {
+        matches!(
+            self,
+            ProxyType::Socks5 | ProxyType::Socks5h | ProxyType::Socks4 | ProxyType::Socks4a
+        )
+    }
+
+    /// Whether target hostnames are resolved by the proxy rather than locally
+    pub fn resolves_remotely(&self) -> bool {
+        !matches!(self, ProxyType::Socks5 | ProxyType::Socks4)
+    }
+}
+
 /// Proxy configuration structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -45,6 +79,136 @@ pub struct ProxyConfig {
     pub auto_detect: bool,
     /// Auto-connect on startup
     pub auto_connect: bool,
+    /// Profile this configuration was activated from, if any
+    #[serde(default)]
+    pub profile_id: Option<String>,
+    /// Hosts, domain suffixes and CIDR ranges that bypass the proxy
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    /// PAC script URL or file path (for `ProxyType::Pac`)
+    #[serde(default)]
+    pub pac_url: Option<String>,
+    /// TLS settings for the connection to the proxy (for `ProxyType::Https`)
+    #[serde(default)]
+    pub tls: ProxyTlsConfig,
+    /// Cipher and plugin settings (for `ProxyType::Shadowsocks`)
+    #[serde(default)]
+    pub shadowsocks: ShadowsocksConfig,
+    /// Key and host verification settings (for `ProxyType::Ssh`)
+    #[serde(default)]
+    pub ssh: SshConfig,
+    /// Proxies to pass through, in order, before reaching this one
+    #[serde(default)]
+    pub chain: Vec<ProxyHop>,
+}
+
+/// Intermediate proxy in a chain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyHop {
+    /// Type of proxy protocol
+    #[serde(rename = "type")]
+    pub proxy_type: ProxyType,
+    /// Proxy server hostname
+    pub host: String,
+    /// Proxy server port
+    pub port: u16,
+    /// Optional username for authentication
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Password for this hop (never persisted, kept in the keyring by profile and position)
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+}
+
+impl ProxyHop {
+    /// Standalone configuration for connecting to this hop
+    pub fn to_config(&self) -> ProxyConfig {
+        ProxyConfig {
+            username: self.username.clone(),
+            password: self.password.clone(),
+            ..ProxyConfig::new(self.proxy_type, self.host.clone(), self.port)
+        }
+    }
+
+    /// Whether this hop authenticates with a stored password
+    pub fn uses_password(&self) -> bool {
+        self.to_config().uses_password()
+    }
+}
+
+/// TLS settings for the connection to an HTTPS proxy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyTlsConfig {
+    /// Server name sent in SNI and checked against the certificate (defaults to the proxy host)
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// Whether to verify the proxy's certificate
+    #[serde(default = "default_verify_certificate")]
+    pub verify_certificate: bool,
+    /// PEM file with additional CA certificates to trust for the proxy
+    #[serde(default)]
+    pub ca_file: Option<String>,
+}
+
+fn default_verify_certificate() -> bool {
+    true
+}
+
+impl Default for ProxyTlsConfig {
+    fn default() -> Self {
+        Self {
+            server_name: None,
+            verify_certificate: true,
+            ca_file: None,
+        }
+    }
+}
+
+/// Shadowsocks settings; the password is the regular proxy password
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShadowsocksConfig {
+    /// Cipher method, e.g. `aes-256-gcm` or `2022-blake3-aes-256-gcm`
+    #[serde(default = "default_shadowsocks_cipher")]
+    pub cipher: String,
+    /// SIP003 plugin executable, e.g. `obfs-local` or `v2ray-plugin`
+    #[serde(default)]
+    pub plugin: Option<String>,
+    /// Options passed to the plugin in `SS_PLUGIN_OPTIONS`
+    #[serde(default)]
+    pub plugin_opts: Option<String>,
+}
+
+fn default_shadowsocks_cipher() -> String {
+    "chacha20-ietf-poly1305".to_string()
+}
+
+impl Default for ShadowsocksConfig {
+    fn default() -> Self {
+        Self {
+            cipher: default_shadowsocks_cipher(),
+            plugin: None,
+            plugin_opts: None,
+        }
+    }
+}
+
+/// SSH settings; the stored password is the login password, or the key passphrase
+/// when a private key is set
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SshConfig {
+    /// Private key file to authenticate with instead of a password
+    #[serde(default)]
+    pub private_key_file: Option<String>,
+    /// known_hosts file to verify the server against (defaults to `~/.ssh/known_hosts`)
+    #[serde(default)]
+    pub known_hosts_file: Option<String>,
+    /// Trust and record the host key of a server not yet in known_hosts
+    #[serde(default)]
+    pub accept_new_host_key: bool,
 }
 
 impl Default for ProxyConfig {
@@ -58,6 +222,13 @@ impl Default for ProxyConfig {
             password: None,
             auto_detect: false,
             auto_connect: false,
+            profile_id: None,
+            no_proxy: Vec::new(),
+            pac_url: None,
+            tls: ProxyTlsConfig::default(),
+            shadowsocks: ShadowsocksConfig::default(),
+            ssh: SshConfig::default(),
+            chain: Vec::new(),
         }
     }
 }
@@ -74,6 +245,13 @@ impl ProxyConfig {
             password: None,
             auto_detect: false,
             auto_connect: false,
+            profile_id: None,
+            no_proxy: Vec::new(),
+            pac_url: None,
+            tls: ProxyTlsConfig::default(),
+            shadowsocks: ShadowsocksConfig::default(),
+            ssh: SshConfig::default(),
+            chain: Vec::new(),
         }
     }
 
@@ -86,6 +264,12 @@ impl ProxyConfig {
 
     /// Validate the proxy configuration
     pub fn validate(&self) -> Result<(), String> {
+        if self.proxy_type == ProxyType::Pac {
+            return match self.pac_url.as_deref().map(str::trim) {
+                Some(url) if !url.is_empty() => Ok(()),
+                _ => Err("PAC script URL or file path is required".to_string()),
+            };
+        }
         if self.host.is_empty() {
             return Err("Proxy host cannot be empty".to_string());
         }
@@ -99,9 +283,104 @@ impl ProxyConfig {
         if self.host.contains(' ') {
             return Err("Proxy host cannot contain spaces".to_string());
         }
+        if self.proxy_type == ProxyType::Https {
+            if let Some(ref name) = self.tls.server_name {
+                if name.trim().is_empty() || name.contains(' ') {
+                    return Err("TLS server name is invalid".to_string());
+                }
+            }
+            if let Some(ref ca_file) = self.tls.ca_file {
+                if !std::path::Path::new(ca_file).is_file() {
+                    return Err(format!("CA file not found: {}", ca_file));
+                }
+            }
+        }
+        if self.proxy_type == ProxyType::Shadowsocks
+            && self
+                .shadowsocks
+                .cipher
+                .parse::<shadowsocks::crypto::CipherKind>()
+                .is_err()
+        {
+            return Err(format!(
+                "Unsupported Shadowsocks cipher: {}",
+                self.shadowsocks.cipher
+            ));
+        }
+        if !self.chain.is_empty() {
+            self.validate_chain()?;
+        }
+        if self.proxy_type == ProxyType::Ssh {
+            if self
+                .username
+                .as_deref()
+                .map(str::trim)
+                .unwrap_or_default()
+                .is_empty()
+            {
+                return Err("SSH username is required".to_string());
+            }
+            if let Some(ref key_file) = self.ssh.private_key_file {
+                if !std::path::Path::new(key_file).is_file() {
+                    return Err(format!("SSH private key not found: {}", key_file));
+                }
+            }
+        }
         Ok(())
     }
 
+    /// Check that every hop of the chain can tunnel to the next one
+    fn validate_chain(&self) -> Result<(), String> {
+        let chainable =
+            |proxy_type: ProxyType| !matches!(proxy_type, ProxyType::Pac | ProxyType::Ssh);
+
+        if !chainable(self.proxy_type) {
+            return Err(format!(
+                "A {} proxy cannot be the exit of a chain",
+                self.proxy_type
+            ));
+        }
+        if self.proxy_type == ProxyType::Shadowsocks && self.shadowsocks.plugin.is_some() {
+            return Err("Shadowsocks plugins cannot be used in a proxy chain".to_string());
+        }
+        for (index, hop) in self.chain.iter().enumerate() {
+            if !chainable(hop.proxy_type) {
+                return Err(format!(
+                    "Chain hop {}: {} proxies cannot be chained",
+                    index + 1,
+                    hop.proxy_type
+                ));
+            }
+            hop.to_config()
+                .validate()
+                .map_err(|e| format!("Chain hop {}: {}", index + 1, e))?;
+        }
+        Ok(())
+    }
+
+    /// Every proxy on the way to a target in connection order: the chain, then this one
+    pub fn hops(&self) -> Vec<ProxyConfig> {
+        self.chain
+            .iter()
+            .map(ProxyHop::to_config)
+            .chain(std::iter::once(ProxyConfig {
+                chain: Vec::new(),
+                ..self.clone()
+            }))
+            .collect()
+    }
+
+    /// Label for logs and test results, without credentials
+    pub fn label(&self) -> String {
+        format!("{}://{}:{}", self.proxy_type, self.host, self.port)
+    }
+
+    /// Whether this configuration authenticates with a stored password
+    pub fn uses_password(&self) -> bool {
+        self.username.is_some()
+            || matches!(self.proxy_type, ProxyType::Shadowsocks | ProxyType::Ssh)
+    }
+
     /// Build the proxy URL
     pub fn to_url(&self) -> String {
         let auth = match (&self.username, &self.password) {
@@ -111,6 +390,31 @@ impl ProxyConfig {
         };
         format!("{}://{}{}:{}", self.proxy_type, auth, self.host, self.port)
     }
+
+    /// Check whether `host` matches an entry of the `no_proxy` list
+    pub fn bypasses(&self, host: &str) -> bool {
+        let host = host
+            .trim_matches(|c| c == '[' || c == ']')
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        let ip = host.parse::<IpAddr>().ok();
+
+        self.no_proxy.iter().any(|entry| {
+            let entry = entry.trim().to_ascii_lowercase();
+            if entry == "*" {
+                return true;
+            }
+            if let Some(ip) = ip {
+                if let Ok(net) = entry.parse::<IpNet>() {
+                    return net.contains(&ip);
+                }
+                return entry.parse::<IpAddr>().map(|e| e == ip).unwrap_or(false);
+            }
+
+            let suffix = entry.trim_start_matches('*').trim_start_matches('.');
+            !suffix.is_empty() && (host == suffix || host.ends_with(&format!(".{}", suffix)))
+        })
+    }
 }
 
 /// Result of a proxy connection test
@@ -125,6 +429,24 @@ pub struct ProxyTestResult {
     pub error: Option<String>,
     /// IP address as seen by the target server
     pub external_ip: Option<String>,
+    /// Per-hop results when testing a proxy chain, in connection order
+    #[serde(default)]
+    pub hops: Vec<HopTestResult>,
+    /// First hop of the chain that could not be reached
+    #[serde(default)]
+    pub failed_hop: Option<String>,
+}
+
+/// Result of reaching one hop of a proxy chain
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HopTestResult {
+    /// Hop as `type://host:port`
+    pub proxy: String,
+    /// Time to reach this hop from the previous one, in milliseconds
+    pub latency_ms: Option<u64>,
+    /// Error message if the hop could not be reached
+    pub error: Option<String>,
 }
 
 impl ProxyTestResult {
@@ -134,6 +456,8 @@ impl ProxyTestResult {
             latency_ms: Some(latency_ms),
             error: None,
             external_ip,
+            hops: Vec::new(),
+            failed_hop: None,
         }
     }
 
@@ -143,8 +467,15 @@ impl ProxyTestResult {
             latency_ms: None,
             error: Some(error),
             external_ip: None,
+            hops: Vec::new(),
+            failed_hop: None,
         }
     }
+
+    /// Attach the per-hop results of a chain test
+    pub fn with_hops(self, hops: Vec<HopTestResult>) -> Self {
+        Self { hops, ..self }
+    }
 }
 
 /// Current proxy status
@@ -161,6 +492,8 @@ pub struct ProxyStatus {
     pub last_error: Option<String>,
     /// Timestamp of last status update
     pub last_updated: String,
+    /// Whether the kill switch is currently blocking traffic
+    pub kill_switch_engaged: bool,
 }
 
 impl Default for ProxyStatus {
@@ -171,14 +504,30 @@ impl Default for ProxyStatus {
             latency_ms: None,
             last_error: None,
             last_updated: chrono::Utc::now().to_rfc3339(),
+            kill_switch_engaged: false,
         }
     }
 }
 
-/// Preset proxy server configuration
+/// Kill switch engagement state
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KillSwitchState {
+    /// Whether Figma traffic is currently blocked
+    pub engaged: bool,
+    /// Why the kill switch engaged
+    pub reason: Option<String>,
+    /// When the kill switch engaged
+    pub since: Option<String>,
+}
+
+/// Saved, named proxy profile
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ProxyPreset {
+    /// Stable profile identifier (password is stored in the keyring under it)
+    #[serde(default)]
+    pub id: String,
     /// Display name
     pub name: String,
     /// Proxy type
@@ -187,10 +536,37 @@ pub struct ProxyPreset {
     pub host: String,
     /// Port
     pub port: u16,
+    /// Optional username for authentication
+    #[serde(default)]
+    pub username: Option<String>,
     /// Whether authentication is required
     pub requires_auth: bool,
     /// Geographic location
     pub location: Option<String>,
+    /// Free-form notes
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// Result of the last connection test
+    #[serde(default)]
+    pub last_test: Option<ProxyTestResult>,
+    /// Timestamp of the last connection test
+    #[serde(default)]
+    pub last_tested_at: Option<String>,
+    /// PAC script URL or file path (for `ProxyType::Pac`)
+    #[serde(default)]
+    pub pac_url: Option<String>,
+    /// TLS settings for `ProxyType::Https`
+    #[serde(default)]
+    pub tls: ProxyTlsConfig,
+    /// Cipher and plugin settings for `ProxyType::Shadowsocks`
+    #[serde(default)]
+    pub shadowsocks: ShadowsocksConfig,
+    /// Key and host verification settings for `ProxyType::Ssh`
+    #[serde(default)]
+    pub ssh: SshConfig,
+    /// Proxies to pass through, in order, before reaching this one
+    #[serde(default)]
+    pub chain: Vec<ProxyHop>,
 }
 
 impl ProxyPreset {
@@ -200,10 +576,25 @@ impl ProxyPreset {
             proxy_type: self.proxy_type,
             host: self.host.clone(),
             port: self.port,
-            username: None,
+            username: self.username.clone(),
             password: None,
             auto_detect: false,
             auto_connect: false,
+            profile_id: Some(self.id.clone()),
+            no_proxy: Vec::new(),
+            pac_url: self.pac_url.clone(),
+            tls: self.tls.clone(),
+            shadowsocks: self.shadowsocks.clone(),
+            ssh: self.ssh.clone(),
+            chain: self.chain.clone(),
+        }
+    }
+
+    /// Validate the profile
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Profile name cannot be empty".to_string());
         }
+        self.to_config().validate()
     }
 }
@@ -0,0 +1,422 @@
+//! Proxy failover group
+//! Probes an ordered list of upstreams in the background and switches the active proxy
+//! to the next healthy one, with hysteresis so a flaky upstream does not cause flapping
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+use crate::proxy::config::{ProxyConfig, ProxyStatus, ProxyTestResult};
+use crate::proxy::manager::SharedProxyManager;
+
+/// Failover group settings, persisted under `keys::FAILOVER`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailoverConfig {
+    /// Whether failover is active
+    pub enabled: bool,
+    /// Profile ids in order of preference
+    pub profile_ids: Vec<String>,
+    /// Seconds between probe rounds
+    #[serde(default = "default_probe_interval")]
+    pub probe_interval_secs: u64,
+    /// Consecutive failed probes before switching away from the active upstream
+    #[serde(default = "default_failures_to_switch")]
+    pub failures_to_switch: u32,
+    /// Consecutive successful probes before switching back to a preferred upstream
+    #[serde(default = "default_successes_to_return")]
+    pub successes_to_return: u32,
+    /// Minimum seconds on an upstream before switching back to a preferred one
+    #[serde(default = "default_min_dwell")]
+    pub min_dwell_secs: u64,
+}
+
+fn default_probe_interval() -> u64 {
+    30
+}
+
+fn default_failures_to_switch() -> u32 {
+    3
+}
+
+fn default_successes_to_return() -> u32 {
+    3
+}
+
+fn default_min_dwell() -> u64 {
+    300
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            profile_ids: Vec::new(),
+            probe_interval_secs: default_probe_interval(),
+            failures_to_switch: default_failures_to_switch(),
+            successes_to_return: default_successes_to_return(),
+            min_dwell_secs: default_min_dwell(),
+        }
+    }
+}
+
+impl FailoverConfig {
+    /// Validate the failover settings
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.profile_ids.len() < 2 {
+            return Err("Failover needs at least two proxy profiles".to_string());
+        }
+        for (index, id) in self.profile_ids.iter().enumerate() {
+            if self.profile_ids[..index].contains(id) {
+                return Err(format!("Profile {} is listed twice", id));
+            }
+        }
+        if self.probe_interval_secs < 5 {
+            return Err("Probe interval must be at least 5 seconds".to_string());
+        }
+        if self.failures_to_switch == 0 || self.successes_to_return == 0 {
+            return Err("Failover thresholds must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Upstream taking part in failover, with its credentials loaded
+#[derive(Debug, Clone)]
+pub struct FailoverMember {
+    pub profile_id: String,
+    pub name: String,
+    pub config: ProxyConfig,
+}
+
+impl FailoverMember {
+    fn upstream(&self) -> FailoverUpstream {
+        FailoverUpstream {
+            profile_id: self.profile_id.clone(),
+            name: self.name.clone(),
+            proxy: self.config.label(),
+        }
+    }
+}
+
+/// Upstream as reported to the frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailoverUpstream {
+    /// Profile the upstream comes from
+    pub profile_id: String,
+    /// Profile name
+    pub name: String,
+    /// Proxy as `type://host:port`
+    pub proxy: String,
+}
+
+/// Sent whenever the failover group switches the active upstream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailoverEvent {
+    /// Previously active upstream, if any
+    pub from: Option<FailoverUpstream>,
+    /// Newly active upstream
+    pub to: FailoverUpstream,
+    /// Why the switch happened
+    pub reason: String,
+    /// When the switch happened
+    pub timestamp: String,
+}
+
+/// Probe history of one upstream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamHealth {
+    #[serde(flatten)]
+    pub upstream: FailoverUpstream,
+    /// Failed probes in a row
+    pub consecutive_failures: u32,
+    /// Successful probes in a row
+    pub consecutive_successes: u32,
+    /// Latency of the last successful probe
+    pub latency_ms: Option<u64>,
+    /// Error of the last failed probe
+    pub last_error: Option<String>,
+    /// Timestamp of the last probe
+    pub last_checked: Option<String>,
+}
+
+impl UpstreamHealth {
+    fn record(&mut self, result: &ProxyTestResult) {
+        if result.success {
+            self.consecutive_successes += 1;
+            self.consecutive_failures = 0;
+            self.latency_ms = result.latency_ms;
+            self.last_error = None;
+        } else {
+            self.consecutive_failures += 1;
+            self.consecutive_successes = 0;
+            self.latency_ms = None;
+            self.last_error = result.error.clone();
+        }
+        self.last_checked = Some(chrono::Utc::now().to_rfc3339());
+    }
+}
+
+/// Failover state reported to the frontend
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailoverStatus {
+    /// Whether the group is running
+    pub enabled: bool,
+    /// Profile id of the active upstream
+    pub active: Option<String>,
+    /// Members in order of preference
+    pub upstreams: Vec<UpstreamHealth>,
+}
+
+struct GroupState {
+    config: FailoverConfig,
+    members: Vec<FailoverMember>,
+    health: Vec<UpstreamHealth>,
+    active: Option<usize>,
+    switched_at: Instant,
+}
+
+/// Failover group driving the `ProxyManager`
+pub struct FailoverGroup {
+    proxy_manager: SharedProxyManager,
+    state: RwLock<Option<GroupState>>,
+    prober: RwLock<Option<CancellationToken>>,
+    events: broadcast::Sender<FailoverEvent>,
+}
+
+impl FailoverGroup {
+    pub fn new(proxy_manager: SharedProxyManager) -> Self {
+        Self {
+            proxy_manager,
+            state: RwLock::new(None),
+            prober: RwLock::new(None),
+            events: broadcast::channel(16).0,
+        }
+    }
+
+    /// Start (or restart) failover across `members`, given in order of preference
+    pub async fn start(
+        self: &Arc<Self>,
+        config: FailoverConfig,
+        members: Vec<FailoverMember>,
+    ) -> Result<(), String> {
+        config.validate()?;
+        self.stop().await;
+
+        // Keep the current proxy if it is already one of the members
+        let current = self.proxy_manager.get_config().await;
+        let active = members.iter().position(|member| {
+            current.enabled && current.profile_id.as_deref() == Some(member.profile_id.as_str())
+        });
+
+        let health = members
+            .iter()
+            .map(|member| UpstreamHealth {
+                upstream: member.upstream(),
+                consecutive_failures: 0,
+                consecutive_successes: 0,
+                latency_ms: None,
+                last_error: None,
+                last_checked: None,
+            })
+            .collect();
+        let interval = Duration::from_secs(config.probe_interval_secs);
+        log::info!("Starting proxy failover across {} upstreams", members.len());
+
+        {
+            let mut state = self.state.write().await;
+            let group = state.insert(GroupState {
+                config,
+                members,
+                health,
+                active,
+                switched_at: Instant::now(),
+            });
+            if group.active.is_none() {
+                self.switch(group, 0, "Failover group started".to_string())
+                    .await;
+            }
+        }
+
+        let token = CancellationToken::new();
+        tokio::spawn(self.clone().probe_loop(interval, token.clone()));
+        *self.prober.write().await = Some(token);
+        Ok(())
+    }
+
+    /// Stop probing and switching; the active proxy stays configured
+    pub async fn stop(&self) {
+        if let Some(token) = self.prober.write().await.take() {
+            token.cancel();
+            log::info!("Proxy failover stopped");
+        }
+        *self.state.write().await = None;
+    }
+
+    /// Current probe results and active upstream
+    pub async fn status(&self) -> FailoverStatus {
+        match *self.state.read().await {
+            Some(ref group) => FailoverStatus {
+                enabled: true,
+                active: group
+                    .active
+                    .map(|index| group.members[index].profile_id.clone()),
+                upstreams: group.health.clone(),
+            },
+            None => FailoverStatus::default(),
+        }
+    }
+
+    /// Subscribe to upstream switches
+    pub fn subscribe(&self) -> broadcast::Receiver<FailoverEvent> {
+        self.events.subscribe()
+    }
+
+    async fn probe_loop(self: Arc<Self>, interval: Duration, token: CancellationToken) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = interval.tick() => self.probe_round(&token).await,
+            }
+        }
+    }
+
+    /// Probe every member concurrently, then decide whether to switch
+    async fn probe_round(&self, token: &CancellationToken) {
+        let members = match *self.state.read().await {
+            Some(ref group) => group.members.clone(),
+            None => return,
+        };
+
+        let mut probes = JoinSet::new();
+        for (index, member) in members.into_iter().enumerate() {
+            let proxy_manager = self.proxy_manager.clone();
+            probes
+                .spawn(async move { (index, proxy_manager.test_connection(&member.config).await) });
+        }
+        let mut results = Vec::new();
+        while let Some(result) = probes.join_next().await {
+            if let Ok(result) = result {
+                results.push(result);
+            }
+        }
+
+        // The group may have been stopped or restarted while probing
+        if token.is_cancelled() {
+            return;
+        }
+        let status = self.proxy_manager.get_status().await;
+        let mut state = self.state.write().await;
+        let Some(ref mut group) = *state else {
+            return;
+        };
+        for (index, result) in results {
+            group.health[index].record(&result);
+        }
+
+        if let Some((to, reason)) = next_upstream(group, &status) {
+            self.switch(group, to, reason).await;
+        }
+    }
+
+    /// Make member `to` the active proxy and announce the switch
+    async fn switch(&self, group: &mut GroupState, to: usize, reason: String) {
+        let current = self.proxy_manager.get_config().await;
+        // Members are enabled configs, so switching now would turn the proxy back on
+        // behind the user's back; the next round after it is re-enabled picks up from here
+        if !current.enabled {
+            log::debug!(
+                "Not failing over to {} while the proxy is off",
+                group.members[to].name
+            );
+            return;
+        }
+        let config = ProxyConfig {
+            auto_connect: current.auto_connect,
+            ..group.members[to].config.clone()
+        };
+        if let Err(e) = self.proxy_manager.configure(config).await {
+            log::warn!("Failover to {} failed: {}", group.members[to].name, e);
+            group.health[to].last_error = Some(e.to_string());
+            return;
+        }
+
+        let from = group.active.map(|index| group.members[index].upstream());
+        let event = FailoverEvent {
+            from,
+            to: group.members[to].upstream(),
+            reason,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+        log::info!("Proxy failover to {}: {}", event.to.name, event.reason);
+
+        group.active = Some(to);
+        group.switched_at = Instant::now();
+        let _ = self.events.send(event);
+    }
+}
+
+/// Decide whether to leave the active upstream, and for which member
+fn next_upstream(group: &GroupState, status: &ProxyStatus) -> Option<(usize, String)> {
+    let healthy = |index: usize| group.health[index].consecutive_successes > 0;
+
+    let Some(active) = group.active else {
+        let to = (0..group.members.len()).find(|&index| healthy(index))?;
+        return Some((to, "No upstream was active".to_string()));
+    };
+    let name = &group.members[active].name;
+    let health = &group.health[active];
+
+    // Fail over once the active upstream has failed enough probes in a row,
+    // or the health monitor has given up on it
+    let reason = if health.consecutive_failures >= group.config.failures_to_switch {
+        Some(format!(
+            "{} failed {} probes in a row: {}",
+            name,
+            health.consecutive_failures,
+            health.last_error.as_deref().unwrap_or("unknown error")
+        ))
+    } else if !status.is_connected && status.last_error.is_some() && !healthy(active) {
+        Some(format!(
+            "{} marked disconnected: {}",
+            name,
+            status.last_error.as_deref().unwrap_or_default()
+        ))
+    } else {
+        None
+    };
+    if let Some(reason) = reason {
+        let to = (0..group.members.len()).find(|&index| index != active && healthy(index))?;
+        return Some((to, reason));
+    }
+
+    // Return to a preferred upstream only once it has been stable for a while
+    let dwell = Duration::from_secs(group.config.min_dwell_secs);
+    if group.switched_at.elapsed() < dwell {
+        return None;
+    }
+    let to = (0..active).find(|&index| {
+        group.health[index].consecutive_successes >= group.config.successes_to_return
+    })?;
+    Some((
+        to,
+        format!(
+            "Preferred upstream {} recovered ({} successful probes)",
+            group.members[to].name, group.health[to].consecutive_successes
+        ),
+    ))
+}
+
+pub type SharedFailoverGroup = Arc<FailoverGroup>;
+
+pub fn create_failover_group(proxy_manager: SharedProxyManager) -> SharedFailoverGroup {
+    Arc::new(FailoverGroup::new(proxy_manager))
+}
@@ -0,0 +1,623 @@
+//! Local forwarding proxy
+//! Loopback-only HTTP CONNECT and SOCKS5 listener that relays webview traffic
+//! to the configured upstream, adding the upstream credentials on the way out
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, RwLock};
+use tokio_util::sync::CancellationToken;
+
+use crate::network::resolver::DnsResolver;
+use crate::network::routing::{RouteAction, RoutingTable};
+use crate::proxy::balancer::LoadBalancer;
+use crate::proxy::config::{KillSwitchState, ProxyConfig};
+use crate::proxy::pac::{PacResolver, PacRoute};
+use crate::proxy::shadowsocks::ShadowsocksPlugin;
+use crate::proxy::ssh::SshTunnel;
+use crate::proxy::upstream::{
+    connect_direct, connect_upstream, connect_via_proxy, format_authority, proxy_authorization,
+    BoxedStream, ProxyAuthRequired,
+};
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_NO_AUTH: u8 = 0x00;
+const SOCKS5_NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+const SOCKS5_REPLY_SUCCEEDED: u8 = 0x00;
+const SOCKS5_REPLY_GENERAL_FAILURE: u8 = 0x01;
+const SOCKS5_REPLY_NOT_ALLOWED: u8 = 0x02;
+const SOCKS5_REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const SOCKS5_REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS5_REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Maximum size of an HTTP request head accepted from the webview
+const MAX_REQUEST_HEAD: usize = 64 * 1024;
+
+/// Where the forwarder sends traffic
+#[derive(Clone)]
+pub struct Upstream {
+    /// Active proxy configuration (credentials, bypass list)
+    pub config: ProxyConfig,
+    /// PAC script choosing the proxy per target, for `ProxyType::Pac`
+    pub pac: Option<Arc<PacResolver>>,
+    /// SIP003 plugin the Shadowsocks server is reached through
+    pub plugin: Option<Arc<ShadowsocksPlugin>>,
+    /// SSH session tunnels are opened through, for `ProxyType::Ssh`
+    pub ssh: Option<Arc<SshTunnel>>,
+    /// Load balancer picking the proxy per connection, overriding `config` for dialing
+    pub balancer: Option<Arc<LoadBalancer>>,
+    /// Split routing rules deciding whether a target goes direct, upstream, or nowhere
+    pub routing: Option<Arc<RoutingTable>>,
+    /// Resolver from the DNS settings; the system resolver when `None`
+    pub resolver: Option<DnsResolver>,
+}
+
+impl From<ProxyConfig> for Upstream {
+    fn from(config: ProxyConfig) -> Self {
+        Self {
+            config,
+            pac: None,
+            plugin: None,
+            ssh: None,
+            balancer: None,
+            routing: None,
+            resolver: None,
+        }
+    }
+}
+
+/// Local forwarding proxy bound to an ephemeral loopback port
+pub struct LocalForwarder {
+    local_addr: SocketAddr,
+    upstream: Arc<RwLock<Upstream>>,
+    shutdown: CancellationToken,
+}
+
+impl LocalForwarder {
+    /// Bind to 127.0.0.1 on an ephemeral port and start relaying to `upstream`.
+    /// While `kill_switch` reports engaged, new clients are refused and open tunnels are cut.
+    pub async fn start(
+        upstream: Upstream,
+        kill_switch: watch::Receiver<KillSwitchState>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let local_addr = listener.local_addr()?;
+        let upstream = Arc::new(RwLock::new(upstream));
+        let shutdown = CancellationToken::new();
+
+        tokio::spawn(accept_loop(
+            listener,
+            upstream.clone(),
+            kill_switch,
+            shutdown.clone(),
+        ));
+
+        log::info!("Local proxy listening on {}", local_addr);
+
+        Ok(Self {
+            local_addr,
+            upstream,
+            shutdown,
+        })
+    }
+
+    /// Address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Proxy URL to hand to the webview
+    pub fn proxy_url(&self) -> String {
+        format!("http://{}", self.local_addr)
+    }
+
+    /// Replace the upstream used for new connections, keeping the same local port
+    pub async fn set_upstream(&self, upstream: Upstream) {
+        *self.upstream.write().await = upstream;
+    }
+
+    /// Replace the routing rules, keeping the upstream
+    pub async fn set_routing(&self, routing: Option<Arc<RoutingTable>>) {
+        self.upstream.write().await.routing = routing;
+    }
+
+    /// Open a tunnel to `host:port` the way a client of the forwarder would get one,
+    /// bypassing the listener and so the kill switch
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        dial(&self.upstream, host, port).await
+    }
+
+    /// Stop accepting connections and tear down active tunnels
+    pub fn stop(&self) {
+        if !self.shutdown.is_cancelled() {
+            self.shutdown.cancel();
+            log::info!("Local proxy on {} stopped", self.local_addr);
+        }
+    }
+}
+
+impl Drop for LocalForwarder {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    upstream: Arc<RwLock<Upstream>>,
+    kill_switch: watch::Receiver<KillSwitchState>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let (socket, peer) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Local proxy accept failed: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        if !peer.ip().is_loopback() {
+            log::warn!("Local proxy rejected non-loopback client {}", peer);
+            continue;
+        }
+
+        if kill_switch.borrow().engaged {
+            log::debug!("Local proxy refused {}: kill switch engaged", peer);
+            continue;
+        }
+
+        let upstream = upstream.clone();
+        let kill_switch = kill_switch.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = shutdown.cancelled() => {}
+                _ = kill_switch_engaged(kill_switch) => {
+                    log::debug!("Local proxy closed tunnel for {}: kill switch engaged", peer);
+                }
+                result = handle_client(socket, upstream) => {
+                    if let Err(e) = result {
+                        log::debug!("Local proxy client {} error: {}", peer, e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Resolve once the kill switch engages
+async fn kill_switch_engaged(mut kill_switch: watch::Receiver<KillSwitchState>) {
+    if kill_switch.wait_for(|state| state.engaged).await.is_err() {
+        // The manager owning the sender is gone; never resolve
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn handle_client(socket: TcpStream, upstream: Arc<RwLock<Upstream>>) -> io::Result<()> {
+    socket.set_nodelay(true)?;
+
+    let mut first = [0u8; 1];
+    if socket.peek(&mut first).await? == 0 {
+        return Ok(());
+    }
+
+    if first[0] == SOCKS5_VERSION {
+        serve_socks5(socket, &upstream).await
+    } else {
+        serve_http(socket, &upstream).await
+    }
+}
+
+/// Open a tunnel to the requested target through the current upstream
+async fn dial(upstream: &RwLock<Upstream>, host: &str, port: u16) -> io::Result<BoxedStream> {
+    let (stream, _) = dial_request(upstream, host, port, false).await?;
+    Ok(stream)
+}
+
+/// Open a connection for a client request to `host:port` through the current upstream.
+/// With `plain_http`, an HTTP(S) proxy is connected to itself and returned alongside the
+/// stream, so the request can be forwarded to it in absolute form.
+async fn dial_request(
+    upstream: &RwLock<Upstream>,
+    host: &str,
+    port: u16,
+    plain_http: bool,
+) -> io::Result<(BoxedStream, Option<ProxyConfig>)> {
+    let Upstream {
+        config,
+        pac,
+        plugin,
+        ssh,
+        balancer,
+        routing,
+        resolver,
+    } = upstream.read().await.clone();
+    let resolver = resolver.as_ref();
+    if let Some(routing) = routing {
+        match routing.route(host, port, resolver).await {
+            RouteAction::Block => {
+                log::debug!("Local proxy blocked {}:{} (routing rule)", host, port);
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} is blocked by a routing rule", host),
+                ));
+            }
+            RouteAction::Direct => {
+                log::debug!("Local proxy connecting {} directly (routing rule)", host);
+                return Ok((connect_direct(host, port, resolver).await?, None));
+            }
+            RouteAction::Proxy => {}
+        }
+    }
+    if config.bypasses(host) {
+        log::debug!("Local proxy connecting {} directly (no_proxy)", host);
+        return Ok((connect_direct(host, port, resolver).await?, None));
+    }
+
+    if let Some(balancer) = balancer {
+        return balancer.dial(host, port, plain_http).await;
+    }
+
+    if let Some(pac) = pac {
+        return dial_pac(pac, &config, host, port, resolver, plain_http).await;
+    }
+    if let Some(ssh) = ssh {
+        return Ok((ssh.open(host, port).await?, None));
+    }
+    match plugin {
+        Some(plugin) => {
+            let stream = connect_via_proxy(&plugin.route(&config), host, port, resolver).await?;
+            Ok((stream, None))
+        }
+        None => connect_upstream(&config, host, port, resolver, plain_http).await,
+    }
+}
+
+/// Ask the PAC script for routes to the target and try them in order
+async fn dial_pac(
+    pac: Arc<PacResolver>,
+    config: &ProxyConfig,
+    host: &str,
+    port: u16,
+    resolver: Option<&DnsResolver>,
+    plain_http: bool,
+) -> io::Result<(BoxedStream, Option<ProxyConfig>)> {
+    // The forwarder only sees host and port; infer the scheme from the port
+    let scheme = if port == 80 || plain_http {
+        "http"
+    } else {
+        "https"
+    };
+    let url = url::Url::parse(&format!("{}://{}/", scheme, format_authority(host, port)))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    // A failing script is an error rather than a direct connection, so it cannot
+    // route Figma traffic around the proxy and the kill switch
+    let pac_resolver = resolver.cloned();
+    let routes = tokio::task::spawn_blocking(move || pac.find_proxy(&url, pac_resolver.as_ref()))
+        .await
+        .map_err(io::Error::other)?
+        .map_err(|e| {
+            log::warn!("PAC evaluation failed for {}:{}: {}", host, port, e);
+            io::Error::other(e)
+        })?;
+
+    let mut last_error = None;
+    for route in routes {
+        let result = match route.to_config(config) {
+            None => connect_direct(host, port, resolver)
+                .await
+                .map(|stream| (stream, None)),
+            Some(ref proxy) => connect_upstream(proxy, host, port, resolver, plain_http).await,
+        };
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                if let PacRoute::Proxy { ref host, port, .. } = route {
+                    log::debug!("PAC route {}:{} failed: {}", host, port, e);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "PAC returned no routes")))
+}
+
+async fn serve_socks5(mut socket: TcpStream, upstream: &RwLock<Upstream>) -> io::Result<()> {
+    // Method negotiation: only "no authentication" is offered on loopback
+    let mut greeting = [0u8; 2];
+    socket.read_exact(&mut greeting).await?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    socket.read_exact(&mut methods).await?;
+
+    if !methods.contains(&SOCKS5_NO_AUTH) {
+        socket
+            .write_all(&[SOCKS5_VERSION, SOCKS5_NO_ACCEPTABLE_METHOD])
+            .await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 client offered no acceptable auth method",
+        ));
+    }
+    socket.write_all(&[SOCKS5_VERSION, SOCKS5_NO_AUTH]).await?;
+
+    // Request: VER CMD RSV ATYP DST.ADDR DST.PORT
+    let mut request = [0u8; 4];
+    socket.read_exact(&mut request).await?;
+
+    let host = match request[3] {
+        SOCKS5_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            socket.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            socket.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let len = socket.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            socket.read_exact(&mut domain).await?;
+            String::from_utf8(domain)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS5 domain"))?
+        }
+        _ => {
+            write_socks5_reply(&mut socket, SOCKS5_REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported SOCKS5 address type",
+            ));
+        }
+    };
+    let port = socket.read_u16().await?;
+
+    if request[1] != SOCKS5_CMD_CONNECT {
+        write_socks5_reply(&mut socket, SOCKS5_REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Only SOCKS5 CONNECT is supported",
+        ));
+    }
+
+    let mut remote = match dial(upstream, &host, port).await {
+        Ok(remote) => remote,
+        Err(e) => {
+            // An upstream 407 has its own error, so it is a general failure here
+            // rather than a ruleset refusal
+            let reply = match e.kind() {
+                io::ErrorKind::ConnectionRefused => SOCKS5_REPLY_CONNECTION_REFUSED,
+                io::ErrorKind::PermissionDenied => SOCKS5_REPLY_NOT_ALLOWED,
+                _ => SOCKS5_REPLY_GENERAL_FAILURE,
+            };
+            write_socks5_reply(&mut socket, reply).await?;
+            return Err(e);
+        }
+    };
+    write_socks5_reply(&mut socket, SOCKS5_REPLY_SUCCEEDED).await?;
+
+    copy_bidirectional(&mut socket, &mut remote).await?;
+    Ok(())
+}
+
+async fn write_socks5_reply(socket: &mut TcpStream, reply: u8) -> io::Result<()> {
+    // Bound address is not meaningful for a relayed tunnel, report 0.0.0.0:0
+    socket
+        .write_all(&[
+            SOCKS5_VERSION,
+            reply,
+            0x00,
+            SOCKS5_ATYP_IPV4,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])
+        .await
+}
+
+async fn serve_http(mut socket: TcpStream, upstream: &RwLock<Upstream>) -> io::Result<()> {
+    let (head, rest) = match read_request_head(&mut socket).await {
+        Ok(parts) => parts,
+        Err(e) => {
+            write_http_error(&mut socket, "400 Bad Request").await?;
+            return Err(e);
+        }
+    };
+
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => {
+            write_http_error(&mut socket, "400 Bad Request").await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed request line: {}", request_line),
+            ));
+        }
+    };
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let Some((host, port)) = parse_authority(target) else {
+            write_http_error(&mut socket, "400 Bad Request").await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid CONNECT target: {}", target),
+            ));
+        };
+
+        let mut remote = match dial(upstream, &host, port).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                write_dial_error(&mut socket, &e).await?;
+                return Err(e);
+            }
+        };
+
+        socket
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        if !rest.is_empty() {
+            remote.write_all(&rest).await?;
+        }
+        copy_bidirectional(&mut socket, &mut remote).await?;
+        return Ok(());
+    }
+
+    // Plain HTTP in absolute form: pass it on as is to an HTTP(S) proxy, otherwise
+    // tunnel to the origin and rewrite to origin form
+    let url = match url::Url::parse(target) {
+        Ok(url) if url.scheme() == "http" && url.host_str().is_some() => url,
+        _ => {
+            write_http_error(&mut socket, "400 Bad Request").await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported request target: {}", target),
+            ));
+        }
+    };
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_matches(|c| c == '[' || c == ']')
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let mut headers = String::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        if name.eq_ignore_ascii_case("proxy-authorization")
+            || name.eq_ignore_ascii_case("proxy-connection")
+            || name.eq_ignore_ascii_case("connection")
+        {
+            continue;
+        }
+        headers.push_str(line);
+        headers.push_str("\r\n");
+    }
+
+    let (mut remote, proxy) = match dial_request(upstream, &host, port, true).await {
+        Ok(dialed) => dialed,
+        Err(e) => {
+            write_dial_error(&mut socket, &e).await?;
+            return Err(e);
+        }
+    };
+
+    let mut outgoing = match proxy {
+        Some(ref proxy) => {
+            let mut outgoing = format!("{} {} {}\r\n", method, url, version);
+            if let Some(authorization) = proxy_authorization(proxy) {
+                outgoing.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+            }
+            outgoing
+        }
+        None => {
+            let mut path = url.path().to_string();
+            if let Some(query) = url.query() {
+                path.push('?');
+                path.push_str(query);
+            }
+            format!("{} {} {}\r\n", method, path, version)
+        }
+    };
+    outgoing.push_str(&headers);
+    outgoing.push_str("Connection: close\r\n\r\n");
+
+    remote.write_all(outgoing.as_bytes()).await?;
+    if !rest.is_empty() {
+        remote.write_all(&rest).await?;
+    }
+    copy_bidirectional(&mut socket, &mut remote).await?;
+    Ok(())
+}
+
+/// Read an HTTP request head, returning it along with any bytes read past it
+async fn read_request_head(socket: &mut TcpStream) -> io::Result<(String, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(pos + 4);
+            buf.truncate(pos);
+            let head = String::from_utf8(buf).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "Request head is not UTF-8")
+            })?;
+            return Ok((head, rest));
+        }
+        if buf.len() >= MAX_REQUEST_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request head too large",
+            ));
+        }
+
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Client closed the connection before sending a request",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Tell the client why the upstream could not be reached: 403 for a routing rule block,
+/// 502 otherwise, explained when the upstream proxy rejected our credentials
+async fn write_dial_error(socket: &mut TcpStream, error: &io::Error) -> io::Result<()> {
+    if ProxyAuthRequired::is(error) {
+        let message = "Upstream proxy rejected the configured credentials";
+        return write_http_response(socket, "502 Bad Gateway", message).await;
+    }
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => write_http_error(socket, "403 Forbidden").await,
+        _ => write_http_error(socket, "502 Bad Gateway").await,
+    }
+}
+
+async fn write_http_error(socket: &mut TcpStream, status: &str) -> io::Result<()> {
+    write_http_response(socket, status, "").await
+}
+
+async fn write_http_response(socket: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await
+}
+
+/// Split a CONNECT target (`host:port` or `[v6]:port`) into its parts
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Some((addr.ip().to_string(), addr.port()));
+    }
+
+    let (host, port) = authority.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok()?;
+    if host.is_empty() || host.contains(':') {
+        return None;
+    }
+    Some((host.to_string(), port))
+}
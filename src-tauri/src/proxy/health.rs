@@ -1,155 +1,457 @@
 //! Health check module for proxy connections
 //! Monitors connection status and performs periodic health checks
 
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
-use tokio::time::interval;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch, Mutex, RwLock};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 
-use crate::proxy::config::ProxyTestResult;
 use crate::proxy::manager::SharedProxyManager;
+use crate::proxy::probe::{run_probe, HealthCheckReport, HealthProbe, ProbeKind, ProbeResult};
 
-/// Health check configuration
-#[derive(Debug, Clone)]
+/// Share of the probe weight that has to pass for a check to count as healthy
+const HEALTHY_SCORE: f64 = 0.5;
+
+/// Health check configuration, persisted under `keys::HEALTH_CHECK`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct HealthCheckConfig {
     /// Interval between health checks in seconds
+    #[serde(default = "default_interval")]
     pub interval_secs: u64,
-    /// Timeout for health check requests
-    pub timeout_secs: u64,
     /// Number of consecutive failures before marking as disconnected
+    #[serde(default = "default_failure_threshold")]
     pub failure_threshold: u32,
-    /// URL to use for health checks
-    pub check_url: String,
+    /// Probes run concurrently on every check
+    #[serde(default = "HealthProbe::defaults")]
+    pub probes: Vec<HealthProbe>,
+}
+
+fn default_interval() -> u64 {
+    30
+}
+
+fn default_failure_threshold() -> u32 {
+    3
 }
 
 impl Default for HealthCheckConfig {
     fn default() -> Self {
         Self {
-            interval_secs: 30,
-            timeout_secs: 10,
-            failure_threshold: 3,
-            check_url: "https://www.google.com".to_string(),
+            interval_secs: default_interval(),
+            failure_threshold: default_failure_threshold(),
+            probes: HealthProbe::defaults(),
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    /// Validate the health check settings
+    pub fn validate(&self) -> Result<(), String> {
+        if !(5..=3600).contains(&self.interval_secs) {
+            return Err("Health check interval must be between 5 and 3600 seconds".to_string());
+        }
+        if !(1..=100).contains(&self.failure_threshold) {
+            return Err("Failure threshold must be between 1 and 100".to_string());
+        }
+        if self.probes.is_empty() {
+            return Err("Add at least one health probe".to_string());
+        }
+        for (index, probe) in self.probes.iter().enumerate() {
+            probe
+                .validate()
+                .map_err(|e| format!("Probe {}: {}", index + 1, e))?;
+            if probe.timeout_secs > self.interval_secs {
+                return Err(format!(
+                    "Probe {}: timeout must not be longer than the interval",
+                    index + 1
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Connection state change found by a health check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StatusChange {
+    /// First healthy check after the proxy was enabled or the monitor started
+    Connected,
+    /// Some probes or checks fail, but fewer than the failure threshold allows
+    Degraded,
+    /// The failure threshold was reached
+    Disconnected,
+    /// Fully healthy again after being degraded or disconnected
+    Recovered,
+}
+
+impl StatusChange {
+    /// Name of the Tauri event the change is sent to the frontend as
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            StatusChange::Connected => "proxy-connected",
+            StatusChange::Degraded => "proxy-degraded",
+            StatusChange::Disconnected => "proxy-disconnected",
+            StatusChange::Recovered => "proxy-recovered",
         }
     }
 }
 
+/// Sent whenever a health check changes the connection state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusChangeEvent {
+    pub change: StatusChange,
+    /// State before the change, `None` on the first check
+    pub previous: Option<LinkState>,
+    /// Latency of the check that caused the change, if it got through
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+    /// Lowest layer with a failed probe
+    pub failed_layer: Option<ProbeKind>,
+    pub consecutive_failures: u32,
+    pub timestamp: String,
+}
+
+/// Connection state as of the last health check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LinkState {
+    Connected,
+    Degraded,
+    Disconnected,
+}
+
+/// Lifecycle state of the health monitor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HealthMonitorState {
+    Stopped,
+    Running,
+    /// The loop is alive but skips its checks until resumed
+    Paused,
+}
+
+/// Health monitor state as reported to the frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthMonitorStatus {
+    pub state: HealthMonitorState,
+    pub consecutive_failures: u32,
+    /// Probe results of the last finished check
+    pub last_report: Option<HealthCheckReport>,
+}
+
 /// Health monitor for proxy connections
 pub struct HealthMonitor {
     proxy_manager: SharedProxyManager,
-    config: HealthCheckConfig,
-    is_running: RwLock<bool>,
+    config: RwLock<HealthCheckConfig>,
+    /// Wakes the loop when the settings change or checks resume
+    wake: watch::Sender<()>,
+    /// Held for the length of a check so the loop and `check_now` never overlap
+    checking: Mutex<()>,
+    /// Cancels the running loop; `None` while stopped
+    runner: RwLock<Option<CancellationToken>>,
+    paused: AtomicBool,
     consecutive_failures: RwLock<u32>,
+    last_report: RwLock<Option<HealthCheckReport>>,
+    /// Connection state of the last check; `None` until the first one after a (re)start
+    link: RwLock<Option<LinkState>>,
+    events: broadcast::Sender<StatusChangeEvent>,
 }
 
 impl HealthMonitor {
     pub fn new(proxy_manager: SharedProxyManager, config: HealthCheckConfig) -> Self {
         Self {
             proxy_manager,
-            config,
-            is_running: RwLock::new(false),
+            config: RwLock::new(config),
+            wake: watch::channel(()).0,
+            checking: Mutex::new(()),
+            runner: RwLock::new(None),
+            paused: AtomicBool::new(false),
             consecutive_failures: RwLock::new(0),
+            last_report: RwLock::new(None),
+            link: RwLock::new(None),
+            events: broadcast::channel(16).0,
         }
     }
 
-    /// Start the health monitoring loop
-    pub async fn start(&self) {
-        let mut running = self.is_running.write().await;
-        if *running {
+    /// Start the health monitoring loop; does nothing if it is already running
+    pub async fn start(self: &Arc<Self>) {
+        let mut runner = self.runner.write().await;
+        if runner.is_some() {
             return;
         }
-        *running = true;
-        drop(running);
+        self.paused.store(false, Ordering::Relaxed);
+
+        let token = CancellationToken::new();
+        // A fresh subscription drops wakeups from before this run, which its first check covers
+        let wake = self.wake.subscribe();
+        tokio::spawn(self.clone().run_loop(token.clone(), wake));
+        *runner = Some(token);
 
         log::info!(
             "Starting health monitor with {}s interval",
-            self.config.interval_secs
+            self.config.read().await.interval_secs
         );
     }
 
-    /// Stop the health monitoring
+    /// Stop the health monitoring, abandoning a check in progress
     pub async fn stop(&self) {
-        let mut running = self.is_running.write().await;
-        *running = false;
-        log::info!("Health monitor stopped");
+        if let Some(token) = self.runner.write().await.take() {
+            token.cancel();
+            log::info!("Health monitor stopped");
+        }
+    }
+
+    /// Stop the loop and start a fresh one with the failure count cleared
+    pub async fn restart(self: &Arc<Self>) {
+        self.stop().await;
+        self.reset_failures().await;
+        *self.link.write().await = None;
+        self.start().await;
+    }
+
+    /// Subscribe to connection state changes
+    pub fn subscribe(&self) -> broadcast::Receiver<StatusChangeEvent> {
+        self.events.subscribe()
+    }
+
+    /// Skip the periodic checks until `resume`
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::Relaxed) {
+            log::info!("Health monitor paused");
+        }
     }
 
-    /// Perform a single health check
+    /// Resume the periodic checks, starting with one right away
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::Relaxed) {
+            log::info!("Health monitor resumed");
+            self.wake.send_replace(());
+        }
+    }
+
+    /// Run one check immediately, whatever the loop's state
+    pub async fn check_now(&self) -> bool {
+        self.check_health().await
+    }
+
+    pub async fn status(&self) -> HealthMonitorStatus {
+        let state = if self.runner.read().await.is_none() {
+            HealthMonitorState::Stopped
+        } else if self.paused.load(Ordering::Relaxed) {
+            HealthMonitorState::Paused
+        } else {
+            HealthMonitorState::Running
+        };
+        HealthMonitorStatus {
+            state,
+            consecutive_failures: self.get_failure_count().await,
+            last_report: self.last_report.read().await.clone(),
+        }
+    }
+
+    /// Restart the monitor whenever the proxy is enabled again (call this in a spawned task)
+    pub async fn watch_proxy(self: Arc<Self>) {
+        let mut enabled = self.proxy_manager.subscribe_enabled();
+        enabled.borrow_and_update();
+        while enabled.changed().await.is_ok() {
+            if *enabled.borrow_and_update() {
+                log::info!("Proxy enabled, restarting health monitor");
+                self.restart().await;
+            }
+        }
+    }
+
+    /// Current health check settings
+    pub async fn config(&self) -> HealthCheckConfig {
+        self.config.read().await.clone()
+    }
+
+    /// Replace the health check settings; a running loop checks again right away and
+    /// waits the new interval from then on
+    pub async fn set_config(&self, config: HealthCheckConfig) -> Result<(), String> {
+        config.validate()?;
+        log::info!(
+            "Health check settings: {}s interval, threshold {}, {} probes",
+            config.interval_secs,
+            config.failure_threshold,
+            config.probes.len()
+        );
+        *self.config.write().await = config;
+        // Stays pending if the loop is mid-check, so the wakeup is not lost
+        self.wake.send_replace(());
+        Ok(())
+    }
+
+    /// Perform a single health check, after any check already in progress
     pub async fn check_health(&self) -> bool {
+        let _checking = self.checking.lock().await;
+        self.run_check().await
+    }
+
+    async fn run_check(&self) -> bool {
         let proxy_manager = &self.proxy_manager;
 
         // Skip if proxy is not enabled
         if !proxy_manager.is_enabled().await {
+            // The next check after re-enabling counts as a fresh connection
+            *self.link.write().await = None;
             return true; // Direct connection assumed healthy
         }
 
         let config = proxy_manager.get_config().await;
+        let settings = self.config().await;
 
-        // Build a ProxyTestResult by performing a GET to the configured check_url
-        let result: ProxyTestResult = {
-            // Validate config first
-            if let Err(e) = config.validate() {
-                ProxyTestResult::failure(e)
-            } else {
-                let start = Instant::now();
-                let response_result = proxy_manager.request(&self.config.check_url).await;
-
-                match response_result {
-                    Ok(response) => {
-                        let latency = start.elapsed().as_millis() as u64;
-                        if response.status().is_success() {
-                            ProxyTestResult::success(latency, None)
-                        } else {
-                            ProxyTestResult::failure(format!("HTTP error: {}", response.status()))
-                        }
+        let report = match config.validate() {
+            // Every probe would fail the same way
+            Err(e) => HealthCheckReport::new(
+                settings
+                    .probes
+                    .iter()
+                    .map(|probe| ProbeResult {
+                        kind: probe.kind,
+                        target: probe.target.clone(),
+                        weight: probe.weight,
+                        success: false,
+                        skipped: false,
+                        latency_ms: None,
+                        error: Some(e.clone()),
+                    })
+                    .collect(),
+                HEALTHY_SCORE,
+            ),
+            Ok(()) => {
+                let mut probes = JoinSet::new();
+                for (index, probe) in settings.probes.iter().cloned().enumerate() {
+                    let proxy_manager = proxy_manager.clone();
+                    probes.spawn(async move { (index, run_probe(&proxy_manager, &probe).await) });
+                }
+                let mut results = vec![None; settings.probes.len()];
+                while let Some(result) = probes.join_next().await {
+                    if let Ok((index, result)) = result {
+                        results[index] = Some(result);
                     }
-                    Err(e) => ProxyTestResult::failure(format!("Connection error: {}", e)),
                 }
+                HealthCheckReport::new(results.into_iter().flatten().collect(), HEALTHY_SCORE)
             }
         };
+        let error = report.error();
+        let latency_ms = report.latency_ms();
+        let failed_layer = report.failed_layer;
+        let healthy = report.healthy;
+        *self.last_report.write().await = Some(report);
 
-        if result.success {
+        if healthy {
             *self.consecutive_failures.write().await = 0;
+            // A partial failure is reported without counting against the connection
             proxy_manager
-                .update_status(true, result.latency_ms, None)
+                .update_status(true, latency_ms, error.clone())
+                .await;
+            let link = match error {
+                Some(ref error) => {
+                    log::debug!("Health check degraded: {}", error);
+                    LinkState::Degraded
+                }
+                None => {
+                    log::debug!("Health check passed, latency: {:?}ms", latency_ms);
+                    LinkState::Connected
+                }
+            };
+            self.transition(link, latency_ms, error, failed_layer, 0)
                 .await;
-            log::debug!("Health check passed, latency: {:?}ms", result.latency_ms);
             true
         } else {
-            let mut failures = self.consecutive_failures.write().await;
-            *failures += 1;
+            let failures = {
+                let mut failures = self.consecutive_failures.write().await;
+                *failures += 1;
+                *failures
+            };
 
-            let error = result.error.clone();
-            let is_disconnected = *failures >= self.config.failure_threshold;
+            let is_disconnected = failures >= settings.failure_threshold;
 
             proxy_manager
                 .update_status(!is_disconnected, None, error.clone())
                 .await;
 
+            // A dead SSH session is re-established right away, a hung one once
+            // the failure threshold is reached
+            match proxy_manager.reconnect_ssh(is_disconnected).await {
+                Ok(true) => log::info!("SSH session re-established after failed health check"),
+                Ok(false) => {}
+                Err(e) => log::warn!("SSH reconnect failed: {}", e),
+            }
+
             if is_disconnected {
                 log::warn!(
                     "Health check failed {} times, marking as disconnected",
-                    *failures
+                    failures
                 );
             } else {
-                log::debug!("Health check failed ({}): {:?}", *failures, error);
+                log::debug!("Health check failed ({}): {:?}", failures, error);
             }
 
+            let link = if is_disconnected {
+                LinkState::Disconnected
+            } else {
+                LinkState::Degraded
+            };
+            self.transition(link, None, error, failed_layer, failures)
+                .await;
             !is_disconnected
         }
     }
 
-    /// Run the health check loop (call this in a spawned task)
-    pub async fn run_loop(self: Arc<Self>) {
-        let mut interval = interval(Duration::from_secs(self.config.interval_secs));
+    /// Record the connection state of a check and announce it if it changed
+    async fn transition(
+        &self,
+        link: LinkState,
+        latency_ms: Option<u64>,
+        error: Option<String>,
+        failed_layer: Option<ProbeKind>,
+        consecutive_failures: u32,
+    ) {
+        let previous = self.link.write().await.replace(link);
+        let change = match (previous, link) {
+            (Some(previous), link) if previous == link => return,
+            (_, LinkState::Degraded) => StatusChange::Degraded,
+            (_, LinkState::Disconnected) => StatusChange::Disconnected,
+            (None, LinkState::Connected) => StatusChange::Connected,
+            (Some(_), LinkState::Connected) => StatusChange::Recovered,
+        };
+        log::info!("Proxy connection {:?}", change);
+        let _ = self.events.send(StatusChangeEvent {
+            change,
+            previous,
+            latency_ms,
+            error,
+            failed_layer,
+            consecutive_failures,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        });
+    }
 
+    /// Check every interval until `token` is cancelled
+    async fn run_loop(self: Arc<Self>, token: CancellationToken, mut wake: watch::Receiver<()>) {
         loop {
-            interval.tick().await;
-
-            if !*self.is_running.read().await {
-                break;
+            if !self.paused.load(Ordering::Relaxed) {
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = self.check_health() => {}
+                }
             }
 
-            self.check_health().await;
+            let interval = Duration::from_secs(self.config.read().await.interval_secs);
+            tokio::select! {
+                _ = token.cancelled() => break,
+                _ = tokio::time::sleep(interval) => {}
+                _ = wake.changed() => {}
+            }
         }
     }
 
@@ -1,11 +1,37 @@
 //! Proxy manager module
 //! Handles proxy client creation, connection management, and request routing
 
-use crate::proxy::config::{ProxyConfig, ProxyStatus, ProxyTestResult, ProxyType};
-use reqwest::{Client, Proxy};
+use crate::network::resolver::DnsResolver;
+use crate::network::routing::{RouteAction, RoutingTable};
+use crate::proxy::balancer::LoadBalancer;
+use crate::proxy::config::{
+    HopTestResult, KillSwitchState, ProxyConfig, ProxyStatus, ProxyTestResult, ProxyType,
+};
+use crate::proxy::forwarder::{LocalForwarder, Upstream};
+use crate::proxy::pac::PacResolver;
+use crate::proxy::shadowsocks::{server_config, ShadowsocksPlugin};
+use crate::proxy::ssh::SshTunnel;
+use crate::proxy::system::{detect_system_proxy, SystemProxy};
+use crate::proxy::upstream::{connect_direct, connect_via_proxy, handshake, BoxedStream};
+use reqwest::header::LOCATION;
+use reqwest::{redirect, Client, ClientBuilder, NoProxy, Proxy, StatusCode};
+use std::io;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
+use tokio_util::sync::CancellationToken;
+
+/// How often the active PAC script is checked for changes
+const PAC_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Host and port of the connection test URL, which chain probes tunnel to
+const TEST_TARGET: (&str, u16) = ("api.ipify.org", 443);
+
+/// How long a chain probe waits for each hop
+const HOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Redirects `request` follows, the same limit reqwest applies by default
+const MAX_REDIRECTS: usize = 10;
 
 /// Custom error type for proxy operations
 #[derive(Debug, thiserror::Error)]
@@ -22,6 +48,10 @@ pub enum ProxyError {
     NotConfigured,
     #[error("Proxy disabled")]
     Disabled,
+    #[error("Kill switch engaged: direct connections are blocked")]
+    KillSwitch,
+    #[error("Blocked by a routing rule: {0}")]
+    Blocked(String),
 }
 
 /// Proxy manager handles all proxy-related operations
@@ -29,49 +59,89 @@ pub struct ProxyManager {
     /// HTTP client with proxy configuration
     client: RwLock<Option<Client>>,
     /// Direct HTTP client (no proxy)
-    direct_client: Client,
+    direct_client: RwLock<Client>,
     /// Current proxy configuration
     config: RwLock<ProxyConfig>,
+    /// Whether the proxy is enabled, observed by the health monitor
+    enabled: watch::Sender<bool>,
     /// Current connection status
     status: RwLock<ProxyStatus>,
+    /// Loopback forwarder the Figma webview connects through
+    forwarder: RwLock<Option<LocalForwarder>>,
+    /// Loopback forwarder for the reqwest client when it cannot reach the upstream itself
+    client_forwarder: RwLock<Option<LocalForwarder>>,
+    /// Whether the kill switch is enabled in advanced settings
+    kill_switch: RwLock<bool>,
+    /// Kill switch engagement, observed by the forwarder and the frontend bridge
+    kill_switch_state: watch::Sender<KillSwitchState>,
+    /// System proxy resolved on the last `configure` with auto-detect on
+    system_proxy: RwLock<Option<SystemProxy>>,
+    /// Compiled PAC script when the proxy type is `Pac`
+    pac: RwLock<Option<Arc<PacResolver>>>,
+    /// Cancels the background PAC reload task
+    pac_reload: RwLock<Option<CancellationToken>>,
+    /// SSH session when the proxy type is `Ssh`
+    ssh: RwLock<Option<Arc<SshTunnel>>>,
+    /// Load balancer the forwarders dial through while balancing is on
+    balancer: RwLock<Option<Arc<LoadBalancer>>>,
+    /// Split routing rules applied by the forwarders and `request`
+    routing: RwLock<Option<Arc<RoutingTable>>>,
+    /// Resolver from the DNS settings, used for every connection instead of the system resolver
+    resolver: watch::Sender<Option<DnsResolver>>,
 }
 
 impl ProxyManager {
     /// Create a new proxy manager
     pub fn new() -> Self {
-        let direct_client = Client::builder()
-            .timeout(Duration::from_secs(30))
-            .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
-            .build()
-            .expect("Failed to create HTTP client");
-
         Self {
             client: RwLock::new(None),
-            direct_client,
+            direct_client: RwLock::new(build_direct_client(None)),
             config: RwLock::new(ProxyConfig::default()),
+            enabled: watch::Sender::new(false),
             status: RwLock::new(ProxyStatus::default()),
+            forwarder: RwLock::new(None),
+            client_forwarder: RwLock::new(None),
+            kill_switch: RwLock::new(false),
+            kill_switch_state: watch::Sender::new(KillSwitchState::default()),
+            system_proxy: RwLock::new(None),
+            pac: RwLock::new(None),
+            pac_reload: RwLock::new(None),
+            ssh: RwLock::new(None),
+            balancer: RwLock::new(None),
+            routing: RwLock::new(None),
+            resolver: watch::Sender::new(None),
         }
     }
 
     /// Configure the proxy with the given settings
     pub async fn configure(&self, config: ProxyConfig) -> Result<(), ProxyError> {
+        // Re-detect the system proxy every time so desktop changes are picked up
+        let config = if config.enabled && config.auto_detect {
+            self.resolve_system_proxy(config).await?
+        } else {
+            *self.system_proxy.write().await = None;
+            config
+        };
+
         if config.enabled {
             // Validate configuration
             config.validate().map_err(ProxyError::ConfigError)?;
 
-            // Build the proxy URL
-            let proxy_url = config.to_url();
-
             // Create proxy based on type
-            let proxy = match config.proxy_type {
-                ProxyType::Socks5 => Proxy::all(&proxy_url),
-                ProxyType::Http | ProxyType::Https => Proxy::all(&proxy_url),
-            }
-            .map_err(|e| ProxyError::InvalidUrl(e.to_string()))?;
+            let upstream = Upstream {
+                pac: self.prepare_pac(&config).await?,
+                plugin: prepare_shadowsocks(&config)
+                    .await
+                    .map_err(ProxyError::ConfigError)?,
+                ssh: self.prepare_ssh(&config).await?,
+                ..self.upstream(config.clone()).await
+            };
+            let proxy = self.client_proxy(&upstream).await?;
 
             // Build new client with proxy
-            let client = Client::builder()
+            let client = with_resolver(Client::builder(), self.resolver())
                 .proxy(proxy)
+                .redirect(redirect::Policy::none())
                 .timeout(Duration::from_secs(30))
                 .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
                 .danger_accept_invalid_certs(false)
@@ -80,11 +150,14 @@ impl ProxyManager {
 
             // Update state
             *self.client.write().await = Some(client);
+            self.start_forwarder(upstream).await?;
+            self.release_kill_switch();
         } else {
-            *self.client.write().await = None;
+            self.teardown(&config, "Proxy disabled").await;
         }
 
         *self.config.write().await = config.clone();
+        self.publish_enabled(config.enabled);
 
         // Update status
         let mut status = self.status.write().await;
@@ -102,6 +175,186 @@ impl ProxyManager {
         Ok(())
     }
 
+    /// Resolve the system proxy and merge it into `config`
+    async fn resolve_system_proxy(&self, config: ProxyConfig) -> Result<ProxyConfig, ProxyError> {
+        let detected = tokio::task::spawn_blocking(detect_system_proxy)
+            .await
+            .map_err(|e| {
+                ProxyError::ConfigError(format!("System proxy detection failed: {}", e))
+            })?;
+
+        let Some(detected) = detected else {
+            *self.system_proxy.write().await = None;
+            return Err(ProxyError::ConfigError(
+                "Auto-detect is on but no system proxy is configured".to_string(),
+            ));
+        };
+
+        log::info!(
+            "Using system proxy {}:{} from {:?} ({})",
+            detected.host,
+            detected.port,
+            detected.source,
+            detected.origin
+        );
+        let resolved = detected.apply_to(&config);
+        *self.system_proxy.write().await = Some(detected);
+        Ok(resolved)
+    }
+
+    /// Load the PAC script for `config`, reusing the compiled one when the source is unchanged
+    async fn prepare_pac(
+        &self,
+        config: &ProxyConfig,
+    ) -> Result<Option<Arc<PacResolver>>, ProxyError> {
+        let source = match (config.proxy_type, config.pac_url.as_deref()) {
+            (ProxyType::Pac, Some(source)) => source.trim(),
+            _ => {
+                self.clear_pac().await;
+                return Ok(None);
+            }
+        };
+
+        if let Some(ref pac) = *self.pac.read().await {
+            if pac.source() == source {
+                if let Err(e) = pac.reload_if_changed().await {
+                    log::warn!("Keeping cached PAC script: {}", e);
+                }
+                return Ok(Some(pac.clone()));
+            }
+        }
+
+        let pac = Arc::new(
+            PacResolver::load(source)
+                .await
+                .map_err(ProxyError::ConfigError)?,
+        );
+        *self.pac.write().await = Some(pac.clone());
+
+        // Periodically pick up edits to the script
+        let token = CancellationToken::new();
+        tokio::spawn(pac_reload_loop(pac.clone(), token.clone()));
+        if let Some(previous) = self.pac_reload.write().await.replace(token) {
+            previous.cancel();
+        }
+
+        Ok(Some(pac))
+    }
+
+    /// Drop the PAC script and stop reloading it
+    async fn clear_pac(&self) {
+        if let Some(token) = self.pac_reload.write().await.take() {
+            token.cancel();
+        }
+        *self.pac.write().await = None;
+    }
+
+    /// Upstream for `config` with the manager's balancer, routing rules and resolver,
+    /// before any per-type state (PAC script, plugin, SSH session) is added
+    async fn upstream(&self, config: ProxyConfig) -> Upstream {
+        Upstream {
+            balancer: self.balancer.read().await.clone(),
+            routing: self.routing.read().await.clone(),
+            resolver: self.resolver(),
+            ..Upstream::from(config)
+        }
+    }
+
+    /// Open the SSH session for `config`, replacing any previous one
+    async fn prepare_ssh(
+        &self,
+        config: &ProxyConfig,
+    ) -> Result<Option<Arc<SshTunnel>>, ProxyError> {
+        let tunnel = if config.proxy_type == ProxyType::Ssh {
+            Some(Arc::new(
+                SshTunnel::connect(config, self.resolver().as_ref())
+                    .await
+                    .map_err(ProxyError::ConnectionError)?,
+            ))
+        } else {
+            None
+        };
+        *self.ssh.write().await = tunnel.clone();
+        Ok(tunnel)
+    }
+
+    /// Route new connections through `balancer` (or stop doing so with `None`),
+    /// reporting `config` as the active proxy. While the proxy is off this only records
+    /// the selection, which the next enable picks up.
+    pub async fn set_balancer(
+        &self,
+        balancer: Option<Arc<LoadBalancer>>,
+        config: ProxyConfig,
+    ) -> Result<(), ProxyError> {
+        *self.balancer.write().await = balancer;
+        let enabled = config.enabled && self.is_enabled().await;
+        self.configure(ProxyConfig { enabled, ..config }).await
+    }
+
+    /// Replace the split routing rules, or stop routing with `None`
+    pub async fn set_routing(&self, routing: Option<Arc<RoutingTable>>) {
+        *self.routing.write().await = routing.clone();
+        if let Some(ref forwarder) = *self.client_forwarder.read().await {
+            forwarder.set_routing(routing.clone()).await;
+        }
+        if let Some(ref forwarder) = *self.forwarder.read().await {
+            forwarder.set_routing(routing).await;
+        }
+    }
+
+    /// Resolve names through `resolver`, or the system resolver with `None`, in every
+    /// client and when connecting to the proxy server
+    pub async fn set_resolver(&self, resolver: Option<DnsResolver>) -> Result<(), ProxyError> {
+        *self.direct_client.write().await = build_direct_client(resolver.clone());
+        self.resolver.send_replace(resolver);
+
+        // Rebuild the proxied client with the new resolver
+        let config = self.get_config().await;
+        if config.enabled {
+            self.configure(config).await?;
+        }
+        Ok(())
+    }
+
+    /// Re-establish the SSH session if it died, or unconditionally with `force`,
+    /// and point both forwarders at the new one. Returns whether it reconnected.
+    pub async fn reconnect_ssh(&self, force: bool) -> Result<bool, ProxyError> {
+        let config = self.config.read().await.clone();
+        if !config.enabled || config.proxy_type != ProxyType::Ssh {
+            return Ok(false);
+        }
+        if !force && self.ssh.read().await.as_ref().is_some_and(|t| t.is_alive()) {
+            return Ok(false);
+        }
+
+        log::info!(
+            "Reconnecting SSH session to {}:{}",
+            config.host,
+            config.port
+        );
+        let upstream = Upstream {
+            ssh: self.prepare_ssh(&config).await?,
+            ..self.upstream(config).await
+        };
+        if let Some(ref forwarder) = *self.client_forwarder.read().await {
+            forwarder.set_upstream(upstream.clone()).await;
+        }
+        if let Some(ref forwarder) = *self.forwarder.read().await {
+            forwarder.set_upstream(upstream).await;
+        }
+        Ok(true)
+    }
+
+    /// Active PAC script, if the proxy type is `Pac`
+    pub async fn pac_resolver(&self) -> Option<Arc<PacResolver>> {
+        self.pac.read().await.clone()
+    }
+
+    /// System proxy applied by the last `configure`, if auto-detect is on
+    pub async fn get_system_proxy(&self) -> Option<SystemProxy> {
+        self.system_proxy.read().await.clone()
+    }
+
     /// Test the proxy connection
     pub async fn test_connection(&self, config: &ProxyConfig) -> ProxyTestResult {
         if !config.enabled {
@@ -113,19 +366,84 @@ impl ProxyManager {
             return ProxyTestResult::failure(e);
         }
 
-        // Build test client
-        let proxy_url = config.to_url();
-        let proxy = match config.proxy_type {
-            ProxyType::Socks5 => Proxy::all(&proxy_url),
-            ProxyType::Http | ProxyType::Https => Proxy::all(&proxy_url),
+        // Reach the hops of a chain one by one so a failure can be pinned on one of them
+        let hops = if config.chain.is_empty() {
+            Vec::new()
+        } else {
+            let hops = probe_chain(
+                &config.hops(),
+                TEST_TARGET.0,
+                TEST_TARGET.1,
+                self.resolver().as_ref(),
+            )
+            .await;
+            if let Some(failed) = hops.iter().find(|hop| hop.error.is_some()) {
+                return ProxyTestResult {
+                    failed_hop: Some(failed.proxy.clone()),
+                    ..ProxyTestResult::failure(format!(
+                        "{} failed: {}",
+                        failed.proxy,
+                        failed.error.as_deref().unwrap_or_default()
+                    ))
+                }
+                .with_hops(hops);
+            }
+            hops
         };
 
+        // PAC scripts are loaded fresh so the test reflects the current source
+        let pac = match (config.proxy_type, config.pac_url.as_deref()) {
+            (ProxyType::Pac, Some(source)) => match PacResolver::load(source.trim()).await {
+                Ok(pac) => Some(Arc::new(pac)),
+                Err(e) => return ProxyTestResult::failure(e),
+            },
+            _ => None,
+        };
+
+        // Build test client, through a temporary forwarder if reqwest cannot reach the proxy
+        let mut _forwarder = None;
+        let proxy = if requires_forwarder(config) {
+            let plugin = match prepare_shadowsocks(config).await {
+                Ok(plugin) => plugin,
+                Err(e) => return ProxyTestResult::failure(e),
+            };
+            let ssh = match config.proxy_type {
+                ProxyType::Ssh => {
+                    match SshTunnel::connect(config, self.resolver().as_ref()).await {
+                        Ok(tunnel) => Some(Arc::new(tunnel)),
+                        Err(e) => return ProxyTestResult::failure(e),
+                    }
+                }
+                _ => None,
+            };
+            let upstream = Upstream {
+                config: config.clone(),
+                pac,
+                plugin,
+                ssh,
+                balancer: None,
+                routing: None,
+                resolver: self.resolver(),
+            };
+            match start_client_forwarder(upstream).await {
+                Ok(forwarder) => {
+                    let proxy = Proxy::all(forwarder.proxy_url()).map_err(|e| e.to_string());
+                    _forwarder = Some(forwarder);
+                    proxy
+                }
+                Err(e) => {
+                    return ProxyTestResult::failure(format!("Failed to start local proxy: {}", e))
+                }
+            }
+        } else {
+            build_proxy(config)
+        };
         let proxy = match proxy {
             Ok(p) => p,
             Err(e) => return ProxyTestResult::failure(format!("Invalid proxy URL: {}", e)),
         };
 
-        let client = match Client::builder()
+        let client = match with_resolver(Client::builder(), self.resolver())
             .proxy(proxy)
             .timeout(Duration::from_secs(10))
             .build()
@@ -138,7 +456,7 @@ impl ProxyManager {
         let start = Instant::now();
 
         // Test connection by fetching a simple IP check service
-        match client.get("https://api.ipify.org?format=json").send().await {
+        let result = match client.get("https://api.ipify.org?format=json").send().await {
             Ok(response) => {
                 let latency = start.elapsed().as_millis() as u64;
 
@@ -164,31 +482,163 @@ impl ProxyManager {
                     ProxyTestResult::failure(format!("Connection error: {}", e))
                 }
             }
-        }
+        };
+        result.with_hops(hops)
     }
 
     /// Get current proxy status
     pub async fn get_status(&self) -> ProxyStatus {
-        self.status.read().await.clone()
+        let mut status = self.status.read().await.clone();
+        status.kill_switch_engaged = self.is_kill_switch_engaged();
+        status
     }
 
     /// Enable or disable the proxy
     pub async fn toggle(&self, enabled: bool) -> Result<(), ProxyError> {
-        let mut config = self.config.write().await;
-        config.enabled = enabled;
+        if enabled {
+            // Rebuild the client and forwarders that toggling off tore down
+            let config = ProxyConfig {
+                enabled: true,
+                ..self.get_config().await
+            };
+            return self.configure(config).await;
+        }
 
-        if !enabled {
-            // Clear the proxy client
-            *self.client.write().await = None;
-            let mut status = self.status.write().await;
-            status.is_connected = false;
-            status.latency_ms = None;
-            status.last_updated = chrono::Utc::now().to_rfc3339();
+        let config = {
+            let mut config = self.config.write().await;
+            config.enabled = false;
+            config.clone()
+        };
+        self.publish_enabled(false);
+
+        self.teardown(&config, "Proxy toggled off").await;
+        let mut status = self.status.write().await;
+        status.is_connected = false;
+        status.latency_ms = None;
+        status.last_updated = chrono::Utc::now().to_rfc3339();
+
+        Ok(())
+    }
+
+    /// Stop what an enabled proxy keeps running: the client and its forwarder, the webview
+    /// forwarder (unless the kill switch holds it up), the PAC reload task and the SSH session
+    async fn teardown(&self, config: &ProxyConfig, reason: &str) {
+        *self.client.write().await = None;
+        self.disable_forwarder(reason).await;
+        self.stop_client_forwarder().await;
+        self.clear_pac().await;
+        *self.ssh.write().await = None;
+
+        // A forwarder kept up by the kill switch must not hold on to the SSH session or
+        // the SIP003 plugin process either
+        if let Some(ref forwarder) = *self.forwarder.read().await {
+            forwarder
+                .set_upstream(self.upstream(config.clone()).await)
+                .await;
         }
+    }
 
+    /// Start the local forwarder, or point the running one at the new upstream
+    async fn start_forwarder(&self, upstream: Upstream) -> Result<(), ProxyError> {
+        let mut forwarder = self.forwarder.write().await;
+        match *forwarder {
+            Some(ref running) => running.set_upstream(upstream).await,
+            None => {
+                let started = LocalForwarder::start(upstream, self.kill_switch_state.subscribe())
+                    .await
+                    .map_err(|e| ProxyError::ConnectionError(format!("Local proxy: {}", e)))?;
+                *forwarder = Some(started);
+            }
+        }
         Ok(())
     }
 
+    /// Proxy for the reqwest client: the upstream itself when reqwest speaks its protocol,
+    /// otherwise a private forwarder (exempt from the kill switch so health checks can recover)
+    async fn client_proxy(&self, upstream: &Upstream) -> Result<Proxy, ProxyError> {
+        if !requires_forwarder(&upstream.config) && upstream.balancer.is_none() {
+            self.stop_client_forwarder().await;
+            return build_proxy(&upstream.config).map_err(ProxyError::InvalidUrl);
+        }
+
+        let mut forwarder = self.client_forwarder.write().await;
+        let proxy_url = match *forwarder {
+            Some(ref running) => {
+                running.set_upstream(upstream.clone()).await;
+                running.proxy_url()
+            }
+            None => {
+                let started = start_client_forwarder(upstream.clone())
+                    .await
+                    .map_err(|e| ProxyError::ConnectionError(format!("Local proxy: {}", e)))?;
+                let proxy_url = started.proxy_url();
+                *forwarder = Some(started);
+                proxy_url
+            }
+        };
+
+        Proxy::all(proxy_url).map_err(|e| ProxyError::InvalidUrl(e.to_string()))
+    }
+
+    /// Stop the reqwest client's forwarder if it is running
+    async fn stop_client_forwarder(&self) {
+        if let Some(forwarder) = self.client_forwarder.write().await.take() {
+            forwarder.stop();
+        }
+    }
+
+    /// Stop the local forwarder if it is running
+    async fn stop_forwarder(&self) {
+        if let Some(forwarder) = self.forwarder.write().await.take() {
+            forwarder.stop();
+        }
+    }
+
+    /// Stop the local forwarder, or keep it up but blocking when the kill switch is on
+    /// so an open Figma window cannot fall back to a direct connection
+    async fn disable_forwarder(&self, reason: &str) {
+        if *self.kill_switch.read().await && self.forwarder.read().await.is_some() {
+            self.engage_kill_switch(reason).await;
+        } else {
+            self.stop_forwarder().await;
+        }
+    }
+
+    /// URL of the local forwarder the webview should use, if running
+    pub async fn local_proxy_url(&self) -> Option<String> {
+        self.forwarder
+            .read()
+            .await
+            .as_ref()
+            .map(LocalForwarder::proxy_url)
+    }
+
+    /// Proxy server the app connects to first: the first hop of a chain, nothing for PAC
+    pub async fn proxy_endpoint(&self) -> Option<(String, u16)> {
+        let config = self.config.read().await;
+        if !config.enabled || config.proxy_type == ProxyType::Pac {
+            return None;
+        }
+        match config.chain.first() {
+            Some(hop) => Some((hop.host.clone(), hop.port)),
+            None => Some((config.host.clone(), config.port)),
+        }
+    }
+
+    /// Open a tunnel to `host:port` through the same upstream, routing rules and balancer
+    /// as the webview, ignoring the kill switch
+    pub async fn open_tunnel(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        if let Some(ref forwarder) = *self.forwarder.read().await {
+            return forwarder.connect(host, port).await;
+        }
+        let config = self.get_config().await;
+        if config.enabled {
+            connect_via_proxy(&config, host, port, self.resolver().as_ref()).await
+        } else {
+            connect_direct(host, port, self.resolver().as_ref()).await
+        }
+    }
+
     /// Update connection status after health check
     pub async fn update_status(
         &self,
@@ -196,11 +646,85 @@ impl ProxyManager {
         latency_ms: Option<u64>,
         error: Option<String>,
     ) {
-        let mut status = self.status.write().await;
-        status.is_connected = is_connected;
-        status.latency_ms = latency_ms;
-        status.last_error = error;
-        status.last_updated = chrono::Utc::now().to_rfc3339();
+        {
+            let mut status = self.status.write().await;
+            status.is_connected = is_connected;
+            status.latency_ms = latency_ms;
+            status.last_error = error.clone();
+            status.last_updated = chrono::Utc::now().to_rfc3339();
+        }
+
+        if is_connected {
+            self.release_kill_switch();
+        } else {
+            let reason = error.unwrap_or_else(|| "Proxy disconnected".to_string());
+            self.engage_kill_switch(&reason).await;
+        }
+    }
+
+    /// Enable or disable the kill switch (from advanced settings)
+    pub async fn set_kill_switch(&self, enabled: bool) {
+        *self.kill_switch.write().await = enabled;
+
+        if !enabled && self.is_kill_switch_engaged() {
+            self.release_kill_switch();
+            if !self.is_enabled().await {
+                self.stop_forwarder().await;
+            }
+        }
+    }
+
+    /// Check if the kill switch is enabled in settings
+    pub async fn is_kill_switch_enabled(&self) -> bool {
+        *self.kill_switch.read().await
+    }
+
+    /// Check if the kill switch is currently blocking traffic
+    pub fn is_kill_switch_engaged(&self) -> bool {
+        self.kill_switch_state.borrow().engaged
+    }
+
+    /// Subscribe to kill switch engagement changes
+    pub fn subscribe_kill_switch(&self) -> watch::Receiver<KillSwitchState> {
+        self.kill_switch_state.subscribe()
+    }
+
+    /// Block all Figma traffic if the kill switch is enabled
+    async fn engage_kill_switch(&self, reason: &str) {
+        if !*self.kill_switch.read().await {
+            return;
+        }
+
+        let engaged = self.kill_switch_state.send_if_modified(|state| {
+            if state.engaged {
+                return false;
+            }
+            *state = KillSwitchState {
+                engaged: true,
+                reason: Some(reason.to_string()),
+                since: Some(chrono::Utc::now().to_rfc3339()),
+            };
+            true
+        });
+
+        if engaged {
+            log::warn!("Kill switch engaged: {}", reason);
+        }
+    }
+
+    /// Let traffic flow again once the proxy is back
+    fn release_kill_switch(&self) {
+        let released = self.kill_switch_state.send_if_modified(|state| {
+            if !state.engaged {
+                return false;
+            }
+            *state = KillSwitchState::default();
+            true
+        });
+
+        if released {
+            log::info!("Kill switch released");
+        }
     }
 
     /// Get the current configuration
@@ -208,24 +732,10 @@ impl ProxyManager {
         self.config.read().await.clone()
     }
 
-    /// Make a request through the proxy (if enabled) or directly
+    /// Make a request through the proxy (if enabled) or directly, as the routing rules say.
+    /// With the kill switch on there is no direct fallback.
     pub async fn request(&self, url: &str) -> Result<reqwest::Response, ProxyError> {
-        let config = self.config.read().await;
-
-        if config.enabled {
-            let client = self.client.read().await;
-            if let Some(ref client) = *client {
-                client.get(url).send().await.map_err(ProxyError::from)
-            } else {
-                Err(ProxyError::NotConfigured)
-            }
-        } else {
-            self.direct_client
-                .get(url)
-                .send()
-                .await
-                .map_err(ProxyError::from)
-        }
+        self.send(url, None).await
     }
 
     /// Make a GET request with a single header through the proxy (or directly when proxy disabled).
@@ -236,34 +746,248 @@ impl ProxyManager {
         header_name: &str,
         header_value: &str,
     ) -> Result<reqwest::Response, ProxyError> {
-        let config = self.config.read().await;
+        self.send(url, Some((header_name, header_value))).await
+    }
 
-        if config.enabled {
-            let client = self.client.read().await;
-            if let Some(ref client) = *client {
-                client
-                    .get(url)
-                    .header(header_name, header_value)
-                    .send()
-                    .await
-                    .map_err(ProxyError::from)
-            } else {
-                Err(ProxyError::NotConfigured)
+    /// GET `url`, following redirects here rather than in reqwest so the routing rules
+    /// decide every hop. The extra header is only sent to the original origin.
+    async fn send(
+        &self,
+        url: &str,
+        header: Option<(&str, &str)>,
+    ) -> Result<reqwest::Response, ProxyError> {
+        let mut url = url::Url::parse(url).map_err(|e| ProxyError::InvalidUrl(e.to_string()))?;
+        let origin = url.origin();
+
+        for _ in 0..=MAX_REDIRECTS {
+            let client = self.client_for(url.as_str()).await?;
+            let mut request = client.get(url.clone());
+            if let Some((name, value)) = header.filter(|_| url.origin() == origin) {
+                request = request.header(name, value);
             }
-        } else {
-            self.direct_client
-                .get(url)
-                .header(header_name, header_value)
-                .send()
+            let response = request.send().await?;
+
+            let location = match response.status() {
+                StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT => response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .and_then(|location| url.join(location).ok()),
+                _ => None,
+            };
+            match location {
+                Some(next) => url = next,
+                None => return Ok(response),
+            }
+        }
+
+        Err(ProxyError::ConnectionError(format!(
+            "Too many redirects, last to {}",
+            url
+        )))
+    }
+
+    /// Client a request to `url` goes out with, after applying the routing rules
+    async fn client_for(&self, url: &str) -> Result<Client, ProxyError> {
+        let routing = self.routing.read().await.clone();
+        let action = match routing {
+            Some(routing) => routing.route_url(url, self.resolver().as_ref()).await,
+            None => RouteAction::Proxy,
+        };
+        let enabled = self.config.read().await.enabled;
+
+        match action {
+            RouteAction::Block => Err(ProxyError::Blocked(url.to_string())),
+            RouteAction::Direct if enabled => Ok(self.direct_client.read().await.clone()),
+            _ if enabled => self
+                .client
+                .read()
                 .await
-                .map_err(ProxyError::from)
+                .clone()
+                .ok_or(ProxyError::NotConfigured),
+            _ if *self.kill_switch.read().await => Err(ProxyError::KillSwitch),
+            _ => Ok(self.direct_client.read().await.clone()),
         }
     }
 
+    /// Resolver from the DNS settings, `None` when the system resolver is used
+    pub fn resolver(&self) -> Option<DnsResolver> {
+        self.resolver.borrow().clone()
+    }
+
+    /// Observe the resolver being replaced when the DNS settings change
+    pub fn subscribe_resolver(&self) -> watch::Receiver<Option<DnsResolver>> {
+        self.resolver.subscribe()
+    }
+
     /// Check if proxy is currently enabled
     pub async fn is_enabled(&self) -> bool {
         self.config.read().await.enabled
     }
+
+    /// Observe the proxy being enabled and disabled
+    pub fn subscribe_enabled(&self) -> watch::Receiver<bool> {
+        self.enabled.subscribe()
+    }
+
+    fn publish_enabled(&self, enabled: bool) {
+        self.enabled.send_if_modified(|current| {
+            let changed = *current != enabled;
+            *current = enabled;
+            changed
+        });
+    }
+}
+
+/// Use the configured DNS servers, when set, for the names a client resolves itself
+fn with_resolver(builder: ClientBuilder, resolver: Option<DnsResolver>) -> ClientBuilder {
+    match resolver {
+        Some(resolver) => builder.dns_resolver(Arc::new(resolver)),
+        None => builder,
+    }
+}
+
+fn build_direct_client(resolver: Option<DnsResolver>) -> Client {
+    with_resolver(Client::builder(), resolver)
+        .redirect(redirect::Policy::none())
+        .timeout(Duration::from_secs(30))
+        .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36")
+        .build()
+        .expect("Failed to create HTTP client")
+}
+
+/// Reach each hop in turn, timing every step, and stop at the first one that fails.
+/// The exit hop must also open a tunnel to `host:port`.
+async fn probe_chain(
+    hops: &[ProxyConfig],
+    host: &str,
+    port: u16,
+    resolver: Option<&DnsResolver>,
+) -> Vec<HopTestResult> {
+    let mut results = Vec::with_capacity(hops.len());
+    let mut reached: Option<(BoxedStream, &ProxyConfig)> = None;
+
+    for hop in hops {
+        let start = Instant::now();
+        let step = async {
+            match reached.take() {
+                Some((stream, previous)) => {
+                    handshake(stream, previous, &hop.host, hop.port, resolver).await
+                }
+                None => connect_direct(&hop.host, hop.port, resolver).await,
+            }
+        };
+        let result = tokio::time::timeout(HOP_TIMEOUT, step)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+
+        match result {
+            Ok(stream) => {
+                results.push(HopTestResult {
+                    proxy: hop.label(),
+                    latency_ms: Some(start.elapsed().as_millis() as u64),
+                    error: None,
+                });
+                reached = Some((stream, hop));
+            }
+            Err(e) => {
+                results.push(HopTestResult {
+                    proxy: hop.label(),
+                    latency_ms: None,
+                    error: Some(e.to_string()),
+                });
+                return results;
+            }
+        }
+    }
+
+    if let Some((stream, exit)) = reached {
+        let result =
+            tokio::time::timeout(HOP_TIMEOUT, handshake(stream, exit, host, port, resolver)).await;
+        let error = match result {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some("timed out".to_string()),
+        };
+        if let (Some(error), Some(last)) = (error, results.last_mut()) {
+            last.error = Some(format!("could not reach {}:{}: {}", host, port, error));
+        }
+    }
+    results
+}
+
+/// Whether reqwest has to go through a local forwarder to use this upstream,
+/// because it cannot apply the settings itself (TLS options, Shadowsocks, SSH, chains,
+/// PAC fallback lists evaluated off the async runtime)
+fn requires_forwarder(config: &ProxyConfig) -> bool {
+    !config.chain.is_empty()
+        || matches!(
+            config.proxy_type,
+            ProxyType::Https | ProxyType::Shadowsocks | ProxyType::Ssh | ProxyType::Pac
+        )
+}
+
+/// Check the Shadowsocks settings and start the SIP003 plugin if one is configured
+async fn prepare_shadowsocks(
+    config: &ProxyConfig,
+) -> Result<Option<Arc<ShadowsocksPlugin>>, String> {
+    if config.proxy_type != ProxyType::Shadowsocks {
+        return Ok(None);
+    }
+    server_config(config)?;
+    Ok(ShadowsocksPlugin::start(config).await?.map(Arc::new))
+}
+
+/// Start a forwarder for reqwest traffic that is not subject to the kill switch
+async fn start_client_forwarder(upstream: Upstream) -> io::Result<LocalForwarder> {
+    // The sender is dropped right away, so this forwarder never sees the kill switch engage
+    let (_, kill_switch) = watch::channel(KillSwitchState::default());
+    LocalForwarder::start(upstream, kill_switch).await
+}
+
+/// Build the reqwest proxy for a configuration, honoring its bypass list
+fn build_proxy(config: &ProxyConfig) -> Result<Proxy, String> {
+    let proxy_url = config.to_url();
+    let proxy = match config.proxy_type {
+        ProxyType::Socks5 | ProxyType::Socks5h | ProxyType::Socks4 | ProxyType::Socks4a => {
+            Proxy::all(&proxy_url)
+        }
+        ProxyType::Http | ProxyType::Https => Proxy::all(&proxy_url),
+        ProxyType::Shadowsocks | ProxyType::Ssh | ProxyType::Pac => {
+            return Err(format!(
+                "{} is only reachable through the local proxy",
+                config.proxy_type
+            ))
+        }
+    }
+    .map_err(|e| e.to_string())?;
+
+    if config.no_proxy.is_empty() {
+        Ok(proxy)
+    } else {
+        Ok(proxy.no_proxy(NoProxy::from_string(&config.no_proxy.join(","))))
+    }
+}
+
+/// Check the PAC source for changes until cancelled
+async fn pac_reload_loop(pac: Arc<PacResolver>, token: CancellationToken) {
+    let mut interval = tokio::time::interval(PAC_RELOAD_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = interval.tick() => {
+                if let Err(e) = pac.reload_if_changed().await {
+                    log::warn!("PAC reload from {} failed: {}", pac.source(), e);
+                }
+            }
+        }
+    }
 }
 
 impl Default for ProxyManager {
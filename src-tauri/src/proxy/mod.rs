@@ -1,10 +1,32 @@
 //! Proxy module
 //! Handles all proxy-related functionality
 
+pub mod balancer;
 pub mod config;
+pub mod failover;
+pub mod forwarder;
 pub mod health;
 pub mod manager;
+pub mod pac;
+pub mod probe;
+pub mod profiles;
+pub mod shadowsocks;
+pub mod ssh;
+pub mod system;
+pub mod tls;
+pub mod upstream;
 
+pub use balancer::*;
 pub use config::*;
+pub use failover::*;
+pub use forwarder::*;
 pub use health::*;
 pub use manager::*;
+pub use pac::*;
+pub use probe::*;
+pub use profiles::*;
+pub use shadowsocks::*;
+pub use ssh::*;
+pub use system::*;
+pub use tls::*;
+pub use upstream::*;
@@ -0,0 +1,517 @@
+//! Proxy auto-config (PAC) support
+//! Loads a PAC script from a file or URL and evaluates `FindProxyForURL` in an embedded JS engine
+
+use rquickjs::{Context, Ctx, Function, Runtime};
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use url::Url;
+
+use crate::network::resolver::DnsResolver;
+use crate::proxy::config::{ProxyConfig, ProxyType};
+
+/// How long a `FindProxyForURL` answer is reused for the same origin
+const RESULT_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Upper bound on a single script evaluation
+const EVALUATION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Timeout for downloading a PAC script
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Standard PAC helper functions, implemented on top of the native `dnsResolve`
+const PAC_UTILS: &str = r#"
+function isPlainHostName(host) {
+    return host.indexOf('.') < 0;
+}
+function dnsDomainIs(host, domain) {
+    return host.length >= domain.length &&
+        host.substring(host.length - domain.length) === domain;
+}
+function localHostOrDomainIs(host, hostdom) {
+    return host === hostdom || hostdom.lastIndexOf(host + '.', 0) === 0;
+}
+function isValidIpAddress(ip) {
+    return /^\d{1,3}(\.\d{1,3}){3}$/.test(ip);
+}
+function isResolvable(host) {
+    return !!dnsResolve(host);
+}
+function convert_addr(ip) {
+    var parts = ip.split('.');
+    return ((parts[0] & 0xff) << 24) | ((parts[1] & 0xff) << 16) |
+        ((parts[2] & 0xff) << 8) | (parts[3] & 0xff);
+}
+function isInNet(host, pattern, mask) {
+    var ip = isValidIpAddress(host) ? host : dnsResolve(host);
+    if (!ip || !isValidIpAddress(ip)) {
+        return false;
+    }
+    return (convert_addr(ip) & convert_addr(mask)) === (convert_addr(pattern) & convert_addr(mask));
+}
+function dnsDomainLevels(host) {
+    return host.split('.').length - 1;
+}
+function shExpMatch(str, shexp) {
+    var re = shexp.replace(/[.+^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*').replace(/\?/g, '.');
+    return new RegExp('^' + re + '$').test(str);
+}
+var __PAC_DAYS = ['SUN', 'MON', 'TUE', 'WED', 'THU', 'FRI', 'SAT'];
+function weekdayRange(wd1, wd2, gmt) {
+    if (wd2 === 'GMT') { gmt = wd2; wd2 = undefined; }
+    var now = new Date();
+    var day = gmt === 'GMT' ? now.getUTCDay() : now.getDay();
+    var d1 = __PAC_DAYS.indexOf(wd1);
+    var d2 = wd2 === undefined ? d1 : __PAC_DAYS.indexOf(wd2);
+    if (d1 < 0 || d2 < 0) { return false; }
+    return d1 <= d2 ? (day >= d1 && day <= d2) : (day >= d1 || day <= d2);
+}
+function timeRange() {
+    var args = Array.prototype.slice.call(arguments);
+    var gmt = args[args.length - 1] === 'GMT';
+    if (gmt) { args.pop(); }
+    var now = new Date();
+    var h = gmt ? now.getUTCHours() : now.getHours();
+    var m = gmt ? now.getUTCMinutes() : now.getMinutes();
+    var s = gmt ? now.getUTCSeconds() : now.getSeconds();
+    var cur = h * 3600 + m * 60 + s;
+    var start, end;
+    if (args.length === 1) { return h === args[0]; }
+    if (args.length === 2) { start = args[0] * 3600; end = args[1] * 3600 + 3599; }
+    else if (args.length === 4) { start = args[0] * 3600 + args[1] * 60; end = args[2] * 3600 + args[3] * 60 + 59; }
+    else if (args.length === 6) { start = args[0] * 3600 + args[1] * 60 + args[2]; end = args[3] * 3600 + args[4] * 60 + args[5]; }
+    else { return false; }
+    return start <= end ? (cur >= start && cur <= end) : (cur >= start || cur <= end);
+}
+function dateRange() {
+    // Date ranges are rarely used for routing; treat them as always matching
+    return true;
+}
+"#;
+
+/// A single entry of a `FindProxyForURL` result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacRoute {
+    Direct,
+    Proxy {
+        proxy_type: ProxyType,
+        host: String,
+        port: u16,
+    },
+}
+
+impl PacRoute {
+    /// Concrete upstream for this route, inheriting credentials from `base`.
+    /// Returns `None` for `DIRECT`.
+    pub fn to_config(&self, base: &ProxyConfig) -> Option<ProxyConfig> {
+        match self {
+            PacRoute::Direct => None,
+            PacRoute::Proxy {
+                proxy_type,
+                host,
+                port,
+            } => Some(ProxyConfig {
+                proxy_type: *proxy_type,
+                host: host.clone(),
+                port: *port,
+                pac_url: None,
+                ..base.clone()
+            }),
+        }
+    }
+}
+
+/// Parse a PAC result such as `PROXY a:8080; SOCKS5 b:1080; DIRECT`
+pub fn parse_pac_result(result: &str) -> Vec<PacRoute> {
+    result
+        .split(';')
+        .filter_map(|entry| {
+            let mut parts = entry.split_whitespace();
+            let kind = parts.next()?.to_ascii_uppercase();
+            if kind == "DIRECT" {
+                return Some(PacRoute::Direct);
+            }
+
+            let proxy_type = match kind.as_str() {
+                "PROXY" | "HTTP" => ProxyType::Http,
+                "HTTPS" => ProxyType::Https,
+                "SOCKS" | "SOCKS5" => ProxyType::Socks5,
+                "SOCKS4" => ProxyType::Socks4,
+                _ => {
+                    log::debug!("Ignoring unsupported PAC entry: {}", entry.trim());
+                    return None;
+                }
+            };
+            let (host, port) = parts.next()?.rsplit_once(':')?;
+            Some(PacRoute::Proxy {
+                proxy_type,
+                host: host.trim_matches(|c| c == '[' || c == ']').to_string(),
+                port: port.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Loaded PAC script with a cache of recent answers
+pub struct PacResolver {
+    source: String,
+    engine: PacEngine,
+    fingerprint: Mutex<u64>,
+    cache: Mutex<HashMap<String, (Vec<PacRoute>, Instant)>>,
+}
+
+impl PacResolver {
+    /// Load and compile the PAC script at `source` (a URL, `file://` URL or path)
+    pub async fn load(source: &str) -> Result<Self, String> {
+        let script = fetch_script(source).await?;
+        let engine = PacEngine::spawn()?;
+        engine.compile(script.clone()).await?;
+        log::info!("Loaded PAC script from {}", source);
+
+        Ok(Self {
+            source: source.to_string(),
+            engine,
+            fingerprint: Mutex::new(fingerprint(&script)),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Location the script is loaded from
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Re-read the source and recompile if the script changed
+    pub async fn reload_if_changed(&self) -> Result<bool, String> {
+        let script = fetch_script(&self.source).await?;
+        let new_fingerprint = fingerprint(&script);
+        if *self.fingerprint.lock().map_err(|e| e.to_string())? == new_fingerprint {
+            return Ok(false);
+        }
+
+        self.engine.compile(script).await?;
+        *self.fingerprint.lock().map_err(|e| e.to_string())? = new_fingerprint;
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.clear();
+        }
+        log::info!("Reloaded changed PAC script from {}", self.source);
+        Ok(true)
+    }
+
+    /// Evaluate `FindProxyForURL` for `url`. Blocks while the script runs.
+    /// `dnsResolve` in the script looks names up through `resolver` (the system resolver
+    /// with `None`).
+    ///
+    /// Like browsers, only the origin is passed to the script so answers can be cached.
+    pub fn find_proxy(
+        &self,
+        url: &Url,
+        resolver: Option<&DnsResolver>,
+    ) -> Result<Vec<PacRoute>, String> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| format!("URL has no host: {}", url))?
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let origin = match url.port() {
+            Some(port) => format!("{}://{}:{}/", url.scheme(), host, port),
+            None => format!("{}://{}/", url.scheme(), host),
+        };
+
+        if let Ok(cache) = self.cache.lock() {
+            if let Some((routes, at)) = cache.get(&origin) {
+                if at.elapsed() < RESULT_CACHE_TTL {
+                    return Ok(routes.clone());
+                }
+            }
+        }
+
+        let result = self
+            .engine
+            .find_proxy(origin.clone(), host, resolver.cloned())?;
+        let routes = parse_pac_result(&result);
+        if routes.is_empty() {
+            return Err(format!("PAC script returned no usable proxy: {}", result));
+        }
+
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.retain(|_, (_, at)| at.elapsed() < RESULT_CACHE_TTL);
+            cache.insert(origin, (routes.clone(), Instant::now()));
+        }
+        Ok(routes)
+    }
+
+    /// Whether the first choice for `url` is a direct connection
+    pub fn is_direct(&self, url: &Url, resolver: Option<&DnsResolver>) -> bool {
+        matches!(
+            self.find_proxy(url, resolver).as_deref(),
+            Ok([PacRoute::Direct, ..])
+        )
+    }
+}
+
+fn fingerprint(script: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    script.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Read a PAC script from a URL, `file://` URL or filesystem path
+async fn fetch_script(source: &str) -> Result<String, String> {
+    match Url::parse(source) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+            // PAC files are usually served on the local network, so fetch them directly
+            let client = reqwest::Client::builder()
+                .no_proxy()
+                .timeout(FETCH_TIMEOUT)
+                .build()
+                .map_err(|e| e.to_string())?;
+            let response = client
+                .get(url)
+                .send()
+                .await
+                .map_err(|e| format!("Failed to download PAC script: {}", e))?;
+            if !response.status().is_success() {
+                return Err(format!(
+                    "Failed to download PAC script: HTTP {}",
+                    response.status()
+                ));
+            }
+            response
+                .text()
+                .await
+                .map_err(|e| format!("Failed to read PAC script: {}", e))
+        }
+        Ok(url) if url.scheme() == "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|_| format!("Invalid PAC file URL: {}", source))?;
+            tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| format!("Failed to read PAC file: {}", e))
+        }
+        _ => tokio::fs::read_to_string(source)
+            .await
+            .map_err(|e| format!("Failed to read PAC file: {}", e)),
+    }
+}
+
+enum EngineRequest {
+    Compile {
+        script: String,
+        reply: oneshot::Sender<Result<(), String>>,
+    },
+    FindProxy {
+        url: String,
+        host: String,
+        resolver: Option<DnsResolver>,
+        reply: mpsc::Sender<Result<String, String>>,
+    },
+}
+
+/// Handle to the dedicated JS engine thread (QuickJS contexts are not `Send`)
+struct PacEngine {
+    requests: mpsc::Sender<EngineRequest>,
+}
+
+impl PacEngine {
+    fn spawn() -> Result<Self, String> {
+        let (requests, receiver) = mpsc::channel();
+        // `dnsResolve` blocks the engine thread on lookups running on the app's runtime
+        let runtime = Handle::try_current().ok();
+        std::thread::Builder::new()
+            .name("pac-engine".to_string())
+            .spawn(move || run_engine(receiver, runtime))
+            .map_err(|e| format!("Failed to start PAC engine: {}", e))?;
+        Ok(Self { requests })
+    }
+
+    /// Compile `script`, waiting on the engine thread without blocking the runtime
+    /// while its top level (and any `dnsResolve` in it) runs
+    async fn compile(&self, script: String) -> Result<(), String> {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send(EngineRequest::Compile { script, reply })
+            .map_err(|_| "PAC engine stopped".to_string())?;
+        response
+            .await
+            .map_err(|_| "PAC engine stopped".to_string())?
+    }
+
+    fn find_proxy(
+        &self,
+        url: String,
+        host: String,
+        resolver: Option<DnsResolver>,
+    ) -> Result<String, String> {
+        let (reply, response) = mpsc::channel();
+        self.requests
+            .send(EngineRequest::FindProxy {
+                url,
+                host,
+                resolver,
+                reply,
+            })
+            .map_err(|_| "PAC engine stopped".to_string())?;
+        response
+            .recv()
+            .map_err(|_| "PAC engine stopped".to_string())?
+    }
+}
+
+/// Engine thread: owns the runtime and the compiled script's context.
+/// Exits once the owning `PacResolver` is dropped.
+fn run_engine(requests: mpsc::Receiver<EngineRequest>, handle: Option<Handle>) {
+    let runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            log::error!("Failed to create PAC runtime: {}", e);
+            return;
+        }
+    };
+
+    // Abort scripts that run past their deadline (milliseconds since `epoch`, 0 = none)
+    let epoch = Instant::now();
+    let deadline = Arc::new(AtomicU64::new(0));
+    let handler_deadline = deadline.clone();
+    runtime.set_interrupt_handler(Some(Box::new(move || {
+        let deadline = handler_deadline.load(Ordering::Relaxed);
+        deadline != 0 && epoch.elapsed().as_millis() as u64 > deadline
+    })));
+    let arm = || {
+        let at = epoch.elapsed() + EVALUATION_TIMEOUT;
+        deadline.store(at.as_millis() as u64, Ordering::Relaxed);
+    };
+    let disarm = || deadline.store(0, Ordering::Relaxed);
+
+    let mut context: Option<Context> = None;
+    // Resolver of the evaluation in progress, read by the native `dnsResolve`
+    let resolver: Rc<RefCell<Option<DnsResolver>>> = Rc::default();
+
+    for request in requests {
+        match request {
+            EngineRequest::Compile { script, reply } => {
+                arm();
+                let result = compile(&runtime, &script, resolver.clone(), handle.clone());
+                disarm();
+                let _ = reply.send(result.map(|compiled| {
+                    context = Some(compiled);
+                }));
+            }
+            EngineRequest::FindProxy {
+                url,
+                host,
+                resolver: current,
+                reply,
+            } => {
+                *resolver.borrow_mut() = current;
+                let result = match context {
+                    Some(ref context) => {
+                        arm();
+                        let result = context.with(|ctx| {
+                            let find: Function = ctx
+                                .globals()
+                                .get("FindProxyForURL")
+                                .map_err(|e| js_error(&ctx, e))?;
+                            find.call::<_, String>((url, host))
+                                .map_err(|e| js_error(&ctx, e))
+                        });
+                        disarm();
+                        result
+                    }
+                    None => Err("No PAC script loaded".to_string()),
+                };
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+/// Compile a script into a fresh context with the PAC helpers installed
+fn compile(
+    runtime: &Runtime,
+    script: &str,
+    resolver: Rc<RefCell<Option<DnsResolver>>>,
+    handle: Option<Handle>,
+) -> Result<Context, String> {
+    let context = Context::full(runtime).map_err(|e| e.to_string())?;
+
+    context.with(|ctx| {
+        let globals = ctx.globals();
+        let dns_resolve = Function::new(ctx.clone(), move |host: String| {
+            dns_resolve(&host, resolver.borrow().as_ref(), handle.as_ref())
+        })
+        .map_err(|e| js_error(&ctx, e))?;
+        globals
+            .set("dnsResolve", dns_resolve)
+            .map_err(|e| js_error(&ctx, e))?;
+        let my_ip = Function::new(ctx.clone(), my_ip_address).map_err(|e| js_error(&ctx, e))?;
+        globals
+            .set("myIpAddress", my_ip)
+            .map_err(|e| js_error(&ctx, e))?;
+
+        ctx.eval::<(), _>(PAC_UTILS)
+            .map_err(|e| js_error(&ctx, e))?;
+        ctx.eval::<(), _>(script)
+            .map_err(|e| format!("PAC script error: {}", js_error(&ctx, e)))?;
+
+        let find: Option<Function> = globals.get("FindProxyForURL").ok();
+        if find.is_none() {
+            return Err("PAC script does not define FindProxyForURL".to_string());
+        }
+        Ok(())
+    })?;
+
+    Ok(context)
+}
+
+fn js_error(ctx: &Ctx<'_>, error: rquickjs::Error) -> String {
+    if let rquickjs::Error::Exception = error {
+        let exception = ctx.catch();
+        if let Some(message) = exception.as_exception().and_then(|e| e.message()) {
+            return message;
+        }
+        return format!("{:?}", exception);
+    }
+    error.to_string()
+}
+
+/// Native `dnsResolve`: first IPv4 address of `host`, like browsers, looked up through
+/// `resolver` so the DNS settings and host overrides apply to PAC decisions too
+fn dns_resolve(
+    host: &str,
+    resolver: Option<&DnsResolver>,
+    handle: Option<&Handle>,
+) -> Option<String> {
+    let addrs: Vec<IpAddr> = match (resolver, handle) {
+        (Some(resolver), Some(handle)) => handle.block_on(resolver.lookup_ip(host)).ok()?,
+        _ => (host, 0)
+            .to_socket_addrs()
+            .ok()?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
+    addrs
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addrs.first())
+        .map(IpAddr::to_string)
+}
+
+/// Native `myIpAddress`: address of the interface used for outbound traffic
+fn my_ip_address() -> String {
+    // Connecting a UDP socket sends nothing but selects the outbound interface
+    UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("198.51.100.1:80")?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|_| "127.0.0.1".to_string())
+}
@@ -0,0 +1,395 @@
+//! Health probes
+//! Checks one layer of the path to Figma each: the proxy server, the tunnel through it,
+//! TLS to Figma, the HTTP API and the multiplayer WebSocket
+
+use base64::Engine;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+use crate::network::resolver::connect_tcp;
+use crate::proxy::config::ProxyTlsConfig;
+use crate::proxy::manager::ProxyManager;
+use crate::proxy::tls::client_config;
+use crate::proxy::upstream::{format_authority, BoxedStream};
+
+/// Largest WebSocket upgrade response head we are willing to read
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+/// Layer of the connection a probe checks, from the bottom up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    /// TCP connect to the proxy server (the first hop of a chain)
+    Tcp,
+    /// Proxy handshake opening a tunnel to the target
+    Handshake,
+    /// TLS handshake with the target through the tunnel
+    Tls,
+    /// HTTP GET through the proxy client
+    Http,
+    /// WebSocket upgrade through the tunnel
+    WebSocket,
+}
+
+impl fmt::Display for ProbeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProbeKind::Tcp => "TCP connect",
+            ProbeKind::Handshake => "Proxy handshake",
+            ProbeKind::Tls => "TLS handshake",
+            ProbeKind::Http => "HTTP request",
+            ProbeKind::WebSocket => "WebSocket upgrade",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One probe of a health check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthProbe {
+    pub kind: ProbeKind,
+    /// `host[:port]` for handshake and TLS probes, an `http(s)://` URL for HTTP and a
+    /// `ws(s)://` URL for WebSocket probes; unused by TCP probes
+    #[serde(default)]
+    pub target: String,
+    #[serde(default = "default_probe_timeout")]
+    pub timeout_secs: u64,
+    /// Share of the health score this probe carries
+    #[serde(default = "default_probe_weight")]
+    pub weight: u32,
+}
+
+fn default_probe_timeout() -> u64 {
+    10
+}
+
+fn default_probe_weight() -> u32 {
+    1
+}
+
+impl HealthProbe {
+    pub fn new(kind: ProbeKind, target: &str, timeout_secs: u64, weight: u32) -> Self {
+        Self {
+            kind,
+            target: target.to_string(),
+            timeout_secs,
+            weight,
+        }
+    }
+
+    /// The probes run when none are configured: every layer, towards Figma
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::new(ProbeKind::Tcp, "", 5, 1),
+            Self::new(ProbeKind::Handshake, "www.figma.com:443", 10, 2),
+            Self::new(ProbeKind::Tls, "www.figma.com", 10, 2),
+            Self::new(ProbeKind::Http, "https://api.figma.com/", 10, 3),
+            Self::new(
+                ProbeKind::WebSocket,
+                "wss://www.figma.com/api/multiplayer",
+                10,
+                2,
+            ),
+        ]
+    }
+
+    /// Validate the target for the probe kind, the timeout and the weight
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout_secs == 0 {
+            return Err(format!(
+                "{} probe timeout must be at least 1 second",
+                self.kind
+            ));
+        }
+        if !(1..=100).contains(&self.weight) {
+            return Err(format!(
+                "{} probe weight must be between 1 and 100",
+                self.kind
+            ));
+        }
+        match self.kind {
+            ProbeKind::Tcp => Ok(()),
+            ProbeKind::Handshake | ProbeKind::Tls => self.host_port().map(|_| ()),
+            ProbeKind::Http => self.url(&["http", "https"]).map(|_| ()),
+            ProbeKind::WebSocket => self.url(&["ws", "wss"]).map(|_| ()),
+        }
+    }
+
+    /// Host and port of a handshake or TLS probe, 443 unless given
+    fn host_port(&self) -> Result<(String, u16), String> {
+        let url = Url::parse(&format!("tcp://{}", self.target.trim()))
+            .map_err(|_| format!("Invalid {} probe target: {}", self.kind, self.target))?;
+        match url.host_str() {
+            Some(host) if url.path().is_empty() || url.path() == "/" => Ok((
+                host.trim_matches(|c| c == '[' || c == ']').to_string(),
+                url.port().unwrap_or(443),
+            )),
+            _ => Err(format!(
+                "{} probe target must be host[:port]: {}",
+                self.kind, self.target
+            )),
+        }
+    }
+
+    fn url(&self, schemes: &[&str]) -> Result<Url, String> {
+        let url = Url::parse(self.target.trim())
+            .map_err(|e| format!("Invalid {} probe URL: {}", self.kind, e))?;
+        if !schemes.contains(&url.scheme()) || url.host_str().is_none() {
+            return Err(format!(
+                "{} probe URL must start with {}://",
+                self.kind,
+                schemes.join(":// or ")
+            ));
+        }
+        Ok(url)
+    }
+}
+
+/// Outcome of one probe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProbeResult {
+    pub kind: ProbeKind,
+    /// What was probed, `host:port` or a URL
+    pub target: String,
+    pub weight: u32,
+    pub success: bool,
+    /// Not applicable to the current proxy, and left out of the score
+    pub skipped: bool,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Combined outcome of all probes of one health check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthCheckReport {
+    /// Whether the score reached the healthy threshold
+    pub healthy: bool,
+    /// Weight of the passed probes over the weight of all that ran, from 0 to 1
+    pub score: f64,
+    /// Lowest layer with a failed probe
+    pub failed_layer: Option<ProbeKind>,
+    pub probes: Vec<ProbeResult>,
+    pub checked_at: String,
+}
+
+impl HealthCheckReport {
+    /// Score the probe results; healthy from `healthy_score` up
+    pub fn new(probes: Vec<ProbeResult>, healthy_score: f64) -> Self {
+        let ran = probes.iter().filter(|probe| !probe.skipped);
+        let total: u32 = ran.clone().map(|probe| probe.weight).sum();
+        let passed: u32 = ran
+            .clone()
+            .filter(|probe| probe.success)
+            .map(|probe| probe.weight)
+            .sum();
+        let score = if total == 0 {
+            1.0
+        } else {
+            passed as f64 / total as f64
+        };
+        let failed_layer = probes
+            .iter()
+            .filter(|probe| !probe.skipped && !probe.success)
+            .map(|probe| probe.kind)
+            .min();
+
+        Self {
+            healthy: score >= healthy_score,
+            score,
+            failed_layer,
+            probes,
+            checked_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// First failure of the lowest failed layer, as `Layer failed (target): error`
+    pub fn error(&self) -> Option<String> {
+        let layer = self.failed_layer?;
+        let probe = self
+            .probes
+            .iter()
+            .find(|probe| probe.kind == layer && !probe.skipped && !probe.success)?;
+        Some(format!(
+            "{} failed ({}): {}",
+            layer,
+            probe.target,
+            probe.error.as_deref().unwrap_or("unknown error")
+        ))
+    }
+
+    /// Latency of the highest layer that passed, the closest to what Figma sees
+    pub fn latency_ms(&self) -> Option<u64> {
+        self.probes
+            .iter()
+            .filter(|probe| probe.success && !probe.skipped)
+            .max_by_key(|probe| probe.kind)
+            .and_then(|probe| probe.latency_ms)
+    }
+}
+
+/// Run `probe` against the current proxy of `proxy_manager`
+pub async fn run_probe(proxy_manager: &ProxyManager, probe: &HealthProbe) -> ProbeResult {
+    let mut result = ProbeResult {
+        kind: probe.kind,
+        target: probe.target.trim().to_string(),
+        weight: probe.weight,
+        success: false,
+        skipped: false,
+        latency_ms: None,
+        error: None,
+    };
+
+    let endpoint = match probe.kind {
+        ProbeKind::Tcp => match proxy_manager.proxy_endpoint().await {
+            Some(endpoint) => Some(endpoint),
+            None => {
+                result.skipped = true;
+                return result;
+            }
+        },
+        _ => None,
+    };
+    if let Some((ref host, port)) = endpoint {
+        result.target = format_authority(host, port);
+    }
+
+    let started = Instant::now();
+    let check = async {
+        match probe.kind {
+            ProbeKind::Tcp => {
+                let (host, port) = endpoint.unwrap_or_default();
+                connect_tcp(proxy_manager.resolver().as_ref(), &host, port)
+                    .await
+                    .map(|_| ())
+            }
+            ProbeKind::Handshake => {
+                let (host, port) = probe.host_port().map_err(invalid_input)?;
+                proxy_manager.open_tunnel(&host, port).await.map(|_| ())
+            }
+            ProbeKind::Tls => {
+                let (host, port) = probe.host_port().map_err(invalid_input)?;
+                let tunnel = proxy_manager.open_tunnel(&host, port).await?;
+                tls_handshake(tunnel, &host).await.map(|_| ())
+            }
+            ProbeKind::Http => {
+                let url = probe.url(&["http", "https"]).map_err(invalid_input)?;
+                let response = proxy_manager
+                    .request(url.as_str())
+                    .await
+                    .map_err(io::Error::other)?;
+                expect_reachable(response.status().as_u16())
+            }
+            ProbeKind::WebSocket => {
+                let url = probe.url(&["ws", "wss"]).map_err(invalid_input)?;
+                websocket_upgrade(proxy_manager, &url).await
+            }
+        }
+    };
+
+    match tokio::time::timeout(Duration::from_secs(probe.timeout_secs), check).await {
+        Ok(Ok(())) => {
+            result.success = true;
+            result.latency_ms = Some(started.elapsed().as_millis() as u64);
+        }
+        Ok(Err(e)) => result.error = Some(e.to_string()),
+        Err(_) => result.error = Some(format!("timed out after {}s", probe.timeout_secs)),
+    }
+    result
+}
+
+/// TLS handshake with `host` over `stream`, verifying the certificate against the web roots
+async fn tls_handshake(stream: BoxedStream, host: &str) -> io::Result<BoxedStream> {
+    let config = client_config(&ProxyTlsConfig::default()).map_err(invalid_input)?;
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|e| invalid_input(format!("Invalid TLS server name: {}", e)))?;
+    let tls = TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await?;
+    Ok(Box::new(tls))
+}
+
+/// Ask for a WebSocket upgrade at `url` through the tunnel. Without a file and a session
+/// the multiplayer server refuses it, so any answer short of a server error counts:
+/// it proves the upgrade request got through to Figma and back.
+async fn websocket_upgrade(proxy_manager: &ProxyManager, url: &Url) -> io::Result<()> {
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_matches(|c| c == '[' || c == ']');
+    let port = url.port_or_known_default().unwrap_or(443);
+    let tunnel = proxy_manager.open_tunnel(host, port).await?;
+    let mut stream = if url.scheme() == "wss" {
+        tls_handshake(tunnel, host).await?
+    } else {
+        tunnel
+    };
+
+    let mut key = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| io::Error::other("No randomness for the WebSocket key"))?;
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nOrigin: https://www.figma.com\r\n\r\n",
+        path,
+        host,
+        base64::engine::general_purpose::STANDARD.encode(key)
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WebSocket upgrade response too large",
+            ));
+        }
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed during the WebSocket upgrade",
+            ));
+        }
+        head.push(byte[0]);
+    }
+
+    let status = String::from_utf8_lossy(&head)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid WebSocket upgrade response",
+            )
+        })?;
+    expect_reachable(status)
+}
+
+/// Any status but a server error means the request made it to the server
+fn expect_reachable(status: u16) -> io::Result<()> {
+    if status >= 500 {
+        return Err(io::Error::other(format!("HTTP error: {}", status)));
+    }
+    Ok(())
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
@@ -0,0 +1,124 @@
+//! Named proxy profiles
+//! Ordered collection of saved proxy presets persisted under `keys::PROXY_PRESETS`
+
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::config::{ProxyPreset, ProxyTestResult};
+
+/// Ordered list of saved proxy profiles
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProxyProfiles {
+    profiles: Vec<ProxyPreset>,
+}
+
+impl ProxyProfiles {
+    /// Build from stored profiles, assigning ids to any legacy entries without one
+    pub fn from_stored(mut profiles: Vec<ProxyPreset>) -> Self {
+        for profile in profiles.iter_mut().filter(|p| p.id.is_empty()) {
+            profile.id = new_profile_id();
+        }
+        Self { profiles }
+    }
+
+    /// All profiles in display order
+    pub fn list(&self) -> Vec<ProxyPreset> {
+        self.profiles.clone()
+    }
+
+    /// Look up a profile by id
+    pub fn get(&self, id: &str) -> Option<&ProxyPreset> {
+        self.profiles.iter().find(|p| p.id == id)
+    }
+
+    /// Add a new profile at the end of the list, returning it with its assigned id
+    pub fn create(&mut self, mut profile: ProxyPreset) -> Result<ProxyPreset, String> {
+        profile.validate()?;
+        profile.id = new_profile_id();
+        profile.requires_auth = profile.to_config().uses_password();
+        profile.last_test = None;
+        profile.last_tested_at = None;
+        self.profiles.push(profile.clone());
+        Ok(profile)
+    }
+
+    /// Replace an existing profile, keeping its position and test history
+    pub fn update(&mut self, mut profile: ProxyPreset) -> Result<ProxyPreset, String> {
+        profile.validate()?;
+        let existing = self.find_mut(&profile.id)?;
+        profile.requires_auth = profile.to_config().uses_password();
+        profile.last_test = existing.last_test.take();
+        profile.last_tested_at = existing.last_tested_at.take();
+        *existing = profile.clone();
+        Ok(profile)
+    }
+
+    /// Remove a profile, returning it
+    pub fn remove(&mut self, id: &str) -> Result<ProxyPreset, String> {
+        let index = self.index_of(id)?;
+        Ok(self.profiles.remove(index))
+    }
+
+    /// Insert a copy of a profile right after the original
+    pub fn duplicate(&mut self, id: &str) -> Result<ProxyPreset, String> {
+        let index = self.index_of(id)?;
+        let mut copy = self.profiles[index].clone();
+        copy.id = new_profile_id();
+        copy.name = format!("{} (copy)", copy.name);
+        copy.last_test = None;
+        copy.last_tested_at = None;
+        self.profiles.insert(index + 1, copy.clone());
+        Ok(copy)
+    }
+
+    /// Reorder profiles to match `ids`, which must list every profile exactly once
+    pub fn reorder(&mut self, ids: &[String]) -> Result<(), String> {
+        if ids.len() != self.profiles.len() {
+            return Err(format!(
+                "Expected {} profile ids, got {}",
+                self.profiles.len(),
+                ids.len()
+            ));
+        }
+
+        let mut reordered = Vec::with_capacity(ids.len());
+        for id in ids {
+            if reordered.iter().any(|p: &ProxyPreset| &p.id == id) {
+                return Err(format!("Duplicate profile id: {}", id));
+            }
+            let profile = self
+                .get(id)
+                .ok_or_else(|| format!("Profile not found: {}", id))?;
+            reordered.push(profile.clone());
+        }
+
+        self.profiles = reordered;
+        Ok(())
+    }
+
+    /// Record the outcome of a connection test
+    pub fn record_test(&mut self, id: &str, result: ProxyTestResult) -> Result<(), String> {
+        let profile = self.find_mut(id)?;
+        profile.last_test = Some(result);
+        profile.last_tested_at = Some(chrono::Utc::now().to_rfc3339());
+        Ok(())
+    }
+
+    fn index_of(&self, id: &str) -> Result<usize, String> {
+        self.profiles
+            .iter()
+            .position(|p| p.id == id)
+            .ok_or_else(|| format!("Profile not found: {}", id))
+    }
+
+    fn find_mut(&mut self, id: &str) -> Result<&mut ProxyPreset, String> {
+        self.profiles
+            .iter_mut()
+            .find(|p| p.id == id)
+            .ok_or_else(|| format!("Profile not found: {}", id))
+    }
+}
+
+fn new_profile_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
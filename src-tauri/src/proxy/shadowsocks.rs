@@ -0,0 +1,175 @@
+//! Shadowsocks client
+//! Encrypted tunnels to a Shadowsocks server, SIP003 plugins and `ss://` URI import
+
+use shadowsocks::config::{Mode, ServerAddr, ServerConfig, ServerType};
+use shadowsocks::context::{Context, SharedContext};
+use shadowsocks::crypto::CipherKind;
+use shadowsocks::plugin::{Plugin, PluginConfig, PluginMode};
+use shadowsocks::relay::Address;
+use shadowsocks::ProxyClientStream;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::proxy::config::{ProxyConfig, ProxyPreset, ProxyType, ShadowsocksConfig};
+use crate::proxy::upstream::BoxedStream;
+
+/// How long to wait for a SIP003 plugin to start listening
+const PLUGIN_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shared client context (replay protection state for AEAD-2022)
+fn context() -> SharedContext {
+    static CONTEXT: OnceLock<SharedContext> = OnceLock::new();
+    CONTEXT
+        .get_or_init(|| Context::new_shared(ServerType::Local))
+        .clone()
+}
+
+/// Build the Shadowsocks server configuration, checking the cipher and password
+pub fn server_config(config: &ProxyConfig) -> Result<ServerConfig, String> {
+    let method = config
+        .shadowsocks
+        .cipher
+        .parse::<CipherKind>()
+        .map_err(|_| {
+            format!(
+                "Unsupported Shadowsocks cipher: {}",
+                config.shadowsocks.cipher
+            )
+        })?;
+    let password = config
+        .password
+        .as_deref()
+        .filter(|p| !p.is_empty())
+        .ok_or("Shadowsocks password is required")?;
+
+    ServerConfig::new(server_addr(config), password, method)
+        .map_err(|e| format!("Invalid Shadowsocks password: {}", e))
+}
+
+fn server_addr(config: &ProxyConfig) -> ServerAddr {
+    match config.host.parse::<IpAddr>() {
+        Ok(ip) => ServerAddr::SocketAddr(SocketAddr::new(ip, config.port)),
+        Err(_) => ServerAddr::DomainName(config.host.clone(), config.port),
+    }
+}
+
+/// Open an encrypted tunnel to `host:port` over a stream connected to the Shadowsocks server.
+/// The target is sent with the first payload, so a wrong key surfaces as a closed tunnel.
+pub async fn shadowsocks_connect(
+    stream: BoxedStream,
+    config: &ProxyConfig,
+    host: &str,
+    port: u16,
+) -> io::Result<BoxedStream> {
+    let server =
+        server_config(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // Hostnames are always resolved by the server
+    let target = match host.parse::<IpAddr>() {
+        Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, port)),
+        Err(_) => Address::DomainNameAddress(host.to_string(), port),
+    };
+
+    Ok(Box::new(ProxyClientStream::from_stream(
+        context(),
+        stream,
+        &server,
+        target,
+    )))
+}
+
+/// Running SIP003 plugin; the process is killed when this is dropped
+pub struct ShadowsocksPlugin {
+    plugin: Plugin,
+}
+
+impl ShadowsocksPlugin {
+    /// Start the plugin configured for `config`, if any, and wait until it accepts connections
+    pub async fn start(config: &ProxyConfig) -> Result<Option<Self>, String> {
+        let Some(name) = config
+            .shadowsocks
+            .plugin
+            .as_deref()
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+        else {
+            return Ok(None);
+        };
+
+        let plugin_config = PluginConfig {
+            plugin: name.to_string(),
+            plugin_opts: config.shadowsocks.plugin_opts.clone(),
+            plugin_args: Vec::new(),
+            plugin_mode: Mode::TcpOnly,
+        };
+        let plugin = Plugin::start(&plugin_config, &server_addr(config), PluginMode::Client)
+            .map_err(|e| format!("Failed to start Shadowsocks plugin {}: {}", name, e))?;
+        if !plugin.wait_started(PLUGIN_START_TIMEOUT).await {
+            return Err(format!("Shadowsocks plugin {} did not start", name));
+        }
+
+        log::info!(
+            "Shadowsocks plugin {} listening on {}",
+            name,
+            plugin.local_addr()
+        );
+        Ok(Some(Self { plugin }))
+    }
+
+    /// Local address the plugin listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.plugin.local_addr()
+    }
+
+    /// `config` rewritten to dial the plugin instead of the server
+    pub fn route(&self, config: &ProxyConfig) -> ProxyConfig {
+        let addr = self.local_addr();
+        ProxyConfig {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            ..config.clone()
+        }
+    }
+}
+
+/// Parse an `ss://` URI (SIP002 or legacy base64 form) into a profile and its password
+pub fn parse_ss_uri(uri: &str) -> Result<(ProxyPreset, String), String> {
+    let server =
+        ServerConfig::from_url(uri.trim()).map_err(|e| format!("Invalid ss:// URI: {}", e))?;
+
+    let (host, port) = match server.addr() {
+        ServerAddr::SocketAddr(addr) => (addr.ip().to_string(), addr.port()),
+        ServerAddr::DomainName(host, port) => (host.clone(), *port),
+    };
+    let name = server
+        .remarks()
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}:{}", host, port));
+
+    let profile = ProxyPreset {
+        id: String::new(),
+        name,
+        proxy_type: ProxyType::Shadowsocks,
+        host,
+        port,
+        username: None,
+        requires_auth: true,
+        location: None,
+        notes: None,
+        last_test: None,
+        last_tested_at: None,
+        pac_url: None,
+        tls: Default::default(),
+        shadowsocks: ShadowsocksConfig {
+            cipher: server.method().to_string(),
+            plugin: server.plugin().map(|p| p.plugin.clone()),
+            plugin_opts: server.plugin().and_then(|p| p.plugin_opts.clone()),
+        },
+        ssh: Default::default(),
+        chain: Vec::new(),
+    };
+
+    Ok((profile, server.password().to_string()))
+}
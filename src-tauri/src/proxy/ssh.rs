@@ -0,0 +1,454 @@
+//! SSH dynamic forwarding
+//! Keeps an SSH session open in-process and tunnels each connection through a
+//! `direct-tcpip` channel, like `ssh -D`, for the local forwarder to dial through
+
+use ssh2::{Channel, CheckResult, ErrorCode, KnownHostFileKind, Session};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::sync::oneshot;
+
+use crate::network::resolver::{resolve_socket_addrs, DnsResolver};
+use crate::proxy::config::ProxyConfig;
+use crate::proxy::upstream::BoxedStream;
+
+/// Timeout for the TCP connection, key exchange and authentication
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Seconds between keepalive messages on an idle session
+const KEEPALIVE_INTERVAL: u32 = 15;
+
+/// How long the session thread waits for new work right after something moved
+const MIN_IDLE_WAIT: Duration = Duration::from_millis(5);
+
+/// Longest wait the idle backoff reaches, bounding the latency of a quiet tunnel
+const MAX_IDLE_WAIT: Duration = Duration::from_millis(250);
+
+/// Buffer of the in-memory pipe between a channel and the forwarder
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// libssh2 error codes the session thread distinguishes
+const LIBSSH2_ERROR_SOCKET_SEND: i32 = -7;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+const LIBSSH2_ERROR_SOCKET_RECV: i32 = -43;
+
+/// Request for a new tunnel to `host:port`
+struct OpenRequest {
+    host: String,
+    port: u16,
+    reply: oneshot::Sender<io::Result<DuplexStream>>,
+}
+
+/// Authenticated SSH session serving `direct-tcpip` tunnels.
+/// The session is closed when this is dropped.
+pub struct SshTunnel {
+    server: String,
+    requests: mpsc::Sender<OpenRequest>,
+    alive: Arc<AtomicBool>,
+}
+
+impl SshTunnel {
+    /// Connect to the SSH server in `config`, verify its host key and log in.
+    /// The server name is resolved through `resolver`, the system resolver with `None`.
+    pub async fn connect(
+        config: &ProxyConfig,
+        resolver: Option<&DnsResolver>,
+    ) -> Result<Self, String> {
+        let server = format!("{}:{}", config.host, config.port);
+        let addr = resolve_socket_addrs(resolver, &config.host, config.port)
+            .await
+            .map_err(|e| format!("Failed to resolve SSH server {}: {}", server, e))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("SSH server {} has no address", server))?;
+
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || Self::connect_blocking(&config, addr))
+            .await
+            .map_err(|e| format!("SSH connection task failed: {}", e))?
+    }
+
+    fn connect_blocking(config: &ProxyConfig, addr: SocketAddr) -> Result<Self, String> {
+        let server = format!("{}:{}", config.host, config.port);
+        let username = config
+            .username
+            .as_deref()
+            .map(str::trim)
+            .filter(|u| !u.is_empty())
+            .ok_or("SSH username is required")?;
+
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .map_err(|e| format!("Failed to connect to SSH server {}: {}", server, e))?;
+        let _ = stream.set_nodelay(true);
+
+        let mut session = Session::new().map_err(|e| e.to_string())?;
+        session.set_tcp_stream(stream);
+        session.set_timeout(CONNECT_TIMEOUT.as_millis() as u32);
+        session
+            .handshake()
+            .map_err(|e| format!("SSH handshake with {} failed: {}", server, e))?;
+
+        verify_host_key(&session, config)?;
+
+        let password = config.password.as_deref().filter(|p| !p.is_empty());
+        match config.ssh.private_key_file.as_deref() {
+            Some(key_file) => session
+                .userauth_pubkey_file(username, None, Path::new(key_file), password)
+                .map_err(|e| format!("SSH key authentication failed: {}", e))?,
+            None => session
+                .userauth_password(
+                    username,
+                    password.ok_or("SSH password or private key is required")?,
+                )
+                .map_err(|e| format!("SSH password authentication failed: {}", e))?,
+        }
+        if !session.authenticated() {
+            return Err(format!("SSH server {} rejected the login", server));
+        }
+
+        session.set_keepalive(true, KEEPALIVE_INTERVAL);
+        session.set_timeout(0);
+        session.set_blocking(false);
+
+        let (requests, receiver) = mpsc::channel();
+        let alive = Arc::new(AtomicBool::new(true));
+        {
+            let alive = alive.clone();
+            let server = server.clone();
+            std::thread::Builder::new()
+                .name("ssh-tunnel".to_string())
+                .spawn(move || run_session(session, receiver, alive, server))
+                .map_err(|e| format!("Failed to start SSH session thread: {}", e))?;
+        }
+
+        log::info!("SSH session to {} established as {}", server, username);
+        Ok(Self {
+            server,
+            requests,
+            alive,
+        })
+    }
+
+    /// Open a tunnel to `host:port`; the SSH server connects to the target
+    pub async fn open(&self, host: &str, port: u16) -> io::Result<BoxedStream> {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send(OpenRequest {
+                host: host.to_string(),
+                port,
+                reply,
+            })
+            .map_err(|_| self.closed_error())?;
+        let stream = response.await.map_err(|_| self.closed_error())??;
+        Ok(Box::new(stream))
+    }
+
+    /// Whether the session is still up
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    /// `host:port` of the SSH server
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    fn closed_error(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!("SSH session to {} is closed", self.server),
+        )
+    }
+}
+
+/// Check the server's host key against known_hosts, recording it on first use when allowed
+fn verify_host_key(session: &Session, config: &ProxyConfig) -> Result<(), String> {
+    let (key, key_type) = session.host_key().ok_or("SSH server sent no host key")?;
+    let path = known_hosts_path(config)?;
+
+    let mut known_hosts = session.known_hosts().map_err(|e| e.to_string())?;
+    if path.is_file() {
+        known_hosts
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    }
+
+    match known_hosts.check_port(&config.host, config.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(format!(
+            "SSH host key for {} does not match {}; the server may be impersonated",
+            config.host,
+            path.display()
+        )),
+        CheckResult::NotFound if config.ssh.accept_new_host_key => {
+            let entry = if config.port == 22 {
+                config.host.clone()
+            } else {
+                format!("[{}]:{}", config.host, config.port)
+            };
+            known_hosts
+                .add(&entry, key, "added by Figma Free", key_type.into())
+                .map_err(|e| e.to_string())?;
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+            }
+            known_hosts
+                .write_file(&path, KnownHostFileKind::OpenSSH)
+                .map_err(|e| format!("Failed to update {}: {}", path.display(), e))?;
+            log::warn!(
+                "Recorded new SSH host key for {} in {}",
+                entry,
+                path.display()
+            );
+            Ok(())
+        }
+        CheckResult::NotFound => Err(format!(
+            "SSH host {} is not in {}; connect once with ssh or allow new host keys",
+            config.host,
+            path.display()
+        )),
+        CheckResult::Failure => Err("SSH host key check failed".to_string()),
+    }
+}
+
+fn known_hosts_path(config: &ProxyConfig) -> Result<PathBuf, String> {
+    match config.ssh.known_hosts_file.as_deref().map(str::trim) {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => dirs::home_dir()
+            .map(|home| home.join(".ssh").join("known_hosts"))
+            .ok_or_else(|| "Cannot locate ~/.ssh/known_hosts".to_string()),
+    }
+}
+
+fn would_block(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN)
+}
+
+/// Whether the error means the connection to the server is gone
+fn is_fatal(e: &ssh2::Error) -> bool {
+    matches!(
+        e.code(),
+        ErrorCode::Session(
+            LIBSSH2_ERROR_SOCKET_SEND | LIBSSH2_ERROR_SOCKET_DISCONNECT | LIBSSH2_ERROR_SOCKET_RECV
+        )
+    )
+}
+
+/// Drive the non-blocking session: open requested channels and pump data between
+/// each channel and its pipe until the tunnel is dropped or the connection dies
+fn run_session(
+    session: Session,
+    requests: mpsc::Receiver<OpenRequest>,
+    alive: Arc<AtomicBool>,
+    server: String,
+) {
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut pending: Vec<OpenRequest> = Vec::new();
+    let mut tunnels: Vec<Tunnel> = Vec::new();
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut busy = false;
+    let mut idle_wait = MIN_IDLE_WAIT;
+
+    let error = 'session: loop {
+        let request = if busy {
+            requests
+                .try_recv()
+                .map_err(|e| matches!(e, mpsc::TryRecvError::Empty))
+        } else {
+            requests
+                .recv_timeout(idle_wait)
+                .map_err(|e| matches!(e, mpsc::RecvTimeoutError::Timeout))
+        };
+        match request {
+            Ok(request) => pending.push(request),
+            Err(true) => {}
+            // The owning `SshTunnel` was dropped
+            Err(false) => break None,
+        }
+        busy = false;
+
+        if let Err(e) = session.keepalive_send() {
+            if !would_block(&e) {
+                break Some(e.to_string());
+            }
+        }
+
+        // libssh2 opens one channel at a time; keep retrying the oldest request
+        while let Some(request) = pending.first() {
+            match session.channel_direct_tcpip(&request.host, request.port, None) {
+                Ok(channel) => {
+                    let request = pending.remove(0);
+                    let (local, remote) = tokio::io::duplex(PIPE_CAPACITY);
+                    if request.reply.send(Ok(local)).is_ok() {
+                        tunnels.push(Tunnel::new(channel, remote));
+                    }
+                    busy = true;
+                }
+                Err(e) if would_block(&e) => break,
+                Err(e) => {
+                    let request = pending.remove(0);
+                    let message = format!(
+                        "SSH server could not open {}:{}: {}",
+                        request.host, request.port, e
+                    );
+                    let _ = request.reply.send(Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        message,
+                    )));
+                    if is_fatal(&e) {
+                        break 'session Some(e.to_string());
+                    }
+                }
+            }
+        }
+
+        tunnels.retain_mut(|tunnel| match tunnel.pump(&mut cx, &mut buf) {
+            Ok(progress) => {
+                busy |= progress;
+                !tunnel.is_done()
+            }
+            Err(e) => {
+                log::debug!("SSH tunnel via {} closed: {}", server, e);
+                false
+            }
+        });
+
+        // Back off while nothing moves so an idle session does not spin the thread
+        idle_wait = if busy || !pending.is_empty() {
+            MIN_IDLE_WAIT
+        } else {
+            (idle_wait * 2).min(MAX_IDLE_WAIT)
+        };
+    };
+
+    alive.store(false, Ordering::Relaxed);
+    for request in pending {
+        let _ = request.reply.send(Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "SSH session closed",
+        )));
+    }
+    drop(tunnels);
+
+    match error {
+        Some(error) => log::warn!("SSH session to {} lost: {}", server, error),
+        None => {
+            let _ = session.disconnect(None, "closing", None);
+            log::info!("SSH session to {} closed", server);
+        }
+    }
+}
+
+/// One `direct-tcpip` channel bridged to the forwarder through an in-memory pipe
+struct Tunnel {
+    channel: Channel,
+    pipe: DuplexStream,
+    /// Read from the pipe, not yet accepted by the channel
+    outgoing: Vec<u8>,
+    /// Read from the channel, not yet accepted by the pipe
+    incoming: Vec<u8>,
+    /// The forwarder finished sending and EOF was passed on to the server
+    local_eof: bool,
+    eof_sent: bool,
+    /// The server finished sending and the pipe was shut down for writing
+    remote_eof: bool,
+    pipe_closed: bool,
+}
+
+impl Tunnel {
+    fn new(channel: Channel, pipe: DuplexStream) -> Self {
+        Self {
+            channel,
+            pipe,
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+            local_eof: false,
+            eof_sent: false,
+            remote_eof: false,
+            pipe_closed: false,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.eof_sent && self.pipe_closed
+    }
+
+    /// Move whatever data is ready in both directions, returning whether anything moved
+    fn pump(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> io::Result<bool> {
+        let mut progress = false;
+
+        // Server -> forwarder
+        if !self.incoming.is_empty() {
+            if let Poll::Ready(written) = Pin::new(&mut self.pipe).poll_write(cx, &self.incoming) {
+                self.incoming.drain(..written?);
+                progress = true;
+            }
+        } else if !self.remote_eof {
+            match self.channel.read(buf) {
+                Ok(0) if self.channel.eof() => self.remote_eof = true,
+                Ok(0) => {}
+                Ok(n) => {
+                    self.incoming.extend_from_slice(&buf[..n]);
+                    progress = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.remote_eof && self.incoming.is_empty() && !self.pipe_closed {
+            if let Poll::Ready(result) = Pin::new(&mut self.pipe).poll_shutdown(cx) {
+                result?;
+                self.pipe_closed = true;
+                progress = true;
+            }
+        }
+
+        // Forwarder -> server
+        if !self.outgoing.is_empty() {
+            match self.channel.write(&self.outgoing) {
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                    progress = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        } else if !self.local_eof {
+            let mut read_buf = ReadBuf::new(buf);
+            if let Poll::Ready(result) = Pin::new(&mut self.pipe).poll_read(cx, &mut read_buf) {
+                result?;
+                match read_buf.filled() {
+                    [] => self.local_eof = true,
+                    data => self.outgoing.extend_from_slice(data),
+                }
+                progress = true;
+            }
+        }
+        if self.local_eof && self.outgoing.is_empty() && !self.eof_sent {
+            match self.channel.send_eof() {
+                Ok(()) => {
+                    self.eof_sent = true;
+                    progress = true;
+                }
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(progress)
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        // Best effort; a close that would block is finished by libssh2 with the session
+        let _ = self.channel.close();
+    }
+}
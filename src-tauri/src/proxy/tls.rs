@@ -0,0 +1,128 @@
+//! TLS to the proxy server
+//! Wraps the connection to an HTTPS proxy so credentials and CONNECT targets stay encrypted
+
+use std::io;
+use std::sync::Arc;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+use crate::proxy::config::{ProxyConfig, ProxyTlsConfig};
+use crate::proxy::upstream::BoxedStream;
+
+/// Perform the TLS handshake with the proxy over `stream`
+pub async fn connect_tls(stream: BoxedStream, config: &ProxyConfig) -> io::Result<BoxedStream> {
+    let client_config = client_config(&config.tls).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Proxy TLS setup: {}", e),
+        )
+    })?;
+
+    let name = config
+        .tls
+        .server_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or(&config.host)
+        .trim_matches(|c| c == '[' || c == ']')
+        .to_string();
+    let server_name = ServerName::try_from(name).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid TLS server name: {}", e),
+        )
+    })?;
+
+    let tls = TlsConnector::from(client_config)
+        .connect(server_name, stream)
+        .await?;
+    Ok(Box::new(tls))
+}
+
+/// Build the rustls client configuration for the proxy connection
+pub fn client_config(tls: &ProxyTlsConfig) -> Result<Arc<ClientConfig>, String> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let config = if tls.verify_certificate {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(ref ca_file) = tls.ca_file {
+            for cert in CertificateDer::pem_file_iter(ca_file)
+                .map_err(|e| format!("Failed to read CA file {}: {}", ca_file, e))?
+            {
+                let cert = cert.map_err(|e| format!("Invalid CA file {}: {}", ca_file, e))?;
+                roots
+                    .add(cert)
+                    .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+            }
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        log::warn!("Proxy certificate verification is disabled");
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth()
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Accepts any proxy certificate, for when verification is turned off in settings
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
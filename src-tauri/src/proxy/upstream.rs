@@ -0,0 +1,309 @@
+//! Upstream proxy dialer
+//! Opens tunnelled TCP streams to a target through the configured proxy server
+
+use base64::Engine;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_socks::tcp::{Socks4Stream, Socks5Stream};
+use tokio_socks::TargetAddr;
+
+use crate::network::resolver::{connect_tcp, resolve_socket_addrs, DnsResolver};
+use crate::proxy::config::{ProxyConfig, ProxyType};
+use crate::proxy::shadowsocks::shadowsocks_connect;
+use crate::proxy::tls::connect_tls;
+
+/// Maximum size of an HTTP CONNECT response head we are willing to read
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+/// The upstream proxy rejected our credentials (HTTP 407). Kept apart from the
+/// `PermissionDenied` of a routing rule so clients are not told the target is blocked.
+#[derive(Debug, thiserror::Error)]
+#[error("Proxy authentication required")]
+pub struct ProxyAuthRequired;
+
+impl ProxyAuthRequired {
+    /// Whether `error` is an upstream authentication failure
+    pub fn is(error: &io::Error) -> bool {
+        error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<ProxyAuthRequired>())
+    }
+}
+
+/// Any bidirectional byte stream that can carry tunnelled traffic
+pub trait ProxyStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ProxyStream for T {}
+
+/// Type-erased tunnel stream
+pub type BoxedStream = Box<dyn ProxyStream>;
+
+/// Open a TCP connection to `host:port` through the upstream proxy described by `config`,
+/// resolving names through `resolver` (the system resolver with `None`)
+pub async fn connect_via_proxy(
+    config: &ProxyConfig,
+    host: &str,
+    port: u16,
+    resolver: Option<&DnsResolver>,
+) -> io::Result<BoxedStream> {
+    if !config.chain.is_empty() {
+        return connect_via_chain(&config.hops(), host, port, resolver).await;
+    }
+
+    let stream = connect_tcp(resolver, &config.host, config.port).await?;
+    stream.set_nodelay(true)?;
+    handshake(Box::new(stream), config, host, port, resolver).await
+}
+
+/// Open a connection for a plain `http://` request to `host:port`. When the exit proxy
+/// speaks HTTP the connection goes to that proxy itself and its config is returned, so the
+/// request can be forwarded in absolute form (many proxies refuse CONNECT to port 80);
+/// otherwise it is a tunnel to the target, as from `connect_via_proxy`.
+async fn connect_for_http(
+    config: &ProxyConfig,
+    host: &str,
+    port: u16,
+    resolver: Option<&DnsResolver>,
+) -> io::Result<(BoxedStream, Option<ProxyConfig>)> {
+    let hops = config.hops();
+    let Some((exit, rest)) = hops
+        .split_last()
+        .filter(|(exit, _)| matches!(exit.proxy_type, ProxyType::Http | ProxyType::Https))
+    else {
+        let stream = connect_via_proxy(config, host, port, resolver).await?;
+        return Ok((stream, None));
+    };
+
+    let stream = if rest.is_empty() {
+        connect_direct(&exit.host, exit.port, resolver).await?
+    } else {
+        connect_via_chain(rest, &exit.host, exit.port, resolver).await?
+    };
+    let stream = match exit.proxy_type {
+        ProxyType::Https => connect_tls(stream, exit).await?,
+        _ => stream,
+    };
+    Ok((stream, Some(exit.clone())))
+}
+
+/// Connect to `host:port` through `config`, or to the HTTP(S) proxy itself with `plain_http`
+pub async fn connect_upstream(
+    config: &ProxyConfig,
+    host: &str,
+    port: u16,
+    resolver: Option<&DnsResolver>,
+    plain_http: bool,
+) -> io::Result<(BoxedStream, Option<ProxyConfig>)> {
+    if plain_http {
+        connect_for_http(config, host, port, resolver).await
+    } else {
+        Ok((connect_via_proxy(config, host, port, resolver).await?, None))
+    }
+}
+
+/// Open a TCP connection to `host:port` through each of `hops` in turn
+pub async fn connect_via_chain(
+    hops: &[ProxyConfig],
+    host: &str,
+    port: u16,
+    resolver: Option<&DnsResolver>,
+) -> io::Result<BoxedStream> {
+    let (first, rest) = hops
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Proxy chain is empty"))?;
+
+    let mut stream = connect_direct(&first.host, first.port, resolver).await?;
+    let mut current = first;
+    for next in rest {
+        stream = handshake(stream, current, &next.host, next.port, resolver).await?;
+        current = next;
+    }
+    handshake(stream, current, host, port, resolver).await
+}
+
+/// Open a direct TCP connection to `host:port`
+pub async fn connect_direct(
+    host: &str,
+    port: u16,
+    resolver: Option<&DnsResolver>,
+) -> io::Result<BoxedStream> {
+    let stream = connect_tcp(resolver, host, port).await?;
+    stream.set_nodelay(true)?;
+    Ok(Box::new(stream))
+}
+
+/// Negotiate a tunnel to `host:port` over an already connected proxy stream
+pub async fn handshake(
+    stream: BoxedStream,
+    config: &ProxyConfig,
+    host: &str,
+    port: u16,
+    resolver: Option<&DnsResolver>,
+) -> io::Result<BoxedStream> {
+    match config.proxy_type {
+        ProxyType::Socks5 | ProxyType::Socks5h => {
+            let target = socks_target(config.proxy_type, host, port, resolver).await?;
+            socks5_handshake(stream, config, target).await
+        }
+        ProxyType::Socks4 | ProxyType::Socks4a => {
+            let target = socks_target(config.proxy_type, host, port, resolver).await?;
+            socks4_handshake(stream, config, target).await
+        }
+        ProxyType::Http => http_connect(stream, config, host, port).await,
+        ProxyType::Https => {
+            let stream = connect_tls(stream, config).await?;
+            http_connect(stream, config, host, port).await
+        }
+        ProxyType::Shadowsocks => shadowsocks_connect(stream, config, host, port).await,
+        ProxyType::Pac => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "PAC configurations must be resolved to a proxy before connecting",
+        )),
+        ProxyType::Ssh => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SSH tunnels are opened through the SSH session",
+        )),
+    }
+}
+
+/// Address to request from a SOCKS proxy: the hostname itself for the remote-DNS
+/// variants, otherwise an address resolved here (IPv4 only for SOCKS4)
+async fn socks_target(
+    proxy_type: ProxyType,
+    host: &str,
+    port: u16,
+    resolver: Option<&DnsResolver>,
+) -> io::Result<TargetAddr<'static>> {
+    if let Ok(ip) = host.parse::<std::net::IpAddr>() {
+        return Ok(TargetAddr::Ip((ip, port).into()));
+    }
+    if proxy_type.resolves_remotely() {
+        return Ok(TargetAddr::Domain(host.to_string().into(), port));
+    }
+
+    let ipv4_only = proxy_type == ProxyType::Socks4;
+    resolve_socket_addrs(resolver, host, port)
+        .await?
+        .into_iter()
+        .find(|addr| !ipv4_only || addr.is_ipv4())
+        .map(TargetAddr::Ip)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No usable address found for {}", host),
+            )
+        })
+}
+
+async fn socks5_handshake(
+    stream: BoxedStream,
+    config: &ProxyConfig,
+    target: TargetAddr<'static>,
+) -> io::Result<BoxedStream> {
+    let result = match (&config.username, &config.password) {
+        (Some(user), Some(pass)) => {
+            Socks5Stream::connect_with_password_and_socket(stream, target, user, pass).await
+        }
+        _ => Socks5Stream::connect_with_socket(stream, target).await,
+    };
+
+    result
+        .map(|s| Box::new(s) as BoxedStream)
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e.to_string()))
+}
+
+async fn socks4_handshake(
+    stream: BoxedStream,
+    config: &ProxyConfig,
+    target: TargetAddr<'static>,
+) -> io::Result<BoxedStream> {
+    // SOCKS4 only carries a user id, no password
+    let result = match config.username {
+        Some(ref user) => Socks4Stream::connect_with_userid_and_socket(stream, target, user).await,
+        None => Socks4Stream::connect_with_socket(stream, target).await,
+    };
+
+    result
+        .map(|s| Box::new(s) as BoxedStream)
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e.to_string()))
+}
+
+async fn http_connect(
+    mut stream: BoxedStream,
+    config: &ProxyConfig,
+    host: &str,
+    port: u16,
+) -> io::Result<BoxedStream> {
+    let authority = format_authority(host, port);
+    let mut request = format!(
+        "CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\nProxy-Connection: keep-alive\r\n"
+    );
+    if let Some(authorization) = proxy_authorization(config) {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let head = read_response_head(&mut stream).await?;
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed proxy response: {}", status_line),
+            )
+        })?;
+
+    match status {
+        200..=299 => Ok(stream),
+        407 => Err(io::Error::other(ProxyAuthRequired)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Proxy refused CONNECT: {}", status_line),
+        )),
+    }
+}
+
+/// `Proxy-Authorization` value for an HTTP(S) proxy with credentials
+pub fn proxy_authorization(config: &ProxyConfig) -> Option<String> {
+    let user = config.username.as_ref()?;
+    let credentials = format!("{}:{}", user, config.password.as_deref().unwrap_or(""));
+    let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+    Some(format!("Basic {}", encoded))
+}
+
+/// Read the response head byte by byte so no tunnelled payload is consumed
+async fn read_response_head(stream: &mut BoxedStream) -> io::Result<String> {
+    let mut head = Vec::with_capacity(256);
+    let mut byte = [0u8; 1];
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Proxy response head too large",
+            ));
+        }
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Proxy closed the connection during CONNECT",
+            ));
+        }
+        head.push(byte[0]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Format `host:port`, bracketing IPv6 literals
+pub fn format_authority(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
@@ -3,6 +3,8 @@
 
 use keyring::Entry;
 
+use crate::proxy::{ProxyConfig, ProxyHop};
+
 const SERVICE_NAME: &str = "figma-desktop-proxy";
 
 /// Error types for credential operations
@@ -72,6 +74,77 @@ pub fn delete_proxy_password(host: &str, port: u16) -> Result<(), CredentialErro
     delete_credential(&key)
 }
 
+/// Store a proxy profile password securely
+pub fn store_profile_password(profile_id: &str, password: &str) -> Result<(), CredentialError> {
+    let key = format!("profile_{}", profile_id);
+    store_credential(&key, password)
+}
+
+/// Retrieve a proxy profile password
+pub fn get_profile_password(profile_id: &str) -> Result<String, CredentialError> {
+    let key = format!("profile_{}", profile_id);
+    get_credential(&key)
+}
+
+/// Delete a proxy profile password
+pub fn delete_profile_password(profile_id: &str) -> Result<(), CredentialError> {
+    let key = format!("profile_{}", profile_id);
+    delete_credential(&key)
+}
+
+/// Retrieve the stored password for a proxy configuration,
+/// from its profile when it was activated from one
+pub fn get_config_password(config: &ProxyConfig) -> Option<String> {
+    match config.profile_id {
+        Some(ref id) => get_profile_password(id).ok(),
+        None => get_proxy_password(&config.host, config.port).ok(),
+    }
+}
+
+/// Keyring key of a chain hop's password: the owning profile and the hop's position in
+/// the chain, or the hop's username, host and port for a configuration outside any profile
+fn chain_password_key(profile_id: Option<&str>, index: usize, hop: &ProxyHop) -> String {
+    match profile_id {
+        Some(id) => format!("profile_{}_hop_{}", id, index),
+        None => format!(
+            "chain_{}@{}_{}",
+            hop.username.as_deref().unwrap_or_default(),
+            hop.host,
+            hop.port
+        ),
+    }
+}
+
+/// Store the passwords entered for chain hops of the given profile (or of no profile)
+pub fn store_chain_passwords(
+    profile_id: Option<&str>,
+    chain: &[ProxyHop],
+) -> Result<(), CredentialError> {
+    for (index, hop) in chain.iter().enumerate() {
+        if let Some(password) = hop.password.as_deref().filter(|p| !p.is_empty()) {
+            store_credential(&chain_password_key(profile_id, index, hop), password)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fill in the stored passwords of a configuration's chain hops
+pub fn load_chain_passwords(config: &mut ProxyConfig) {
+    let profile_id = config.profile_id.as_deref();
+    for (index, hop) in config.chain.iter_mut().enumerate() {
+        if hop.uses_password() && hop.password.is_none() {
+            hop.password = get_credential(&chain_password_key(profile_id, index, hop)).ok();
+        }
+    }
+}
+
+/// Delete the stored passwords of chain hops of the given profile (or of no profile)
+pub fn delete_chain_passwords(profile_id: Option<&str>, chain: &[ProxyHop]) {
+    for (index, hop) in chain.iter().enumerate() {
+        let _ = delete_credential(&chain_password_key(profile_id, index, hop));
+    }
+}
+
 /// Check if a credential exists
 pub fn credential_exists(key: &str) -> bool {
     let entry = match Entry::new(SERVICE_NAME, key) {
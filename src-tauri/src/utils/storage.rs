@@ -11,6 +11,13 @@ pub mod keys {
     pub const WINDOW_STATE: &str = "window_state";
     pub const PROXY_PRESETS: &str = "proxy_presets";
     pub const FIRST_RUN: &str = "first_run";
+    pub const FAILOVER: &str = "failover";
+    pub const BALANCER: &str = "balancer";
+    pub const ROUTING: &str = "routing";
+    pub const INTERCEPTOR: &str = "interceptor";
+    pub const DNS: &str = "dns";
+    pub const DNS_STUB: &str = "dns_stub";
+    pub const HEALTH_CHECK: &str = "health_check";
 }
 
 /// Store filename (saved under the app data directory by tauri-plugin-store)
@@ -30,6 +37,22 @@ pub struct AdvancedSettings {
     pub kill_switch: bool,
     /// Auto-update enabled
     pub auto_update: bool,
+    /// Notify when the proxy connection is lost
+    #[serde(default = "default_notify")]
+    pub notify_on_disconnect: bool,
+    /// Notify when the proxy connection comes back
+    #[serde(default = "default_notify")]
+    pub notify_on_recover: bool,
+    /// Notify when failover switches to another proxy
+    #[serde(default = "default_notify")]
+    pub notify_on_failover: bool,
+    /// Notify when the kill switch blocks traffic
+    #[serde(default = "default_notify")]
+    pub notify_on_kill_switch: bool,
+}
+
+fn default_notify() -> bool {
+    true
 }
 
 impl Default for AdvancedSettings {
@@ -40,6 +63,10 @@ impl Default for AdvancedSettings {
             custom_user_agent: None,
             kill_switch: false,
             auto_update: true,
+            notify_on_disconnect: true,
+            notify_on_recover: true,
+            notify_on_failover: true,
+            notify_on_kill_switch: true,
         }
     }
 }
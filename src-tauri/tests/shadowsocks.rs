@@ -0,0 +1,191 @@
+//! Shadowsocks client tests against an in-process Shadowsocks server
+
+use figma_wpr_lib::proxy::{
+    connect_via_proxy, parse_ss_uri, KillSwitchState, LocalForwarder, ProxyConfig, ProxyType,
+    Upstream,
+};
+use shadowsocks::config::{ServerConfig, ServerType};
+use shadowsocks::context::Context;
+use shadowsocks::relay::tcprelay::ProxyServerStream;
+use shadowsocks::relay::Address;
+use std::net::SocketAddr;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+/// Echo every byte back to the sender
+async fn start_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Minimal Shadowsocks server relaying to the requested target
+async fn start_ss_server(cipher: &str, password: &str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = ServerConfig::new(addr, password, cipher.parse().unwrap()).unwrap();
+    let context = Context::new_shared(ServerType::Server);
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let context = context.clone();
+            let method = config.method();
+            let key = config.key().to_vec();
+            tokio::spawn(async move {
+                let mut stream = ProxyServerStream::from_stream(context, socket, method, &key);
+                let target = match stream.handshake().await {
+                    Ok(target) => target,
+                    Err(_) => return,
+                };
+                let mut remote = match target {
+                    Address::SocketAddress(addr) => TcpStream::connect(addr).await,
+                    Address::DomainNameAddress(host, port) => {
+                        TcpStream::connect((host.as_str(), port)).await
+                    }
+                }
+                .unwrap();
+                let _ = copy_bidirectional(&mut stream, &mut remote).await;
+            });
+        }
+    });
+    addr
+}
+
+fn ss_config(server: SocketAddr, cipher: &str, password: &str) -> ProxyConfig {
+    let mut config = ProxyConfig::new(
+        ProxyType::Shadowsocks,
+        server.ip().to_string(),
+        server.port(),
+    );
+    config.password = Some(password.to_string());
+    config.shadowsocks.cipher = cipher.to_string();
+    config
+}
+
+async fn assert_echo<S: AsyncReadExt + AsyncWriteExt + Unpin>(stream: &mut S) {
+    stream.write_all(b"ping through shadowsocks").await.unwrap();
+    let mut buf = [0u8; 24];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping through shadowsocks");
+}
+
+#[tokio::test]
+async fn tunnels_with_aead_ciphers() {
+    let echo = start_echo_server().await;
+
+    for cipher in ["aes-256-gcm", "chacha20-ietf-poly1305"] {
+        let server = start_ss_server(cipher, "correct horse").await;
+        let config = ss_config(server, cipher, "correct horse");
+        assert!(config.validate().is_ok());
+
+        let mut tunnel = connect_via_proxy(&config, "127.0.0.1", echo.port())
+            .await
+            .unwrap();
+        assert_echo(&mut tunnel).await;
+    }
+}
+
+#[tokio::test]
+async fn tunnels_with_aead_2022_cipher() {
+    let echo = start_echo_server().await;
+    // 2022 ciphers take a base64 key of the cipher's key length (16 bytes here)
+    let key = "AAECAwQFBgcICQoLDA0ODw==";
+    let server = start_ss_server("2022-blake3-aes-128-gcm", key).await;
+    let config = ss_config(server, "2022-blake3-aes-128-gcm", key);
+
+    let mut tunnel = connect_via_proxy(&config, "127.0.0.1", echo.port())
+        .await
+        .unwrap();
+    assert_echo(&mut tunnel).await;
+}
+
+#[tokio::test]
+async fn wrong_password_closes_tunnel() {
+    let echo = start_echo_server().await;
+    let server = start_ss_server("aes-256-gcm", "correct horse").await;
+    let config = ss_config(server, "aes-256-gcm", "battery staple");
+
+    let mut tunnel = connect_via_proxy(&config, "127.0.0.1", echo.port())
+        .await
+        .unwrap();
+    tunnel.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    assert!(tunnel.read_exact(&mut buf).await.is_err());
+}
+
+#[tokio::test]
+async fn rejects_invalid_settings() {
+    let mut config = ss_config("127.0.0.1:8388".parse().unwrap(), "rot13", "secret");
+    assert!(config.validate().is_err());
+
+    config.shadowsocks.cipher = "2022-blake3-aes-256-gcm".to_string();
+    assert!(figma_wpr_lib::proxy::server_config(&config).is_err());
+
+    config.password = None;
+    assert!(figma_wpr_lib::proxy::server_config(&config).is_err());
+}
+
+#[tokio::test]
+async fn local_forwarder_relays_to_shadowsocks() {
+    let echo = start_echo_server().await;
+    let server = start_ss_server("aes-128-gcm", "forwarded").await;
+    let config = ss_config(server, "aes-128-gcm", "forwarded");
+
+    let (_kill_switch, kill_switch_rx) = watch::channel(KillSwitchState::default());
+    let forwarder = LocalForwarder::start(Upstream::from(config), kill_switch_rx)
+        .await
+        .unwrap();
+
+    let mut client = TcpStream::connect(forwarder.local_addr()).await.unwrap();
+    let request = format!(
+        "CONNECT 127.0.0.1:{0} HTTP/1.1\r\nHost: 127.0.0.1:{0}\r\n\r\n",
+        echo.port()
+    );
+    client.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        response.push(client.read_u8().await.unwrap());
+    }
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+
+    assert_echo(&mut client).await;
+}
+
+#[test]
+fn imports_ss_uris() {
+    // SIP002 with a base64url user-info and a plugin
+    let (profile, password) = parse_ss_uri(
+        "ss://YWVzLTI1Ni1nY206dGVzdA@192.168.100.1:8888/?plugin=obfs-local%3Bobfs%3Dhttp#Office",
+    )
+    .unwrap();
+    assert_eq!(profile.proxy_type, ProxyType::Shadowsocks);
+    assert_eq!(profile.name, "Office");
+    assert_eq!(profile.host, "192.168.100.1");
+    assert_eq!(profile.port, 8888);
+    assert_eq!(profile.shadowsocks.cipher, "aes-256-gcm");
+    assert_eq!(profile.shadowsocks.plugin.as_deref(), Some("obfs-local"));
+    assert_eq!(
+        profile.shadowsocks.plugin_opts.as_deref(),
+        Some("obfs=http")
+    );
+    assert_eq!(password, "test");
+
+    // Legacy form with everything base64 encoded
+    let (profile, password) =
+        parse_ss_uri("ss://Y2hhY2hhMjAtaWV0Zi1wb2x5MTMwNTpwYXNzQGV4YW1wbGUuY29tOjgzODg").unwrap();
+    assert_eq!(profile.name, "example.com:8388");
+    assert_eq!(profile.shadowsocks.cipher, "chacha20-ietf-poly1305");
+    assert_eq!(password, "pass");
+
+    assert!(parse_ss_uri("socks5://example.com:1080").is_err());
+}
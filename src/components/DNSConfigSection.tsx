@@ -37,15 +37,31 @@ export function DNSConfigSection() {
 
   const validateDNS = (dns: string): boolean => {
     if (!dns.trim()) return true;
-    const servers = dns.split(',').map(s => s.trim());
+    const servers = dns.split(/[,\s]+/).map(s => s.trim()).filter(Boolean);
     const ipv4Regex = /^(\d{1,3}\.){3}\d{1,3}$/;
-    return servers.every(server => ipv4Regex.test(server) && 
-      server.split('.').every(octet => parseInt(octet) <= 255));
+    const ipv6Regex = /^[0-9a-fA-F:]+$/;
+    const isIp = (host: string) =>
+      (ipv4Regex.test(host) && host.split('.').every(octet => parseInt(octet) <= 255)) ||
+      (host.includes(':') && ipv6Regex.test(host));
+    const isAddress = (address: string) => {
+      const bracketed = address.match(/^\[([^\]]+)\](?::(\d+))?$/);
+      if (bracketed) return isIp(bracketed[1]) && (!bracketed[2] || parseInt(bracketed[2]) <= 65535);
+      const withPort = address.match(/^([\d.]+):(\d+)$/);
+      if (withPort) return isIp(withPort[1]) && parseInt(withPort[2]) <= 65535;
+      return isIp(address);
+    };
+    return servers.every(server => {
+      if (server.startsWith('https://')) return true;
+      const [, scheme, rest = ''] = server.match(/^(?:(tcp|tls|quic):\/\/)?(.*)$/) ?? [];
+      const [address, tlsName] = rest.split('#');
+      const needsTlsName = scheme === 'tls' || scheme === 'quic';
+      return isAddress(address) && (needsTlsName ? !!tlsName : tlsName === undefined);
+    });
   };
 
   const handleSave = async () => {
     if (localConfig.enabled && !validateDNS(localConfig.dnsServers)) {
-      message('Please enter valid comma-separated IPv4 addresses.', { kind: "error", title: "Invalid DNS Servers" });
+      message('Please enter comma-separated IP addresses, tcp://, tls://ip#name, quic://ip#name or https:// DoH servers.', { kind: "error", title: "Invalid DNS Servers" });
       return;
     }
     
@@ -106,7 +122,7 @@ export function DNSConfigSection() {
             />
           </InputGroup>
           <p className="text-xs text-muted-foreground mt-1">
-            Examples: Cloudflare (1.1.1.1), Google (8.8.8.8), Quad9 (9.9.9.9)
+            Examples: Cloudflare (1.1.1.1), Google over TLS (tls://8.8.8.8#dns.google), AdGuard over QUIC (quic://94.140.14.14#dns.adguard-dns.com)
           </p>
         </div>
 
@@ -28,11 +28,7 @@ export function HomeScreen() {
 
   const launchFigma = async () => {
     try {
-      const scheme = config.type === "https" ? "http" : config.type || "http";
-      const host = config.host || "127.0.0.1";
-      const port = config.port || 1080;
-      const proxy = `${scheme}://${host}:${port}`;
-      await invoke("create_figma_window", { proxy });
+      await invoke("create_figma_window");
     } catch (err) {
       message("Failed to launch Figma", {
         kind: "error",
@@ -64,6 +64,29 @@ export function AdvancedTab() {
         />
       </div>
 
+      {/* Notifications */}
+      <div className="space-y-3">
+        <div>
+          <h3 className="text-sm font-medium">Notifications</h3>
+          <p className="text-xs text-muted-foreground">Desktop notifications when the connection changes</p>
+        </div>
+        {([
+          ['notifyOnDisconnect', 'Connection lost'],
+          ['notifyOnRecover', 'Connection restored'],
+          ['notifyOnFailover', 'Switched to another proxy'],
+          ['notifyOnKillSwitch', 'Kill switch engaged'],
+        ] as const).map(([field, label]) => (
+          <div key={field} className="flex items-center justify-between">
+            <Label htmlFor={field} className="text-sm font-normal">{label}</Label>
+            <Switch
+              id={field}
+              checked={localSettings[field]}
+              onCheckedChange={(checked) => handleChange(field, checked)}
+            />
+          </div>
+        ))}
+      </div>
+
       {/* Auto Update */}
       <div className="flex items-center justify-between">
         <div>
@@ -123,6 +123,9 @@ export function ProxyTab() {
           </SelectTrigger>
           <SelectContent>
             <SelectItem value="socks5">SOCKS5</SelectItem>
+            <SelectItem value="socks5h">SOCKS5 (remote DNS)</SelectItem>
+            <SelectItem value="socks4">SOCKS4</SelectItem>
+            <SelectItem value="socks4a">SOCKS4a</SelectItem>
             <SelectItem value="http">HTTP</SelectItem>
             <SelectItem value="https">HTTPS</SelectItem>
           </SelectContent>
@@ -1,7 +1,7 @@
 import { useEffect, useCallback } from 'react';
 import { listen } from '@tauri-apps/api/event';
 import { useProxyStore, useSettingsStore } from '../store/proxyStore';
-import type { ConnectionInfo, ConnectionStatus } from '../types/proxy';
+import type { ConnectionInfo, ConnectionStatus, KillSwitchState, StatusChangeEvent } from '../types/proxy';
 import { toast } from "sonner"
 
 /**
@@ -16,14 +16,21 @@ export function useProxy() {
   useEffect(() => {
     loadConfig();
     refreshStatus();
-    
-    // Set up periodic status refresh
-    const interval = setInterval(() => {
-      refreshStatus();
-    }, 5000);
-    
-    return () => clearInterval(interval);
   }, [loadConfig, refreshStatus]);
+
+  // Refresh whenever a health check changes the connection state
+  useEffect(() => {
+    const events = ['proxy-connected', 'proxy-degraded', 'proxy-disconnected', 'proxy-recovered'];
+    const unlisteners = events.map((name) =>
+      listen<StatusChangeEvent>(name, () => {
+        refreshStatus();
+      })
+    );
+
+    return () => {
+      unlisteners.forEach((unlisten) => unlisten.then((fn) => fn()));
+    };
+  }, [refreshStatus]);
   
   // Listen for proxy toggle events from system tray
   useEffect(() => {
@@ -39,6 +46,26 @@ export function useProxy() {
       unlisten.then((fn) => fn());
     };
   }, [store.setConfig, store.refreshStatus]);
+
+  // Listen for kill switch transitions from the backend
+  useEffect(() => {
+    const refresh = store.refreshStatus;
+
+    const unlistenEngaged = listen<KillSwitchState>('kill-switch-engaged', (event) => {
+      toast.error("Kill switch engaged", {
+        description: event.payload.reason ?? "Figma traffic is blocked until the proxy is back",
+      });
+      refresh();
+    });
+    const unlistenReleased = listen<KillSwitchState>('kill-switch-released', () => {
+      refresh();
+    });
+
+    return () => {
+      unlistenEngaged.then((fn) => fn());
+      unlistenReleased.then((fn) => fn());
+    };
+  }, [store.refreshStatus]);
   
   const saveAndEnable = useCallback(async () => {
     await store.saveConfig();
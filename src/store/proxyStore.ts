@@ -58,6 +58,10 @@ export const useProxyStore = create<ProxyStore>()(
         customUserAgent: null,
         killSwitch: false,
         autoUpdate: true,
+        notifyOnDisconnect: true,
+        notifyOnRecover: true,
+        notifyOnFailover: true,
+        notifyOnKillSwitch: true,
       },
 
       // Actions
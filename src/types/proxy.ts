@@ -1,6 +1,6 @@
 // Proxy configuration types matching Rust backend
 
-export type ProxyType = 'socks5' | 'http' | 'https';
+export type ProxyType = 'socks5' | 'socks5h' | 'socks4' | 'socks4a' | 'http' | 'https' | 'pac' | 'shadowsocks' | 'ssh';
 
 export interface ProxyConfig {
   enabled: boolean;
@@ -11,6 +11,56 @@ export interface ProxyConfig {
   password?: string;
   autoDetect: boolean;
   autoConnect: boolean;
+  profileId?: string | null;
+  noProxy?: string[];
+  pacUrl?: string | null;
+  tls?: ProxyTlsConfig;
+  shadowsocks?: ShadowsocksConfig;
+  ssh?: SshConfig;
+  chain?: ProxyHop[];
+}
+
+// Shadowsocks cipher and SIP003 plugin; the password is the proxy password
+export interface ShadowsocksConfig {
+  cipher: string;
+  plugin: string | null;
+  pluginOpts: string | null;
+}
+
+// Intermediate proxy in a chain; the password is stored in the keyring by profile and position
+export interface ProxyHop {
+  type: ProxyType;
+  host: string;
+  port: number;
+  username?: string | null;
+  password?: string;
+}
+
+// SSH key and host verification; the password is the login password or key passphrase
+export interface SshConfig {
+  privateKeyFile: string | null;
+  knownHostsFile: string | null;
+  acceptNewHostKey: boolean;
+}
+
+// TLS settings for the connection to an HTTPS proxy
+export interface ProxyTlsConfig {
+  serverName: string | null;
+  verifyCertificate: boolean;
+  caFile: string | null;
+}
+
+// System proxy detected when autoDetect is on
+export type SystemProxySource = 'environment' | 'gnome' | 'kde';
+
+export interface SystemProxy {
+  source: SystemProxySource;
+  origin: string;
+  type: ProxyType;
+  host: string;
+  port: number;
+  username: string | null;
+  noProxy: string[];
 }
 
 export interface ProxyTestResult {
@@ -18,6 +68,15 @@ export interface ProxyTestResult {
   latencyMs: number | null;
   error: string | null;
   externalIp: string | null;
+  hops?: HopTestResult[];
+  failedHop?: string | null;
+}
+
+// Time to reach one hop of a proxy chain
+export interface HopTestResult {
+  proxy: string;
+  latencyMs: number | null;
+  error: string | null;
 }
 
 export interface ProxyStatus {
@@ -26,6 +85,33 @@ export interface ProxyStatus {
   latencyMs: number | null;
   lastError: string | null;
   lastUpdated: string;
+  killSwitchEngaged: boolean;
+}
+
+export interface KillSwitchState {
+  engaged: boolean;
+  reason: string | null;
+  since: string | null;
+}
+
+// Saved proxy profile
+export interface ProxyPreset {
+  id: string;
+  name: string;
+  proxyType: ProxyType;
+  host: string;
+  port: number;
+  username: string | null;
+  requiresAuth: boolean;
+  location: string | null;
+  notes: string | null;
+  lastTest: ProxyTestResult | null;
+  lastTestedAt: string | null;
+  pacUrl?: string | null;
+  tls?: ProxyTlsConfig;
+  shadowsocks?: ShadowsocksConfig;
+  ssh?: SshConfig;
+  chain?: ProxyHop[];
 }
 
 // Advanced settings
@@ -35,6 +121,268 @@ export interface AdvancedSettings {
   customUserAgent: string | null;
   killSwitch: boolean;
   autoUpdate: boolean;
+  // Native notifications per proxy state change
+  notifyOnDisconnect: boolean;
+  notifyOnRecover: boolean;
+  notifyOnFailover: boolean;
+  notifyOnKillSwitch: boolean;
+}
+
+// Periodic health check of the active proxy
+export type ProbeKind = 'tcp' | 'handshake' | 'tls' | 'http' | 'websocket';
+
+// target: host[:port] for handshake/tls, http(s) URL for http, ws(s) URL for websocket
+export interface HealthProbe {
+  kind: ProbeKind;
+  target: string;
+  timeoutSecs: number;
+  weight: number;
+}
+
+export interface HealthCheckConfig {
+  intervalSecs: number;
+  failureThreshold: number;
+  probes: HealthProbe[];
+}
+
+export interface ProbeResult {
+  kind: ProbeKind;
+  target: string;
+  weight: number;
+  success: boolean;
+  skipped: boolean;
+  latencyMs: number | null;
+  error: string | null;
+}
+
+export interface HealthCheckReport {
+  healthy: boolean;
+  score: number;
+  failedLayer: ProbeKind | null;
+  probes: ProbeResult[];
+  checkedAt: string;
+}
+
+export type HealthMonitorState = 'stopped' | 'running' | 'paused';
+
+export interface HealthMonitorStatus {
+  state: HealthMonitorState;
+  consecutiveFailures: number;
+  lastReport: HealthCheckReport | null;
+}
+
+// Payload of the `proxy-connected`, `proxy-degraded`, `proxy-disconnected` and
+// `proxy-recovered` events
+export type StatusChange = 'connected' | 'degraded' | 'disconnected' | 'recovered';
+
+export type LinkState = 'connected' | 'degraded' | 'disconnected';
+
+export interface StatusChangeEvent {
+  change: StatusChange;
+  previous: LinkState | null;
+  latencyMs: number | null;
+  error: string | null;
+  failedLayer: ProbeKind | null;
+  consecutiveFailures: number;
+  timestamp: string;
+}
+
+// Ordered failover group across proxy profiles
+export interface FailoverConfig {
+  enabled: boolean;
+  profileIds: string[];
+  probeIntervalSecs: number;
+  failuresToSwitch: number;
+  successesToReturn: number;
+  minDwellSecs: number;
+}
+
+export interface FailoverUpstream {
+  profileId: string;
+  name: string;
+  proxy: string;
+}
+
+// Payload of the `proxy-failover` event
+export interface FailoverEvent {
+  from: FailoverUpstream | null;
+  to: FailoverUpstream;
+  reason: string;
+  timestamp: string;
+}
+
+export interface UpstreamHealth extends FailoverUpstream {
+  consecutiveFailures: number;
+  consecutiveSuccesses: number;
+  latencyMs: number | null;
+  lastError: string | null;
+  lastChecked: string | null;
+}
+
+export interface FailoverStatus {
+  enabled: boolean;
+  active: string | null;
+  upstreams: UpstreamHealth[];
+}
+
+// Load balancing across all saved proxy profiles
+export type BalanceStrategy = 'lowestLatency' | 'highestSuccessRate' | 'roundRobin' | 'leastConnections';
+
+export interface BalancerConfig {
+  enabled: boolean;
+  strategy: BalanceStrategy;
+  probeIntervalSecs: number;
+  selectedProfileId: string | null;
+}
+
+export interface MemberStats {
+  profileId: string;
+  name: string;
+  proxy: string;
+  healthy: boolean;
+  successRate: number | null;
+  latencyMs: number | null;
+  activeConnections: number;
+  lastError: string | null;
+  lastChecked: string | null;
+}
+
+export interface BalancerStatus {
+  enabled: boolean;
+  strategy: BalanceStrategy;
+  selected: string | null;
+  members: MemberStats[];
+}
+
+// Split routing rules; the first matching rule wins
+export type RouteAction = 'direct' | 'proxy' | 'block';
+
+export interface RoutingRule {
+  hosts: string[];
+  cidrs: string[];
+  ports: string[];
+  action: RouteAction;
+}
+
+export interface RoutingConfig {
+  enabled: boolean;
+  rules: RoutingRule[];
+  defaultAction: RouteAction;
+}
+
+// Webview request interception; globs over scheme://host[:port]/path or host-only
+export interface InterceptorConfig {
+  enabled: boolean;
+  interceptPatterns: string[];
+  bypassPatterns: string[];
+}
+
+// Custom DNS servers; customDns in AdvancedSettings is the same list in shorthand
+export type DnsTransport = 'udp' | 'tcp' | 'doh' | 'dot' | 'doq';
+
+export interface DnsServer {
+  address: string;
+  transport: DnsTransport;
+  tlsName: string | null;
+  spkiPin: string | null;
+}
+
+export interface DnsCacheConfig {
+  enabled: boolean;
+  maxEntries: number;
+  maxTtlSecs: number;
+  negativeTtlSecs: number;
+  prefetch: boolean;
+}
+
+// Name pinned to fixed addresses; `*.example.com` covers every subdomain
+export interface HostOverride {
+  name: string;
+  addresses: string[];
+}
+
+export interface DnsConfig {
+  enabled: boolean;
+  servers: DnsServer[];
+  cache: DnsCacheConfig;
+  hosts: HostOverride[];
+}
+
+// Cached lookup; negative entries are NXDOMAIN, empty answers or failed lookups
+export interface DnsCacheEntry {
+  name: string;
+  recordType: string;
+  answers: string[];
+  negative: boolean;
+  error: string | null;
+  ttlRemainingSecs: number;
+  hits: number;
+}
+
+export interface DnsCacheStats {
+  capacity: number;
+  hits: number;
+  misses: number;
+  hitRate: number;
+  prefetches: number;
+  evictions: number;
+  entries: DnsCacheEntry[];
+}
+
+// Result of check_dns_poisoning
+export type AddressFlag = 'private' | 'bogon' | 'sinkhole';
+export type AnswerVerdict = 'clean' | 'mismatch' | 'poisoned' | 'failed';
+
+export interface FlaggedAddress {
+  address: string;
+  flag: AddressFlag;
+}
+
+export interface ResolverAnswer {
+  resolver: string;
+  transport: string;
+  addresses: string[];
+  flagged: FlaggedAddress[];
+  error: string | null;
+  latencyMs: number;
+  verdict: AnswerVerdict;
+}
+
+export interface DomainReport {
+  domain: string;
+  reference: string[];
+  answers: ResolverAnswer[];
+}
+
+export interface PoisoningReport {
+  domains: DomainReport[];
+  suspiciousResolvers: string[];
+  recommendEncryptedDns: boolean;
+  checkedAt: string;
+}
+
+// Local stub DNS server on 127.0.0.1
+export interface DnsStubConfig {
+  enabled: boolean;
+  port: number;
+}
+
+export interface DnsStubStatus {
+  running: boolean;
+  address: string | null;
+  queries: number;
+}
+
+export interface DnsQueryLogEntry {
+  timestamp: string;
+  client: string;
+  protocol: 'udp' | 'tcp';
+  name: string;
+  recordType: string;
+  responseCode: string;
+  answers: string[];
+  error: string | null;
+  latencyMs: number;
 }
 
 // Connection status for UI
@@ -63,4 +411,8 @@ export const defaultAdvancedSettings: AdvancedSettings = {
   customUserAgent: null,
   killSwitch: false,
   autoUpdate: true,
+  notifyOnDisconnect: true,
+  notifyOnRecover: true,
+  notifyOnFailover: true,
+  notifyOnKillSwitch: true,
 };